@@ -0,0 +1,251 @@
+use crate::DbManager;
+use anyhow::{Context, Result, bail};
+use matrix_macros::get_env;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{FromRow, query, query_as};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, error, instrument};
+
+/// How long a user stays online without another heartbeat
+pub static PRESENCE_TTL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(get_env!("PRESENCE_TTL_SECS", 60, u64)));
+/// How long a user is shown as typing without another update
+pub static TYPING_TTL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(get_env!("TYPING_TTL_SECS", 8, u64)));
+/// Expired entries are never returned, this only keeps the tables small
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const EPHEMERAL_LEADER: &str = "ephemeral_sweep";
+
+/// Status a user reports with a heartbeat, users without a current one are offline
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PresenceStatus {
+    Online,
+    Away,
+}
+
+impl Display for PresenceStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresenceStatus::Online => write!(f, "online"),
+            PresenceStatus::Away => write!(f, "away"),
+        }
+    }
+}
+
+impl FromStr for PresenceStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "online" => Ok(PresenceStatus::Online),
+            "away" => Ok(PresenceStatus::Away),
+            _ => bail!("Unknown presence status {s:?}, expected online or away"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Presence {
+    pub user: String,
+    pub status: PresenceStatus,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct PresenceRow {
+    user_name: String,
+    status: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl DbManager {
+    /// Creates the tables of presence and typing state
+    ///
+    /// Unlogged, so they are cheap to write and empty after a crash of Postgres, which only logs everyone out
+    #[instrument(skip_all)]
+    pub(crate) async fn prepare_ephemeral(&self) -> Result<()> {
+        let db_pool = backoff!(self);
+
+        for statement in [
+            r#"
+            CREATE UNLOGGED TABLE IF NOT EXISTS presence (
+                user_name TEXT PRIMARY KEY,
+                status TEXT NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            "#,
+            r#"
+            CREATE UNLOGGED TABLE IF NOT EXISTS typing (
+                room TEXT NOT NULL,
+                user_name TEXT NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (room, user_name)
+            );
+            "#,
+        ] {
+            query(statement)
+                .execute(db_pool)
+                .await
+                .context("Can't create ephemeral table")
+                .map_err(|e| hans!(self, e))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes expired entries on the leader of the ephemeral sweep
+    pub fn spawn_ephemeral_sweep(&self) {
+        let manager = self.clone();
+        self.spawn_leader_task(EPHEMERAL_LEADER, SWEEP_INTERVAL, move || {
+            let manager = manager.clone();
+            async move {
+                debug!("Sweeping ephemeral state");
+                if let Err(e) = manager.sweep().await {
+                    error!(?e, "Sweeping ephemeral state failed");
+                }
+            }
+        });
+    }
+
+    async fn sweep(&self) -> Result<()> {
+        let db_pool = backoff!(self);
+
+        for statement in [
+            "DELETE FROM presence WHERE expires_at <= now();",
+            "DELETE FROM typing WHERE expires_at <= now();",
+        ] {
+            query(statement)
+                .execute(db_pool)
+                .await
+                .context("Can't delete expired entries")
+                .map_err(|e| hans!(self, e))?;
+        }
+        Ok(())
+    }
+
+    /// Heartbeat of a user, `None` logs the user out right away
+    #[instrument(skip(self))]
+    pub async fn set_presence(&self, user: &str, status: Option<PresenceStatus>) -> Result<()> {
+        let db_pool = backoff!(self);
+
+        match status {
+            Some(status) => query(
+                r#"
+                INSERT INTO presence (user_name, status, expires_at)
+                    VALUES ($1, $2, now() + $3)
+                    ON CONFLICT (user_name) DO UPDATE SET
+                        status = EXCLUDED.status,
+                        expires_at = EXCLUDED.expires_at;
+                "#,
+            )
+            .bind(user)
+            .bind(status.to_string())
+            .bind(*PRESENCE_TTL),
+            None => query("DELETE FROM presence WHERE user_name = $1;").bind(user),
+        }
+        .execute(db_pool)
+        .await
+        .context("Can't set presence")
+        .map_err(|e| hans!(self, e))?;
+        Ok(())
+    }
+
+    /// Users of `users` that are online or away, everyone else is offline
+    #[instrument(skip_all, fields(users = users.len()))]
+    pub async fn presence(&self, users: &[String]) -> Result<Vec<Presence>> {
+        let db_pool = backoff!(self);
+
+        let rows = query_as::<_, PresenceRow>(
+            r#"
+            SELECT user_name, status, expires_at
+                FROM presence
+                WHERE user_name = ANY($1) AND expires_at > now()
+                ORDER BY user_name;
+            "#,
+        )
+        .bind(users)
+        .fetch_all(db_pool)
+        .await
+        .context("Can't get presence")
+        .map_err(|e| hans!(self, e))?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Presence {
+                    status: row.status.parse()?,
+                    user: row.user_name,
+                    expires_at: row.expires_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Marks `user` as typing in `room` for [`TYPING_TTL`], or as done typing
+    #[instrument(skip(self))]
+    pub async fn set_typing(&self, room: &str, user: &str, typing: bool) -> Result<()> {
+        let db_pool = backoff!(self);
+
+        if typing {
+            query(
+                r#"
+                INSERT INTO typing (room, user_name, expires_at)
+                    VALUES ($1, $2, now() + $3)
+                    ON CONFLICT (room, user_name) DO UPDATE SET
+                        expires_at = EXCLUDED.expires_at;
+                "#,
+            )
+            .bind(room)
+            .bind(user)
+            .bind(*TYPING_TTL)
+        } else {
+            query("DELETE FROM typing WHERE room = $1 AND user_name = $2;")
+                .bind(room)
+                .bind(user)
+        }
+        .execute(db_pool)
+        .await
+        .context("Can't set typing")
+        .map_err(|e| hans!(self, e))?;
+        Ok(())
+    }
+
+    /// Users currently typing in `room`
+    #[instrument(skip(self))]
+    pub async fn typing(&self, room: &str) -> Result<Vec<String>> {
+        let db_pool = backoff!(self);
+
+        let users = query_as::<_, (String,)>(
+            r#"
+            SELECT user_name
+                FROM typing
+                WHERE room = $1 AND expires_at > now()
+                ORDER BY user_name;
+            "#,
+        )
+        .bind(room)
+        .fetch_all(db_pool)
+        .await
+        .context("Can't get typing users")
+        .map_err(|e| hans!(self, e))?;
+
+        Ok(users.into_iter().map(|(user,)| user).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status() {
+        for status in [PresenceStatus::Online, PresenceStatus::Away] {
+            assert_eq!(
+                status.to_string().parse::<PresenceStatus>().unwrap(),
+                status
+            );
+        }
+        assert!("offline".parse::<PresenceStatus>().is_err());
+    }
+}
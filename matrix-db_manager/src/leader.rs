@@ -0,0 +1,163 @@
+use crate::DbManager;
+use crate::lock::AdvisoryLock;
+use futures::future;
+use parking_lot::Mutex;
+use std::mem;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::watch::{self, Receiver};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+
+/// How often workers that don't lead a task try to take over, and leaders check that they still lead
+const ELECTION_INTERVAL: Duration = Duration::from_secs(10);
+/// Time leader tasks get to hand over their locks on shutdown
+const STEP_DOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The leader tasks of a worker
+#[derive(Debug)]
+pub(crate) struct Leadership {
+    shutdown: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Leadership {
+    pub(crate) fn new() -> Self {
+        Self {
+            shutdown: watch::Sender::new(false),
+            tasks: Mutex::default(),
+        }
+    }
+}
+
+impl DbManager {
+    /// Runs `job` every `interval` on the one worker that leads `name`
+    ///
+    /// Every worker spawns the same tasks, the others take over once the leader is gone. Jobs are cancelled when the
+    /// worker steps down, so they have to be safe to interrupt
+    pub fn spawn_leader_task<F, Fut>(&self, name: &'static str, interval: Duration, job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let manager = self.clone();
+        let shutdown = self.leadership.shutdown.subscribe();
+        let handle = tokio::spawn(
+            async move { manager.lead(name, interval, job, shutdown).await }
+                .instrument(info_span!("leader", name)),
+        );
+        self.leadership.tasks.lock().push(handle);
+    }
+
+    /// Stops every leader task and releases its lock, so other workers take over right away
+    pub async fn step_down(&self) {
+        if self.leadership.shutdown.send(true).is_err() {
+            return;
+        }
+        let tasks = mem::take(&mut *self.leadership.tasks.lock());
+        if timeout(STEP_DOWN_TIMEOUT, future::join_all(tasks))
+            .await
+            .is_err()
+        {
+            // Their locks are released with their connections once the process exits
+            warn!("Leader tasks didn't stop in time");
+        }
+    }
+
+    async fn lead<F, Fut>(
+        &self,
+        name: &'static str,
+        interval: Duration,
+        mut job: F,
+        mut shutdown: Receiver<bool>,
+    ) where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut lock: Option<AdvisoryLock> = None;
+        let mut last_run: Option<Instant> = None;
+        while !*shutdown.borrow() {
+            if let Some(held) = lock.as_mut()
+                && !held.is_held().await
+            {
+                warn!("Lost leadership");
+                lock = None;
+            }
+            if lock.is_none() {
+                match self.try_lock(name).await {
+                    Ok(Some(taken)) => {
+                        info!("Became leader");
+                        lock = Some(taken);
+                        last_run = None;
+                    }
+                    Ok(None) => debug!("Another worker leads"),
+                    Err(e) => error!(?e, "Leader election failed"),
+                }
+            }
+
+            if lock.is_some() && last_run.is_none_or(|run| run.elapsed() >= interval) {
+                last_run = Some(Instant::now());
+                select! {
+                    _ = job() => {}
+                    _ = shutdown.changed() => break,
+                }
+            }
+
+            let wait = match (&lock, last_run) {
+                (Some(_), Some(run)) => interval
+                    .saturating_sub(run.elapsed())
+                    .min(ELECTION_INTERVAL),
+                _ => ELECTION_INTERVAL,
+            };
+            select! {
+                _ = sleep(wait) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        if let Some(lock) = lock {
+            match lock.release().await {
+                Ok(()) => info!("Handed over leadership"),
+                Err(e) => warn!(?e, "Unable to hand over leadership"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn count_runs(manager: &DbManager, name: &'static str) -> Arc<AtomicUsize> {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        manager.spawn_leader_task(name, Duration::from_millis(100), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {}
+        });
+        runs
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Postgres at DATABASE_URL"]
+    async fn leadership_is_handed_over_on_step_down() {
+        let name = "test_leader_handover";
+        let first = DbManager::test_manager().await;
+        let first_runs = count_runs(&first, name);
+        sleep(Duration::from_millis(500)).await;
+        let second = DbManager::test_manager().await;
+        let second_runs = count_runs(&second, name);
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(first_runs.load(Ordering::SeqCst) > 0);
+        assert_eq!(second_runs.load(Ordering::SeqCst), 0);
+
+        first.step_down().await;
+        sleep(ELECTION_INTERVAL + Duration::from_secs(1)).await;
+        assert!(second_runs.load(Ordering::SeqCst) > 0);
+        second.step_down().await;
+    }
+}
@@ -1,19 +1,27 @@
 #[macro_use]
 mod macros;
+pub mod ephemeral;
 mod err_handling;
 pub mod guard;
+mod leader;
+pub mod lock;
 pub mod metrics_manager;
+mod mongo_admin;
 mod mongo_manager;
+mod retention_manager;
 
-use anyhow::{Context, Result, anyhow, bail};
+use crate::guard::DbGuard;
+use crate::leader::Leadership;
+use anyhow::{Context, Result, bail};
 use matrix_errors::DbErr::Unreachable;
 use matrix_macros::get_env;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::postgres::types::PgInterval;
 use sqlx::types::chrono;
-use sqlx::{Postgres, migrate, query};
+use sqlx::{Connection, Postgres, migrate, query};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self, Sender};
 use tracing::{debug, info, instrument};
 use uuid::Uuid;
@@ -27,10 +35,20 @@ const DB_POOL_TIMEOUT: Duration = Duration::from_secs(5);
 
 static LOADED: AtomicBool = AtomicBool::new(false);
 
+/// Parses every setting that is read lazily, see [`matrix_mongo_manager::load_config`]
+pub fn load_config() {
+    LazyLock::force(&ephemeral::PRESENCE_TTL);
+    LazyLock::force(&ephemeral::TYPING_TTL);
+    LazyLock::force(&retention_manager::SWEEP_INTERVAL);
+    matrix_mongo_manager::load_config();
+}
+
 #[derive(Clone, Debug)]
 pub struct DbManager {
     instance_id: Uuid,
     db_pool: DbPool,
+    guard: Arc<DbGuard>,
+    leadership: Arc<Leadership>,
     tx: Sender<String>,
 }
 
@@ -63,6 +81,8 @@ impl DbManager {
 
         let manager = DbManager {
             instance_id: Uuid::new_v4(),
+            guard: DbGuard::new(&db_pool),
+            leadership: Arc::new(Leadership::new()),
             db_pool,
             tx,
         };
@@ -75,6 +95,27 @@ impl DbManager {
         Ok(manager)
     }
 
+    pub fn guard(&self) -> &DbGuard {
+        &self.guard
+    }
+
+    /// Round trip of a ping on a pooled connection
+    #[instrument(skip_all)]
+    pub async fn ping(&self) -> Result<Duration> {
+        let db_pool = backoff!(self);
+        let start = Instant::now();
+        let mut conn = db_pool
+            .acquire()
+            .await
+            .context("Unable to get connection")
+            .map_err(|e| hans!(self, e))?;
+        conn.ping()
+            .await
+            .context("Ping failed")
+            .map_err(|e| hans!(self, e))?;
+        Ok(start.elapsed())
+    }
+
     #[instrument(skip_all)]
     pub async fn worker_metric_example(&self) -> Result<()> {
         let db_pool = backoff!(self);
@@ -137,6 +178,25 @@ impl DbManager {
             .await
             .context("Failed to run migrations")
             .map_err(|e| hans!(self, e))?;
-        Ok(())
+        self.prepare_mappings().await?;
+        // Not part of the shared migrations, the tables only hold state of the workers
+        self.prepare_ephemeral().await
+    }
+}
+
+#[cfg(test)]
+impl DbManager {
+    /// Skips the check of [`Self::new`], so tests can simulate several workers
+    pub(crate) async fn test_manager() -> Self {
+        let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let db_pool = PgPoolOptions::new().connect(&db_url).await.unwrap();
+        let (tx, _) = mpsc::channel(1);
+        DbManager {
+            instance_id: Uuid::new_v4(),
+            guard: DbGuard::new(&db_pool),
+            leadership: Arc::new(Leadership::new()),
+            db_pool,
+            tx,
+        }
     }
 }
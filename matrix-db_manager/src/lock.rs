@@ -0,0 +1,120 @@
+use crate::{DbManager, DbType};
+use anyhow::{Context, Result};
+use sqlx::pool::PoolConnection;
+use sqlx::{query, query_as};
+use tracing::{debug, instrument, warn};
+
+/// Session level advisory lock, held by one worker at a time
+///
+/// Released by [`Self::release`], or by closing its connection when dropped
+#[derive(Debug)]
+pub struct AdvisoryLock {
+    name: String,
+    conn: Option<PoolConnection<DbType>>,
+}
+
+impl DbManager {
+    /// Takes the lock `name` if no other worker holds it
+    #[instrument(skip(self))]
+    pub async fn try_lock(&self, name: &str) -> Result<Option<AdvisoryLock>> {
+        let db_pool = backoff!(self);
+
+        let mut conn = db_pool
+            .acquire()
+            .await
+            .context("Can't get connection for lock")
+            .map_err(|e| hans!(self, e))?;
+        let (locked,) = query_as::<_, (bool,)>("SELECT pg_try_advisory_lock(hashtext($1));")
+            .bind(name)
+            .fetch_one(&mut *conn)
+            .await
+            .context("Can't take lock")
+            .map_err(|e| hans!(self, e))?;
+
+        if !locked {
+            debug!("Lock is held by another worker");
+            return Ok(None);
+        }
+        debug!("Took lock");
+        Ok(Some(AdvisoryLock {
+            name: name.to_string(),
+            conn: Some(conn),
+        }))
+    }
+}
+
+impl AdvisoryLock {
+    /// Whether the connection holding the lock is still alive, the lock is gone with it otherwise
+    pub async fn is_held(&mut self) -> bool {
+        let Some(conn) = self.conn.as_mut() else {
+            return false;
+        };
+        match query("SELECT 1;").execute(&mut **conn).await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!(self.name, ?e, "Lost connection of lock");
+                self.close();
+                false
+            }
+        }
+    }
+
+    /// Hands the lock to the next worker that asks for it
+    #[instrument(skip_all, fields(name = self.name))]
+    pub async fn release(mut self) -> Result<()> {
+        let Some(mut conn) = self.conn.take() else {
+            return Ok(());
+        };
+        let unlocked = query("SELECT pg_advisory_unlock(hashtext($1));")
+            .bind(&self.name)
+            .execute(&mut *conn)
+            .await;
+        if let Err(e) = unlocked {
+            // The connection must not go back to the pool while it may still hold the lock
+            drop(conn.detach());
+            return Err(e).context("Can't release lock");
+        }
+        debug!("Released lock");
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            drop(conn.detach());
+        }
+    }
+}
+
+impl Drop for AdvisoryLock {
+    fn drop(&mut self) {
+        // Postgres releases session locks with their connection
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    #[ignore = "needs a local Postgres at DATABASE_URL"]
+    async fn lock_is_held_by_one_worker() {
+        let (first, second) = (
+            DbManager::test_manager().await,
+            DbManager::test_manager().await,
+        );
+        let name = format!("test_{}", Uuid::new_v4());
+
+        let mut lock = first.try_lock(&name).await.unwrap().unwrap();
+        assert!(lock.is_held().await);
+        assert!(second.try_lock(&name).await.unwrap().is_none());
+
+        lock.release().await.unwrap();
+        let lock = second.try_lock(&name).await.unwrap().unwrap();
+        // Dropping closes the connection, which releases the lock as well
+        drop(lock);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(first.try_lock(&name).await.unwrap().is_some());
+    }
+}
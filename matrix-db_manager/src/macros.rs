@@ -3,13 +3,12 @@
 /// Get `db_pool` otherwise
 macro_rules! backoff {
     ($manager:expr) => {{
-        use crate::guard::DbGuard;
+        use crate::guard::DbState;
         #[allow(unused_imports)]
         use anyhow::{anyhow, bail};
-        use core::sync::atomic::Ordering;
         use matrix_errors::DbErr;
 
-        if DbGuard::is_running(Ordering::SeqCst) {
+        if $manager.guard.state() == DbState::Open {
             bail!(DbErr::Unreachable(anyhow!("Unreachable")));
         }
         &$manager.db_pool
@@ -19,11 +18,17 @@ macro_rules! backoff {
 // Shoutout to the server
 macro_rules! hans {
     ($manager:expr, $e:expr) => {{
-        use crate::guard::DbGuard;
+        use crate::guard::ErrKind;
         #[allow(unused_imports)]
         use matrix_errors::DbErr;
 
-        DbGuard::init(&$manager.db_pool);
-        DbErr::Unreachable($e)
+        let e = $e;
+        match ErrKind::of(&e) {
+            ErrKind::Transport => {
+                $manager.guard.record_failure();
+                DbErr::Unreachable(e)
+            }
+            ErrKind::Database => DbErr::Query(e),
+        }
     }};
 }
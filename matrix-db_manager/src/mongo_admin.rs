@@ -0,0 +1,347 @@
+use crate::DbManager;
+use anyhow::{Context, Result};
+use matrix_errors::MappingErr;
+use matrix_mongo_manager::room::canonicalize;
+use sqlx::{query, query_as, query_scalar};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+struct RemovalState {
+    exists: bool,
+    instances: i64,
+}
+
+impl DbManager {
+    /// Makes concurrent inserts of the same boundary fail, instead of both passing the existence check
+    ///
+    /// Not part of the shared migrations, which are owned by another repository
+    #[instrument(skip_all)]
+    pub(crate) async fn prepare_mappings(&self) -> Result<()> {
+        let db_pool = backoff!(self);
+
+        sqlx::query(
+            r#"
+            CREATE UNIQUE INDEX IF NOT EXISTS db_mapping_from_key ON db_mapping ("from");
+            "#,
+        )
+        .execute(db_pool)
+        .await
+        .context("Can't create unique index on Mongo mapping boundaries")
+        .map_err(|e| hans!(self, e))?;
+        Ok(())
+    }
+
+    /// Adds a regular Mongo instance, responsible for every namespace from `from` up to the next instance
+    ///
+    /// All workers pick it up with their next mapping refresh. A boundary inside the range of another instance takes
+    /// rooms away from it, so a migration to `url` has to cover the boundary. Only the first instance and ones below
+    /// every other can be added without
+    ///
+    /// returns: Result<Result<Uuid, MappingErr>>
+    /// - Outer Err: Postgres Error
+    /// - Inner Err: Postgres works, but request was not valid
+    /// - Uuid: Id of the new instance
+    #[instrument(skip(self, url))]
+    pub async fn add_instance(&self, url: &str, from: &str) -> Result<Result<Uuid, MappingErr>> {
+        // Room names are canonical, so a boundary like "M" would sort before all of them
+        let from = canonicalize(from);
+        let db_pool = backoff!(self);
+
+        let id = Uuid::new_v4();
+        // Compared like routing does, by bytes instead of the collation of the database
+        let inserted = query!(
+            r#"
+            INSERT INTO db_mapping (id, url, "from")
+                SELECT $1, $2, $3
+                WHERE NOT EXISTS (SELECT 1 FROM db_mapping WHERE "from" COLLATE "C" < $3)
+                OR EXISTS (
+                    SELECT 1 FROM db_migration
+                        WHERE url = $2 AND "from" COLLATE "C" <= $3 AND "to" COLLATE "C" >= $3
+                );
+            "#,
+            id,
+            url,
+            &from,
+        )
+        .execute(db_pool)
+        .await;
+
+        // Backed by a unique index, so concurrent inserts of the same boundary can't both succeed
+        let affected = match inserted {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                return Ok(Err(MappingErr::DuplicateFrom(from)));
+            }
+            res => res
+                .context("Can't insert Mongo mapping")
+                .map_err(|e| hans!(self, e))?
+                .rows_affected(),
+        };
+        if affected == 0 {
+            let duplicate = query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM db_mapping WHERE "from" = $1) AS "exists!";"#,
+                &from,
+            )
+            .fetch_one(db_pool)
+            .await
+            .context("Can't check Mongo mapping boundaries")
+            .map_err(|e| hans!(self, e))?;
+            return Ok(Err(if duplicate {
+                MappingErr::DuplicateFrom(from)
+            } else {
+                MappingErr::Unmigrated(from)
+            }));
+        }
+
+        info!(%id, "Added Mongo instance");
+        Ok(Ok(id))
+    }
+
+    /// Removes a regular Mongo instance, the one with the lowest boundary can't be removed
+    ///
+    /// Rooms below the boundary of the next instance would have no instance at all otherwise. Doesn't move any data,
+    /// start a migration beforehand if the instance still holds rooms
+    #[instrument(skip(self))]
+    pub async fn remove_instance(&self, id: Uuid) -> Result<Result<(), MappingErr>> {
+        let db_pool = backoff!(self);
+
+        let affected = query!(
+            r#"
+            DELETE FROM db_mapping
+                WHERE id = $1
+                AND EXISTS (SELECT 1 FROM db_mapping lower WHERE lower."from" < db_mapping."from");
+            "#,
+            id,
+        )
+        .execute(db_pool)
+        .await
+        .context("Can't delete Mongo mapping")
+        .map_err(|e| hans!(self, e))?
+        .rows_affected();
+
+        if affected != 0 {
+            info!("Removed Mongo instance");
+            return Ok(Ok(()));
+        }
+
+        let state = query_as!(
+            RemovalState,
+            r#"
+            SELECT
+                EXISTS (SELECT 1 FROM db_mapping WHERE id = $1) AS "exists!",
+                (SELECT COUNT(*) FROM db_mapping) AS "instances!";
+            "#,
+            id,
+        )
+        .fetch_one(db_pool)
+        .await
+        .context("Can't check if Mongo mapping exists")
+        .map_err(|e| hans!(self, e))?;
+
+        Ok(Err(match state {
+            RemovalState { exists: false, .. } => MappingErr::NotFound(id.to_string()),
+            RemovalState { instances: 1, .. } => MappingErr::LastInstance,
+            RemovalState { .. } => MappingErr::FirstInstance,
+        }))
+    }
+
+    /// Starts a migration, routing writes for `from..=to` to the instance at `url`
+    ///
+    /// Reads are served from both the regular and the migration instance until the migration is cancelled. Ranges of
+    /// migrations don't overlap, writes to a room could go to either instance otherwise
+    #[instrument(skip(self, url))]
+    pub async fn start_migration(
+        &self,
+        url: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<Result<Uuid, MappingErr>> {
+        let (from, to) = (canonicalize(from), canonicalize(to));
+        if from > to {
+            return Ok(Err(MappingErr::InvalidRange(from, to)));
+        }
+
+        let db_pool = backoff!(self);
+        let mut tx = db_pool
+            .begin()
+            .await
+            .context("Can't start transaction")
+            .map_err(|e| hans!(self, e))?;
+
+        // Held until the commit, so concurrent migrations can't both pass the overlap check
+        query!("SELECT pg_advisory_xact_lock(hashtext('db_migration'));")
+            .execute(&mut *tx)
+            .await
+            .context("Can't lock Mongo migrations")
+            .map_err(|e| hans!(self, e))?;
+        let overlapping = query_scalar!(
+            r#"
+            SELECT id FROM db_migration
+                WHERE "from" COLLATE "C" <= $2 AND "to" COLLATE "C" >= $1
+                LIMIT 1;
+            "#,
+            &from,
+            &to,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Can't check Mongo migrations")
+        .map_err(|e| hans!(self, e))?;
+        if let Some(overlapping) = overlapping {
+            return Ok(Err(MappingErr::OverlappingMigration(
+                overlapping.to_string(),
+            )));
+        }
+
+        let id = Uuid::new_v4();
+        query!(
+            r#"
+            INSERT INTO db_migration (id, url, "from", "to")
+                VALUES ($1, $2, $3, $4);
+            "#,
+            id,
+            url,
+            &from,
+            &to,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("Can't insert Mongo migration")
+        .map_err(|e| hans!(self, e))?;
+        tx.commit()
+            .await
+            .context("Can't commit Mongo migration")
+            .map_err(|e| hans!(self, e))?;
+
+        info!(%id, "Started Mongo migration");
+        Ok(Ok(id))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn cancel_migration(&self, id: Uuid) -> Result<Result<(), MappingErr>> {
+        let db_pool = backoff!(self);
+
+        let affected = query!(
+            r#"
+            DELETE FROM db_migration
+                WHERE id = $1;
+            "#,
+            id,
+        )
+        .execute(db_pool)
+        .await
+        .context("Can't delete Mongo migration")
+        .map_err(|e| hans!(self, e))?
+        .rows_affected();
+
+        if affected == 0 {
+            return Ok(Err(MappingErr::NotFound(id.to_string())));
+        }
+
+        info!("Cancelled Mongo migration");
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Boundary of an instance below the ones of the test, inserted like by hand
+    async fn base_instance(manager: &DbManager) -> String {
+        let base = format!("zz-test-{}", Uuid::new_v4().simple());
+        sqlx::query(r#"INSERT INTO db_mapping (id, url, "from") VALUES ($1, $2, $3);"#)
+            .bind(Uuid::new_v4())
+            .bind("mongodb://base")
+            .bind(&base)
+            .execute(&manager.db_pool)
+            .await
+            .unwrap();
+        base
+    }
+
+    async fn clean_up(manager: &DbManager, base: &str) {
+        for table in ["db_mapping", "db_migration"] {
+            sqlx::query(&format!(r#"DELETE FROM {table} WHERE "from" LIKE $1;"#))
+                .bind(format!("{base}%"))
+                .execute(&manager.db_pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Postgres at DATABASE_URL"]
+    async fn concurrent_boundaries_are_rejected() {
+        let manager = DbManager::test_manager().await;
+        let base = base_instance(&manager).await;
+        let from = format!("{base}-m");
+        let upper = format!("{base}-u");
+
+        // The rooms from there on are still on the base instance
+        assert!(matches!(
+            manager.add_instance("mongodb://new", &from).await.unwrap(),
+            Err(MappingErr::Unmigrated(_))
+        ));
+        manager
+            .start_migration("mongodb://new", &from, &format!("{base}-z"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (first, second) = tokio::join!(
+            manager.add_instance("mongodb://new", &from),
+            manager.add_instance("mongodb://new", &from),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.is_ok() != second.is_ok());
+        assert!(matches!(
+            first.err().or(second.err()),
+            Some(MappingErr::DuplicateFrom(_))
+        ));
+
+        let upper = manager
+            .add_instance("mongodb://new", &upper)
+            .await
+            .unwrap()
+            .unwrap();
+        manager.remove_instance(upper).await.unwrap().unwrap();
+
+        clean_up(&manager, &base).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Postgres at DATABASE_URL"]
+    async fn overlapping_migrations_are_rejected() {
+        let manager = DbManager::test_manager().await;
+        let base = base_instance(&manager).await;
+        let (b, d, f) = (
+            format!("{base}-b"),
+            format!("{base}-d"),
+            format!("{base}-f"),
+        );
+
+        let (first, second) = tokio::join!(
+            manager.start_migration("mongodb://first", &b, &d),
+            manager.start_migration("mongodb://second", &d, &f),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.is_ok() != second.is_ok());
+        assert!(matches!(
+            first.as_ref().err().or(second.as_ref().err()),
+            Some(MappingErr::OverlappingMigration(_))
+        ));
+
+        // Ranges next to each other are fine
+        manager
+            .start_migration(
+                "mongodb://third",
+                &format!("{f}-next"),
+                &format!("{base}-x"),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        clean_up(&manager, &base).await;
+    }
+}
@@ -2,6 +2,7 @@ use crate::DbManager;
 use anyhow::{Context, Result};
 use futures::future;
 use itertools::Itertools;
+use matrix_metrics::MetricsWrapper;
 use matrix_mongo_manager::MongoManager;
 use matrix_mongo_manager::mappings::{
     Instance, MONGO_MAPPINGS_MANAGER, Mappings, MigrationInstance,
@@ -17,39 +18,49 @@ const MAP_INTERVAL: Duration = Duration::from_secs(10);
 
 impl DbManager {
     #[instrument(skip_all)]
-    pub async fn manage_mongo(self) {
+    pub async fn manage_mongo(self, metrics: MetricsWrapper) {
         loop {
             debug!("Get mappings");
-            'guarded: {
-                let mongo_mappings = match self.get_mappings().await {
-                    Ok(mappings) => mappings,
-                    Err(e) => {
-                        error!(?e, "Getting Mongo mappings failed");
-                        break 'guarded;
-                    }
-                };
-                let mongo_migration_mappings = match self.get_migration_mappings().await {
-                    Ok(mappings) => mappings,
-                    Err(e) => {
-                        error!(?e, "Getting Mongo migration mappings failed");
-                        break 'guarded;
-                    }
-                };
-                let mut guard = MONGO_MAPPINGS_MANAGER.write().await;
-                guard.instances = mongo_mappings;
-                guard.migration_instances = mongo_migration_mappings;
-                self.set_mongo_mapping_guards(&mut guard).await;
-                debug!("Set mappings");
+            if let Err(e) = self.load_mappings(&metrics).await {
+                error!(?e, "Getting Mongo mappings failed");
             }
             sleep(MAP_INTERVAL).await;
         }
     }
 
+    /// Loads the instances and migrations once and connects to new instances
+    #[instrument(skip_all)]
+    pub async fn load_mappings(&self, metrics: &MetricsWrapper) -> Result<()> {
+        let mongo_mappings = self.get_mappings().await?;
+        let mongo_migration_mappings = self
+            .list_migrations()
+            .await
+            .context("Getting Mongo migration mappings failed")?;
+        let mut guard = MONGO_MAPPINGS_MANAGER.write().await;
+        guard
+            .set_instances(mongo_mappings, mongo_migration_mappings)
+            .context("Refusing to route with the stored boundaries")?;
+        self.set_mongo_mapping_guards(&mut guard, metrics).await;
+        debug!("Set mappings");
+        Ok(())
+    }
+
     #[instrument(skip_all)]
     async fn get_mappings(&self) -> Result<Vec<Instance>> {
+        let new_mappings = self.list_instances().await?;
+
+        if new_mappings.is_empty() {
+            error!("No regular Mongo instances found");
+            exit(1);
+        }
+        Ok(new_mappings)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn list_instances(&self) -> Result<Vec<Instance>> {
         let db_pool = backoff!(self);
 
-        let new_mappings = query_as!(
+        let instances = query_as!(
             Instance,
             r#"
             SELECT id, url, "from"
@@ -62,15 +73,11 @@ impl DbManager {
         .context("Can't get Mongo mappings")
         .map_err(|e| hans!(self, e))?;
 
-        if new_mappings.is_empty() {
-            error!("No regular Mongo instances found");
-            exit(1);
-        }
-        Ok(new_mappings)
+        Ok(instances)
     }
 
     #[instrument(skip_all)]
-    async fn get_migration_mappings(&self) -> Result<Vec<MigrationInstance>> {
+    pub async fn list_migrations(&self) -> Result<Vec<MigrationInstance>> {
         let db_pool = backoff!(self);
 
         let new_migration_records = query!(
@@ -99,7 +106,11 @@ impl DbManager {
     }
 
     #[instrument(skip_all)]
-    async fn set_mongo_mapping_guards(&self, mappings: &mut RwLockWriteGuard<'_, Mappings>) {
+    async fn set_mongo_mapping_guards(
+        &self,
+        mappings: &mut RwLockWriteGuard<'_, Mappings>,
+        metrics: &MetricsWrapper,
+    ) {
         let existing_ids = mappings
             .instances
             .iter()
@@ -116,8 +127,8 @@ impl DbManager {
 
         let existing_urls = mappings
             .managers
-            .iter()
-            .map(|(url, _)| url.as_str())
+            .keys()
+            .map(|url| url.as_str())
             .collect::<Vec<_>>();
 
         let futures = mappings
@@ -133,7 +144,7 @@ impl DbManager {
             .unique_by(|(url, _)| *url)
             .filter(|(url, _)| !existing_urls.contains(url))
             .map(|(url, id)| async move {
-                let manager = MongoManager::new(url, id, self.tx.clone()).await;
+                let manager = MongoManager::new(url, id, self.tx.clone(), metrics.clone()).await;
                 (url.to_string(), manager)
             })
             .collect::<Vec<_>>();
@@ -0,0 +1,22 @@
+use crate::DbManager;
+use matrix_macros::get_env;
+use matrix_mongo_manager::MongoManager;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, error};
+
+pub(crate) static SWEEP_INTERVAL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(get_env!("RETENTION_SWEEP_SECS", 60 * 60, u64)));
+const RETENTION_LEADER: &str = "retention_sweep";
+
+impl DbManager {
+    /// Sweeps expired messages on the leader of the retention sweep
+    pub fn spawn_retention(&self) {
+        self.spawn_leader_task(RETENTION_LEADER, *SWEEP_INTERVAL, || async {
+            match MongoManager::sweep_retention().await {
+                Ok(report) => debug!(?report, "Swept expired messages"),
+                Err(e) => error!(?e, "Sweeping expired messages failed"),
+            }
+        });
+    }
+}
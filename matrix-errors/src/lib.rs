@@ -5,6 +5,8 @@ use thiserror::Error;
 pub enum DbErr {
     #[error("Database is unreachable: {0:?}")]
     Unreachable(Error),
+    #[error("Database query failed: {0:?}")]
+    Query(Error),
 }
 
 #[derive(Debug, Error)]
@@ -13,18 +15,86 @@ pub enum MongoErr {
     Unreachable(Error),
     #[error("Invalid Mongo URL for id: {0:?}")]
     InvalidUrl(String),
+    #[error("Mongo operation failed: {0:?}")]
+    Operation(Error),
+}
+
+#[derive(Debug, Error)]
+pub enum MappingErr {
+    #[error("An instance starting at {0:?} already exists")]
+    DuplicateFrom(String),
+    #[error("Invalid range, {0:?} is bigger than {1:?}")]
+    InvalidRange(String, String),
+    #[error("No mapping with id {0}")]
+    NotFound(String),
+    #[error("Can't remove the last Mongo instance")]
+    LastInstance,
+    #[error(
+        "Can't remove the instance with the lowest boundary, the rooms below the next one would be lost"
+    )]
+    FirstInstance,
+    #[error(
+        "Stored boundary {0:?} is not canonical, add an instance at {1:?} and migrate its rooms before removing this one"
+    )]
+    NotCanonical(String, String),
+    #[error(
+        "Rooms from {0:?} are served by another instance, migrate them to the new one before adding it"
+    )]
+    Unmigrated(String),
+    #[error(
+        "The range overlaps migration {0}, rooms can only be migrated to one instance at a time"
+    )]
+    OverlappingMigration(String),
 }
 
 #[derive(Debug, Error)]
 pub enum MatrixErr {
     #[error("Room {0:?} already exists, can't create")]
     RoomAlreadyExists(String),
-    #[error("Room name {0:?} is not allowed")]
-    IllegalRoomName(String),
+    #[error("Room name {0:?} is not allowed: {1}")]
+    IllegalRoomName(String, &'static str),
     #[error("The room {0:?} does not exist")]
     RoomNotFound(String),
     #[error("You are not a member of room {0:?}")]
     NotInRoom(String),
+    #[error("Invalid room config: {0}")]
+    InvalidRoomConfig(String),
+    #[error(transparent)]
+    InvalidMessage(#[from] ContentErr),
+    #[error("Attachment {0:?} does not exist")]
+    AttachmentNotFound(String),
+    #[error("Attachment is {0} bytes large, the maximum is {1}")]
+    AttachmentTooLarge(usize, usize),
+    #[error("Invalid search: {0}")]
+    InvalidSearch(&'static str),
+    #[error("Message {0:?} is still being sent")]
+    SendInProgress(String),
+    #[error("Invalid direct message: {0}")]
+    InvalidDirect(&'static str),
+    #[error("Room {0:?} is being migrated or converted, try again afterwards")]
+    RoomMigrating(String),
+    #[error("Invalid room export: {0}")]
+    InvalidExport(String),
     #[error("General error: {0}")]
     General(String),
 }
+
+#[derive(Debug, Error)]
+pub enum ContentErr {
+    #[error("Message is empty")]
+    Empty,
+    #[error("Message is {0} characters long, the maximum is {1}")]
+    TooLong(usize, usize),
+    #[error("Message contains control characters")]
+    ControlCharacters,
+    #[error("A {0} has to reference a message")]
+    MissingReference(&'static str),
+    #[error("Only replies and reactions can reference a message")]
+    UnexpectedReference,
+    #[error("Message {0} can't be referenced")]
+    InvalidReference(i64),
+    #[error("Invalid attachment: {0}")]
+    InvalidAttachment(&'static str),
+    #[error("Client message ids have to be between 1 and {0} printable ASCII characters")]
+    InvalidClientMsgId(usize),
+}
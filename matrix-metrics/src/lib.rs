@@ -28,6 +28,11 @@ pub struct Metrics {
     writes: MetricStore,
     total_requests: AtomicU64,
     total_failed_requests: AtomicU64,
+    circuits_opened: AtomicU64,
+    circuits_half_opened: AtomicU64,
+    circuits_closed: AtomicU64,
+    room_cache_hits: AtomicU64,
+    room_cache_misses: AtomicU64,
 }
 
 impl Metrics {
@@ -40,6 +45,11 @@ impl Metrics {
             writes: Default::default(),
             total_requests: Default::default(),
             total_failed_requests: Default::default(),
+            circuits_opened: Default::default(),
+            circuits_half_opened: Default::default(),
+            circuits_closed: Default::default(),
+            room_cache_hits: Default::default(),
+            room_cache_misses: Default::default(),
         };
         trace!(?metrics);
 
@@ -59,6 +69,23 @@ impl Metrics {
         self.total_failed_requests.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn circuit_opened(&self) {
+        self.circuits_opened.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn circuit_half_opened(&self) {
+        self.circuits_half_opened.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn circuit_closed(&self) {
+        self.circuits_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn room_cache_hit(&self) {
+        self.room_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn room_cache_miss(&self) {
+        self.room_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
     fn insert_metric(lock: &MetricStore) {
         let now = Instant::now();
         let mut guard = lock.write();
@@ -92,4 +119,21 @@ impl Metrics {
     pub fn get_total_fails(&self) -> u64 {
         self.total_failed_requests.load(Ordering::Relaxed)
     }
+
+    pub fn get_circuit_opened(&self) -> u64 {
+        self.circuits_opened.load(Ordering::Relaxed)
+    }
+    pub fn get_circuit_half_opened(&self) -> u64 {
+        self.circuits_half_opened.load(Ordering::Relaxed)
+    }
+    pub fn get_circuit_closed(&self) -> u64 {
+        self.circuits_closed.load(Ordering::Relaxed)
+    }
+
+    pub fn get_room_cache_hits(&self) -> u64 {
+        self.room_cache_hits.load(Ordering::Relaxed)
+    }
+    pub fn get_room_cache_misses(&self) -> u64 {
+        self.room_cache_misses.load(Ordering::Relaxed)
+    }
 }
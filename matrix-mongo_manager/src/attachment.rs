@@ -0,0 +1,697 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::content::check_attachment;
+use crate::layout::{Layout, SHARED_DB};
+use crate::messaging::Attachment;
+use crate::room::RoomName;
+use anyhow::{Context, Result, bail};
+use bson::{Bson, Document, doc};
+use futures::TryStreamExt;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use matrix_errors::{ContentErr, MatrixErr};
+use matrix_macros::get_env;
+use mongodb::IndexModel;
+use mongodb::error::GridFsErrorKind;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::options::GridFsBucketOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tokio::fs;
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+
+/// Upper bound for a single attachment, in bytes
+pub static MAX_ATTACHMENT_BYTES: LazyLock<usize> =
+    LazyLock::new(|| get_env!("MAX_ATTACHMENT_BYTES", 10 * 1024 * 1024, usize));
+/// Where attachments are kept, `gridfs` or `fs:<dir>`
+pub static ATTACHMENT_STORE: LazyLock<AttachmentStore> =
+    LazyLock::new(|| get_env!("ATTACHMENT_STORE", AttachmentStore::GridFs, AttachmentStore));
+/// GridFS bucket in the database of the room, or in the shared database for shared rooms
+const BUCKET: &str = "attachments";
+const META_EXTENSION: &str = "meta";
+const FALLBACK_MIME: &str = "application/octet-stream";
+/// Magic bytes of the types we recognize, everything else is served as text or [`FALLBACK_MIME`]
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Backend for the files of attachments
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttachmentStore {
+    /// GridFS next to the messages of the room, on the instance that serves it
+    GridFs,
+    /// One directory per room below the given one, meant for local development
+    Local(PathBuf),
+}
+
+impl Display for AttachmentStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttachmentStore::GridFs => write!(f, "gridfs"),
+            AttachmentStore::Local(dir) => write!(f, "fs:{}", dir.display()),
+        }
+    }
+}
+
+/// Parses the format of [`Display`], e.g. `gridfs` or `fs:/tmp/attachments`
+impl FromStr for AttachmentStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("fs", dir)) if !dir.is_empty() => Ok(AttachmentStore::Local(dir.into())),
+            None if s == "gridfs" => Ok(AttachmentStore::GridFs),
+            _ => bail!("Unknown attachment store {s:?}, expected gridfs or fs:<dir>"),
+        }
+    }
+}
+
+/// Stored next to the file
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FileMeta {
+    pub(crate) room: String,
+    pub(crate) author: String,
+    pub(crate) name: String,
+    pub(crate) mime: String,
+}
+
+impl MongoManager {
+    /// Stores a file for a room, the returned metadata can be attached to messages of the room
+    ///
+    /// The mime type is sniffed from the data, whatever the client claims is ignored
+    #[instrument(skip(data), fields(size = data.len()))]
+    pub async fn upload_attachment(
+        room: &str,
+        user: &str,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<Attachment> {
+        let room = RoomName::parse(room)?;
+        if data.is_empty() {
+            bail!(MatrixErr::from(ContentErr::InvalidAttachment(
+                "the file is empty"
+            )));
+        }
+        if data.len() > *MAX_ATTACHMENT_BYTES {
+            bail!(MatrixErr::AttachmentTooLarge(
+                data.len(),
+                *MAX_ATTACHMENT_BYTES
+            ));
+        }
+        let attachment = Attachment {
+            name: name.trim().to_string(),
+            mime: sniff_mime(&data).to_string(),
+            size: data.len() as u64,
+            storage_key: Uuid::new_v4().simple().to_string(),
+        };
+        check_attachment(&attachment).map_err(MatrixErr::from)?;
+
+        let manager = mappings::write_manager(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+        manager
+            .check_member(&room, user)
+            .await
+            .context("Unable to check membership")
+            .map_err(|e| fritz!(manager, e))??;
+
+        let meta = FileMeta {
+            room: room.to_string(),
+            author: user.to_string(),
+            name: attachment.name.clone(),
+            mime: attachment.mime.clone(),
+        };
+        manager
+            .store_file(&attachment.storage_key, &meta, &data)
+            .await
+            .map_err(|e| fritz!(manager, e))?;
+
+        info!(
+            key = attachment.storage_key,
+            mime = attachment.mime,
+            "Stored attachment"
+        );
+        Ok(attachment)
+    }
+
+    /// Returns the metadata and content of an attachment of the room
+    #[instrument]
+    pub async fn download_attachment(
+        room: &str,
+        user: &str,
+        key: &str,
+    ) -> Result<(Attachment, Vec<u8>)> {
+        let room = RoomName::parse(room)?;
+        let not_found = || MatrixErr::AttachmentNotFound(key.to_string());
+        // Keys are generated by us, anything else can't exist and must not end up in a path
+        let Ok(key) = Uuid::try_parse(key).map(|k| k.simple().to_string()) else {
+            bail!(not_found());
+        };
+
+        let manager = mappings::write_manager(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+        manager
+            .check_member(&room, user)
+            .await
+            .context("Unable to check membership")
+            .map_err(|e| fritz!(manager, e))??;
+
+        let found = match &*ATTACHMENT_STORE {
+            AttachmentStore::GridFs => {
+                let mut found = None;
+                for manager in gridfs_managers(&room).await? {
+                    found = manager
+                        .load_gridfs(&room, &key)
+                        .await
+                        .context("Unable to load attachment")
+                        .map_err(|e| fritz!(manager, e))?;
+                    if found.is_some() {
+                        break;
+                    }
+                }
+                found
+            }
+            AttachmentStore::Local(dir) => load_local(dir, &room, &key).await?,
+        };
+
+        // Keys are unique across rooms, but only members of the room it was uploaded to get to see it
+        match found {
+            Some((meta, data)) if meta.room == room.as_str() => {
+                let attachment = Attachment {
+                    name: meta.name,
+                    mime: meta.mime,
+                    size: data.len() as u64,
+                    storage_key: key,
+                };
+                Ok((attachment, data))
+            }
+            _ => bail!(not_found()),
+        }
+    }
+
+    /// Replaces what the client claims about the attachments with the stored files, which have to belong to the room
+    #[instrument(skip_all)]
+    pub(crate) async fn resolve_attachments(
+        room: &RoomName,
+        attachments: &mut [Attachment],
+    ) -> Result<()> {
+        for attachment in attachments {
+            let not_found = || MatrixErr::AttachmentNotFound(attachment.storage_key.clone());
+            let Ok(key) = Uuid::try_parse(&attachment.storage_key).map(|k| k.simple().to_string())
+            else {
+                bail!(not_found());
+            };
+
+            let found = match &*ATTACHMENT_STORE {
+                AttachmentStore::GridFs => {
+                    let mut found = None;
+                    for manager in gridfs_managers(room).await? {
+                        found = manager
+                            .stat_gridfs(room, &key)
+                            .await
+                            .context("Unable to find attachment")
+                            .map_err(|e| fritz!(manager, e))?;
+                        if found.is_some() {
+                            break;
+                        }
+                    }
+                    found
+                }
+                AttachmentStore::Local(dir) => stat_local(dir, room, &key).await?,
+            };
+
+            match found {
+                Some((meta, size)) if meta.room == room.as_str() => {
+                    attachment.mime = meta.mime;
+                    attachment.size = size;
+                    attachment.storage_key = key;
+                }
+                _ => bail!(not_found()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores a file in the configured store, the room is taken from its metadata
+    pub(crate) async fn store_file(&self, key: &str, meta: &FileMeta, data: &[u8]) -> Result<()> {
+        match &*ATTACHMENT_STORE {
+            AttachmentStore::GridFs => self
+                .store_gridfs(&meta.room, key, meta, data)
+                .await
+                .context("Unable to store attachment"),
+            AttachmentStore::Local(dir) => store_local(dir, key, meta, data).await,
+        }
+    }
+
+    /// `None` if the room has no file with this key on this instance
+    pub(crate) async fn load_file(
+        &self,
+        room: &str,
+        key: &str,
+    ) -> Result<Option<(FileMeta, Vec<u8>)>> {
+        match &*ATTACHMENT_STORE {
+            AttachmentStore::GridFs => self.load_gridfs(room, key).await,
+            AttachmentStore::Local(dir) => load_local(dir, room, key).await,
+        }
+    }
+
+    /// Keys of the files of a room on this instance
+    #[instrument(skip(self))]
+    pub(crate) async fn file_keys(&self, room: &str) -> Result<Vec<String>> {
+        let AttachmentStore::Local(dir) = &*ATTACHMENT_STORE else {
+            let mut keys = BTreeSet::new();
+            for bucket in self.file_buckets(room).await? {
+                let ids = bucket
+                    .find(doc! { "metadata.room": room })
+                    .await
+                    .context("Unable to find attachments")?
+                    .map_ok(|file| file.id)
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .context("Unable to read attachments")?;
+                keys.extend(ids.into_iter().filter_map(|id| match id {
+                    Bson::String(key) => Some(key),
+                    _ => None,
+                }));
+            }
+            return Ok(keys.into_iter().collect());
+        };
+
+        let mut entries = match fs::read_dir(dir.join(room)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).context("Unable to list attachments"),
+        };
+        let mut keys = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Unable to list attachments")?
+        {
+            // Only complete files, their metadata is written last
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == META_EXTENSION)
+                && let Some(key) = path.file_stem().and_then(|s| s.to_str())
+            {
+                keys.push(key.to_string());
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Removes a file of the room, files that don't exist are fine
+    #[instrument(skip(self))]
+    pub(crate) async fn delete_file(&self, room: &str, key: &str) -> Result<()> {
+        let AttachmentStore::Local(dir) = &*ATTACHMENT_STORE else {
+            for bucket in self.file_buckets(room).await? {
+                match bucket.delete(Bson::String(key.to_string())).await {
+                    Ok(()) => {}
+                    Err(e) if is_file_not_found(&e) => {}
+                    Err(e) => return Err(e).context("Unable to delete attachment"),
+                }
+            }
+            return Ok(());
+        };
+
+        // Metadata first, a file without it is never served
+        let path = dir.join(room).join(key);
+        for path in [path.with_extension(META_EXTENSION), path] {
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("Unable to delete attachment"),
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, meta, data))]
+    async fn store_gridfs(
+        &self,
+        room: &str,
+        key: &str,
+        meta: &FileMeta,
+        data: &[u8],
+    ) -> Result<()> {
+        let bucket = self.file_buckets(room).await?.swap_remove(0);
+        upload(&bucket, key, meta, data).await?;
+        debug!("Uploaded attachment");
+        Ok(())
+    }
+
+    /// `None` if the file is not on this instance
+    #[instrument(skip(self))]
+    async fn load_gridfs(&self, room: &str, key: &str) -> Result<Option<(FileMeta, Vec<u8>)>> {
+        for bucket in self.file_buckets(room).await? {
+            if let Some(found) = download(&bucket, key).await? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Metadata and size, `None` if the file is not on this instance
+    #[instrument(skip(self))]
+    async fn stat_gridfs(&self, room: &str, key: &str) -> Result<Option<(FileMeta, u64)>> {
+        for bucket in self.file_buckets(room).await? {
+            if let Some(file) = bucket
+                .find_one(doc! { "_id": key })
+                .await
+                .context("Unable to find attachment")?
+            {
+                let meta = bson::from_document::<FileMeta>(file.metadata.unwrap_or_default())
+                    .context("Invalid attachment metadata")?;
+                return Ok(Some((meta, file.length)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves the files of a room that is converted to the shared layout
+    #[instrument(skip(self))]
+    pub(crate) async fn copy_attachments(&self, room: &str) -> Result<()> {
+        let own = self.bucket(room)?;
+        let shared = self.bucket(SHARED_DB)?;
+        let keys = own
+            .find(doc! {})
+            .await
+            .context("Unable to find attachments")?
+            .map_ok(|file| file.id)
+            .try_collect::<Vec<_>>()
+            .await
+            .context("Unable to read attachments")?;
+
+        let mut copied = 0;
+        for key in keys {
+            let Bson::String(key) = key else {
+                warn!(?key, "Skipping attachment with a foreign id");
+                continue;
+            };
+            // Copied by an earlier run
+            if shared
+                .find_one(doc! { "_id": &key })
+                .await
+                .context("Unable to find attachment")?
+                .is_some()
+            {
+                continue;
+            }
+            let Some((meta, data)) = download(&own, &key).await? else {
+                continue;
+            };
+            upload(&shared, &key, &meta, &data).await?;
+            copied += 1;
+        }
+
+        info!(copied, "Copied attachments");
+        Ok(())
+    }
+
+    /// Buckets that may hold the files of the room, the one new files go to first
+    ///
+    /// Files of shared rooms that are still being converted are in the database of the room until it is dropped
+    async fn file_buckets(&self, room: &str) -> Result<Vec<GridFsBucket>> {
+        let own = self.bucket(room)?;
+        if self.layout().await? == Layout::Shared && self.shared_state(room).await?.is_some() {
+            self.index_shared_files().await?;
+            return Ok(vec![self.bucket(SHARED_DB)?, own]);
+        }
+        Ok(vec![own])
+    }
+
+    /// Files of shared rooms are found by their room, indexed once per instance
+    async fn index_shared_files(&self) -> Result<()> {
+        let col = format!("{BUCKET}.files");
+        let namespace = format!("{SHARED_DB}.{col}");
+        if self.cache.claim_index(&namespace) {
+            let index = IndexModel::builder()
+                .keys(doc! { "metadata.room": 1 })
+                .build();
+            if let Err(e) = backoff!(self)
+                .database(SHARED_DB)
+                .collection::<Document>(&col)
+                .create_index(index)
+                .await
+            {
+                self.cache.release_index(&namespace);
+                return Err(e).context("Unable to create attachment index");
+            }
+        }
+        Ok(())
+    }
+
+    fn bucket(&self, db: &str) -> Result<GridFsBucket> {
+        let options = GridFsBucketOptions::builder()
+            .bucket_name(BUCKET.to_string())
+            .build();
+        Ok(backoff!(self).database(db).gridfs_bucket(options))
+    }
+}
+
+async fn upload(bucket: &GridFsBucket, key: &str, meta: &FileMeta, data: &[u8]) -> Result<()> {
+    let mut upload = bucket
+        .open_upload_stream(&meta.name)
+        .id(Bson::String(key.to_string()))
+        .metadata(bson::to_document(meta).context("Unable to serialize metadata")?)
+        .await
+        .context("Unable to open upload stream")?;
+
+    if let Err(e) = upload.write_all(data).await {
+        if let Err(e) = upload.abort().await {
+            warn!(?e, "Unable to abort upload");
+        }
+        return Err(e).context("Unable to upload attachment");
+    }
+    upload.close().await.context("Unable to finish upload")
+}
+
+/// `None` if the file is not in the bucket
+async fn download(bucket: &GridFsBucket, key: &str) -> Result<Option<(FileMeta, Vec<u8>)>> {
+    let Some(file) = bucket
+        .find_one(doc! { "_id": key })
+        .await
+        .context("Unable to find attachment")?
+    else {
+        return Ok(None);
+    };
+    let meta = bson::from_document::<FileMeta>(file.metadata.unwrap_or_default())
+        .context("Invalid attachment metadata")?;
+
+    let mut data = Vec::with_capacity(file.length as usize);
+    bucket
+        .open_download_stream(file.id)
+        .await
+        .context("Unable to open download stream")?
+        .read_to_end(&mut data)
+        .await
+        .context("Unable to download attachment")?;
+
+    Ok(Some((meta, data)))
+}
+
+/// Files are kept with the messages of the room, which are on both instances during migrations
+async fn gridfs_managers(room: &RoomName) -> Result<Vec<MongoManager>> {
+    match mappings::read_manager(room).await {
+        Ok(either::Left(manager)) => Ok(vec![manager]),
+        Ok(either::Right((man, mig_m))) => Ok(vec![man, mig_m]),
+        Err(e) => {
+            warn!(?e, "Failed to get migration manager");
+            bail!("Internal server error");
+        }
+    }
+}
+
+fn is_file_not_found(e: &mongodb::error::Error) -> bool {
+    matches!(
+        &*e.kind,
+        mongodb::error::ErrorKind::GridFs(GridFsErrorKind::FileNotFound { .. })
+    )
+}
+
+/// Writes the file first, a file without metadata is never served
+async fn store_local(dir: &Path, key: &str, meta: &FileMeta, data: &[u8]) -> Result<()> {
+    let room_dir = dir.join(&meta.room);
+    fs::create_dir_all(&room_dir)
+        .await
+        .with_context(|| format!("Unable to create {}", room_dir.display()))?;
+    fs::write(room_dir.join(key), data)
+        .await
+        .context("Unable to write attachment")?;
+    let meta_bytes = bson::to_vec(meta).context("Unable to serialize metadata")?;
+    fs::write(
+        room_dir.join(key).with_extension(META_EXTENSION),
+        meta_bytes,
+    )
+    .await
+    .context("Unable to write attachment metadata")?;
+    Ok(())
+}
+
+/// `None` if there is no (complete) file with this key in the directory of the room
+async fn load_local(dir: &Path, room: &str, key: &str) -> Result<Option<(FileMeta, Vec<u8>)>> {
+    let path = dir.join(room).join(key);
+    let meta_bytes = match fs::read(path.with_extension(META_EXTENSION)).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Unable to read attachment metadata"),
+    };
+    let meta = bson::from_slice::<FileMeta>(&meta_bytes).context("Invalid attachment metadata")?;
+    let data = fs::read(path).await.context("Unable to read attachment")?;
+    Ok(Some((meta, data)))
+}
+
+/// Metadata and size without reading the file
+async fn stat_local(dir: &Path, room: &str, key: &str) -> Result<Option<(FileMeta, u64)>> {
+    let path = dir.join(room).join(key);
+    let meta_bytes = match fs::read(path.with_extension(META_EXTENSION)).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Unable to read attachment metadata"),
+    };
+    let meta = bson::from_slice::<FileMeta>(&meta_bytes).context("Invalid attachment metadata")?;
+    let size = fs::metadata(path)
+        .await
+        .context("Unable to read attachment")?
+        .len();
+    Ok(Some((meta, size)))
+}
+
+/// Mime type by the magic bytes of the data, UTF-8 without control characters is plain text
+pub(crate) fn sniff_mime(data: &[u8]) -> &'static str {
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(sig, _)| data.starts_with(sig)) {
+        return mime;
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if data.len() >= 8 && &data[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+    match std::str::from_utf8(data) {
+        Ok(text)
+            if !text
+                .chars()
+                .any(|c| c.is_control() && !c.is_ascii_whitespace()) =>
+        {
+            "text/plain"
+        }
+        _ => FALLBACK_MIME,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn sniffs_by_content() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff_mime(b"\xff\xd8\xff\xe0\0\x10JFIF"), "image/jpeg");
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime(b"\0\0\0\x18ftypmp42"), "video/mp4");
+        assert_eq!(sniff_mime(b"%PDF-1.7"), "application/pdf");
+        assert_eq!(sniff_mime("hällo\n".as_bytes()), "text/plain");
+        // Markup is never served as such
+        assert_eq!(sniff_mime(b"<html><script>"), "text/plain");
+        assert_eq!(sniff_mime(b"\0\x01\x02"), FALLBACK_MIME);
+        assert_eq!(sniff_mime(b"\xff\xfe"), FALLBACK_MIME);
+    }
+
+    #[test]
+    fn parses_display_format() {
+        for store in [
+            AttachmentStore::GridFs,
+            AttachmentStore::Local("/tmp/attachments".into()),
+        ] {
+            assert_eq!(store.to_string().parse::<AttachmentStore>().unwrap(), store);
+        }
+        assert!("fs:".parse::<AttachmentStore>().is_err());
+        assert!("s3".parse::<AttachmentStore>().is_err());
+    }
+
+    #[tokio::test]
+    async fn local_store_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("attachments-{}", Uuid::new_v4()));
+        let key = Uuid::new_v4().simple().to_string();
+        let meta = FileMeta {
+            room: "room".to_string(),
+            author: "user".to_string(),
+            name: "cat.png".to_string(),
+            mime: "image/png".to_string(),
+        };
+
+        assert!(load_local(&dir, "room", &key).await.unwrap().is_none());
+        store_local(&dir, &key, &meta, b"data").await.unwrap();
+        let (loaded, data) = load_local(&dir, "room", &key).await.unwrap().unwrap();
+        assert_eq!(loaded.author, "user");
+        assert_eq!(loaded.name, "cat.png");
+        assert_eq!(data, b"data");
+        assert!(load_local(&dir, "other", &key).await.unwrap().is_none());
+        let (stat, size) = stat_local(&dir, "room", &key).await.unwrap().unwrap();
+        assert_eq!((stat.mime.as_str(), size), ("image/png", 4));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Mongo at MONGO_URL"]
+    async fn files_move_with_converted_rooms() {
+        let url = std::env::var("MONGO_URL").unwrap();
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        let manager = MongoManager::new(&url, Uuid::new_v4(), err_tx, Metrics::new()).await;
+        let room = format!("files-{}", Uuid::new_v4().simple());
+        let key = Uuid::new_v4().simple().to_string();
+        let meta = FileMeta {
+            room: room.clone(),
+            author: "user".to_string(),
+            name: "cat.png".to_string(),
+            mime: "image/png".to_string(),
+        };
+
+        // Rooms in their own database keep the files there, and take them along when the database is moved
+        manager
+            .store_gridfs(&room, &key, &meta, b"data")
+            .await
+            .unwrap();
+        let own = manager.bucket(&room).unwrap();
+        assert!(download(&own, &key).await.unwrap().is_some());
+
+        // Twice, as by a conversion that is run again
+        manager.copy_attachments(&room).await.unwrap();
+        manager.copy_attachments(&room).await.unwrap();
+        let shared = manager.bucket(SHARED_DB).unwrap();
+        let (copied, data) = download(&shared, &key).await.unwrap().unwrap();
+        assert_eq!(
+            (copied.room.as_str(), data.as_slice()),
+            (room.as_str(), &b"data"[..])
+        );
+
+        shared.delete(Bson::String(key)).await.unwrap();
+        manager
+            .client
+            .as_ref()
+            .as_ref()
+            .unwrap()
+            .database(&room)
+            .drop()
+            .await
+            .unwrap();
+    }
+}
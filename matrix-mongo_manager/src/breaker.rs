@@ -0,0 +1,460 @@
+use matrix_errors::MongoErr;
+use matrix_metrics::MetricsWrapper;
+use mongodb::error::ErrorKind;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{info, instrument, warn};
+
+const WINDOW: Duration = Duration::from_secs(10);
+/// Failures needed within a window before the rate is considered, so a single error can't trip it
+const MIN_FAILURES: u32 = 5;
+const MAX_FAILURE_RATE: f64 = 0.5;
+/// While half-open, every nth request is let through
+const HALF_OPEN_RATIO: u32 = 4;
+/// Probes that have to succeed before closing again
+const HALF_OPEN_PROBES: u32 = 5;
+const HALF_OPEN_MIN_DUR: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BreakerState {
+    /// Everything passes, failures are counted
+    Closed,
+    /// Everything is rejected until the guard can ping the instance again
+    Open,
+    /// A fraction of the traffic passes, a single failure opens the breaker again
+    HalfOpen,
+}
+
+/// What an error says about the health of the instance
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ErrKind {
+    /// The instance could not be reached, counts towards the failure rate
+    Transport,
+    /// Rejected before reaching Mongo (breaker is open or there is no client)
+    Rejected,
+    /// Mongo answered or never got asked, e.g. missing room config or a duplicate key
+    Application,
+}
+
+impl ErrKind {
+    pub(crate) fn of(e: &anyhow::Error) -> Self {
+        if e.chain().any(|c| c.is::<MongoErr>()) {
+            return Self::Rejected;
+        }
+
+        let transport = e
+            .chain()
+            .filter_map(|c| c.downcast_ref::<mongodb::error::Error>())
+            .any(|e| {
+                matches!(
+                    *e.kind,
+                    ErrorKind::Io(_)
+                        | ErrorKind::ConnectionPoolCleared { .. }
+                        | ErrorKind::ServerSelection { .. }
+                        | ErrorKind::DnsResolve { .. }
+                        | ErrorKind::Authentication { .. }
+                        | ErrorKind::InvalidResponse { .. }
+                )
+            });
+
+        if transport {
+            Self::Transport
+        } else {
+            Self::Application
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    since: Instant,
+    window_start: Instant,
+    attempts: u32,
+    failures: u32,
+    /// Requests seen while half-open, to let every [`HALF_OPEN_RATIO`]th through
+    half_open_seen: u32,
+    /// Probes that succeeded while half-open
+    probes: u32,
+    /// Bumped on every transition, so requests admitted before it don't count as probes
+    epoch: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    inner: Mutex<Inner>,
+    /// Wakes the guard every time the breaker opens
+    open_notify: Notify,
+    metrics: MetricsWrapper,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(metrics: MetricsWrapper) -> Self {
+        let now = Instant::now();
+        Self {
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                since: now,
+                window_start: now,
+                attempts: 0,
+                failures: 0,
+                half_open_seen: 0,
+                probes: 0,
+                epoch: 0,
+            }),
+            open_notify: Notify::new(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        self.inner.lock().state
+    }
+
+    /// Time spent in the current state
+    pub(crate) fn in_state_for(&self) -> Duration {
+        self.inner.lock().since.elapsed()
+    }
+
+    /// Whether a request may be sent to the instance, counts it as an attempt if so
+    ///
+    /// Returns the epoch the request was admitted in, its outcome is recorded against it
+    pub(crate) fn admit(&self) -> Option<u64> {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            BreakerState::Closed => {
+                Self::roll_window(&mut inner);
+                inner.attempts += 1;
+                Some(inner.epoch)
+            }
+            BreakerState::Open => None,
+            BreakerState::HalfOpen => {
+                inner.half_open_seen += 1;
+                inner
+                    .half_open_seen
+                    .is_multiple_of(HALF_OPEN_RATIO)
+                    .then_some(inner.epoch)
+            }
+        }
+    }
+
+    /// Whether a request admitted in `epoch` may still talk to the instance
+    ///
+    /// Requests admitted before the breaker opened aren't probes, so they stop once it half-opens
+    pub(crate) fn passes(&self, epoch: u64) -> bool {
+        let inner = self.inner.lock();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => inner.epoch == epoch,
+        }
+    }
+
+    /// A request admitted in `epoch` reached the instance without a transport error
+    pub(crate) fn record_success(&self, epoch: u64) {
+        let mut inner = self.inner.lock();
+        if inner.state != BreakerState::HalfOpen || inner.epoch != epoch {
+            return;
+        }
+        inner.probes += 1;
+        if inner.probes >= HALF_OPEN_PROBES && inner.since.elapsed() >= HALF_OPEN_MIN_DUR {
+            self.transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    /// Only call for [`ErrKind::Transport`] errors
+    pub(crate) fn record_failure(&self) {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            BreakerState::Closed => {
+                Self::roll_window(&mut inner);
+                inner.failures += 1;
+                let rate = inner.failures as f64 / inner.attempts.max(1) as f64;
+                if inner.failures >= MIN_FAILURES && rate >= MAX_FAILURE_RATE {
+                    warn!(failures = inner.failures, rate, "Failure rate exceeded");
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            BreakerState::HalfOpen => {
+                warn!("Probe failed");
+                self.transition(&mut inner, BreakerState::Open);
+            }
+            BreakerState::Open => {}
+        }
+    }
+
+    /// Called by the guard once the instance answers pings again
+    pub(crate) fn half_open(&self) {
+        let mut inner = self.inner.lock();
+        if inner.state == BreakerState::Open {
+            self.transition(&mut inner, BreakerState::HalfOpen);
+        }
+    }
+
+    /// Resolves once the breaker opened, immediately if it opened while nobody was waiting
+    pub(crate) async fn opened(&self) {
+        self.open_notify.notified().await
+    }
+
+    fn roll_window(inner: &mut Inner) {
+        if inner.window_start.elapsed() >= WINDOW {
+            inner.window_start = Instant::now();
+            inner.attempts = 0;
+            inner.failures = 0;
+        }
+    }
+
+    #[instrument(skip(self, inner), fields(from = ?inner.state))]
+    fn transition(&self, inner: &mut Inner, to: BreakerState) {
+        info!(in_state_for = ?inner.since.elapsed(), "Circuit breaker transition");
+
+        let now = Instant::now();
+        inner.state = to;
+        inner.since = now;
+        inner.window_start = now;
+        inner.attempts = 0;
+        inner.failures = 0;
+        inner.half_open_seen = 0;
+        inner.probes = 0;
+        inner.epoch += 1;
+
+        match to {
+            BreakerState::Closed => self.metrics.circuit_closed(),
+            BreakerState::Open => {
+                self.metrics.circuit_opened();
+                // Stores a permit if the guard isn't waiting yet, so no opening gets lost
+                self.open_notify.notify_one();
+            }
+            BreakerState::HalfOpen => self.metrics.circuit_half_opened(),
+        }
+    }
+}
+
+/// One logical request against an instance, admitted by the breaker once
+///
+/// Shared by the clones of the manager the request was routed to, so every operation of the request passes or none
+/// does. The outcome is recorded once the last clone is dropped
+#[derive(Debug)]
+pub(crate) struct Admission {
+    breaker: Arc<CircuitBreaker>,
+    epoch: Option<u64>,
+    used: AtomicBool,
+    failed: AtomicBool,
+}
+
+impl Admission {
+    pub(crate) fn new(breaker: Arc<CircuitBreaker>) -> Self {
+        Self {
+            epoch: breaker.admit(),
+            breaker,
+            used: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the request may send another operation to the instance
+    pub(crate) fn allows(&self) -> bool {
+        let passes = self.epoch.is_some_and(|epoch| self.breaker.passes(epoch));
+        if passes {
+            self.used.store(true, Ordering::Relaxed);
+        }
+        passes
+    }
+
+    /// Only call for [`ErrKind::Transport`] errors, counts once per request
+    pub(crate) fn fail(&self) {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        // Requests that never reached the instance say nothing about it
+        if let Some(epoch) = self.epoch
+            && self.used.load(Ordering::Relaxed)
+            && !self.failed.load(Ordering::Relaxed)
+        {
+            self.breaker.record_success(epoch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+    use matrix_metrics::Metrics;
+    use std::io;
+
+    fn trip(breaker: &CircuitBreaker) {
+        for _ in 0..MIN_FAILURES {
+            assert!(breaker.admit().is_some());
+            breaker.record_failure();
+        }
+    }
+
+    #[test]
+    fn single_failure_does_not_open() {
+        let breaker = CircuitBreaker::new(Metrics::new());
+        assert!(breaker.admit().is_some());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn low_failure_rate_does_not_open() {
+        let breaker = CircuitBreaker::new(Metrics::new());
+        for _ in 0..(MIN_FAILURES * 4) {
+            assert!(breaker.admit().is_some());
+        }
+        for _ in 0..MIN_FAILURES {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failure_rate_opens_and_rejects() {
+        let metrics = Metrics::new();
+        let breaker = CircuitBreaker::new(metrics.clone());
+        trip(&breaker);
+
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.admit().is_none());
+        assert_eq!(metrics.get_circuit_opened(), 1);
+    }
+
+    #[test]
+    fn half_open_lets_fraction_through() {
+        let breaker = CircuitBreaker::new(Metrics::new());
+        trip(&breaker);
+        breaker.half_open();
+
+        let allowed = (0..HALF_OPEN_RATIO * 2)
+            .filter(|_| breaker.admit().is_some())
+            .count();
+        assert_eq!(allowed, 2);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn half_open_failure_reopens() {
+        let metrics = Metrics::new();
+        let breaker = CircuitBreaker::new(metrics.clone());
+        trip(&breaker);
+        breaker.half_open();
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(metrics.get_circuit_opened(), 2);
+        assert_eq!(metrics.get_circuit_half_opened(), 1);
+    }
+
+    /// A probe that got through the half-open breaker, used for one operation
+    fn probe(breaker: &Arc<CircuitBreaker>) -> Admission {
+        loop {
+            let admission = Admission::new(breaker.clone());
+            if admission.allows() {
+                return admission;
+            }
+        }
+    }
+
+    fn half_opened() -> Arc<CircuitBreaker> {
+        let breaker = Arc::new(CircuitBreaker::new(Metrics::new()));
+        trip(&breaker);
+        breaker.half_open();
+        breaker.inner.lock().since -= HALF_OPEN_MIN_DUR;
+        breaker
+    }
+
+    #[test]
+    fn admitted_requests_pass_every_operation() {
+        let breaker = half_opened();
+
+        let admission = probe(&breaker);
+        assert!((0..HALF_OPEN_RATIO * 3).all(|_| admission.allows()));
+        let rejected = Admission::new(breaker.clone());
+        assert!(!rejected.allows());
+    }
+
+    #[test]
+    fn closes_only_after_successful_probes() {
+        let breaker = half_opened();
+
+        // Admitted, but never sent anything
+        for _ in 0..HALF_OPEN_PROBES * HALF_OPEN_RATIO {
+            drop(Admission::new(breaker.clone()));
+        }
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        for _ in 0..HALF_OPEN_PROBES {
+            drop(probe(&breaker));
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = half_opened();
+
+        let admission = probe(&breaker);
+        admission.fail();
+        drop(admission);
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn requests_from_before_opening_are_no_probes() {
+        let breaker = Arc::new(CircuitBreaker::new(Metrics::new()));
+        let stale = Admission::new(breaker.clone());
+        assert!(stale.allows());
+
+        trip(&breaker);
+        breaker.half_open();
+        assert!(!stale.allows());
+        drop(stale);
+        assert_eq!(breaker.inner.lock().probes, 0);
+    }
+
+    #[test]
+    fn failures_count_once_per_request() {
+        let breaker = Arc::new(CircuitBreaker::new(Metrics::new()));
+
+        let admission = Admission::new(breaker.clone());
+        assert!(admission.allows());
+        for _ in 0..MIN_FAILURES {
+            admission.fail();
+        }
+        assert_eq!(breaker.inner.lock().failures, 1);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let io_err = mongodb::error::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let transport = anyhow::Error::from(io_err).context("Failed to insert msg");
+        assert_eq!(ErrKind::of(&transport), ErrKind::Transport);
+
+        let rejected = anyhow::Error::from(MongoErr::Unreachable(anyhow!("Unreachable")))
+            .context("Unable to check access");
+        assert_eq!(ErrKind::of(&rejected), ErrKind::Rejected);
+
+        let application = None::<()>.context("No conf in room (how?)").unwrap_err();
+        assert_eq!(ErrKind::of(&application), ErrKind::Application);
+    }
+
+    #[tokio::test]
+    async fn opening_notifies_without_waiter() {
+        let breaker = CircuitBreaker::new(Metrics::new());
+        trip(&breaker);
+        tokio::time::timeout(Duration::from_millis(100), breaker.opened())
+            .await
+            .expect("opening was lost");
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{Context, Result, bail};
+use bson::DateTime;
+use chrono::Datelike;
+use matrix_macros::get_env;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Size of rooms created before bucketing was configurable
+pub const DEFAULT_BUCKET_SIZE: i64 = 100;
+const MAX_BUCKET_SIZE: i64 = 1_000_000;
+
+/// Used for new rooms that don't specify a strategy
+pub static DEFAULT_BUCKETING: LazyLock<Bucketing> =
+    LazyLock::new(|| get_env!("DEFAULT_BUCKETING", Bucketing::default(), Bucketing));
+
+/// How the messages of a room are split into `chat_N` collections
+///
+/// Every strategy produces increasing indices, so the newest messages are always in the highest bucket
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Bucketing {
+    /// `size` messages per bucket, by sequence number
+    Count { size: i64 },
+    /// One bucket per UTC day (`chat_YYYYMMDD`)
+    Daily,
+    /// One bucket per UTC month (`chat_YYYYMM`)
+    Monthly,
+    /// Everything in `chat_1`, indexed by sequence number
+    Single,
+}
+
+impl Default for Bucketing {
+    /// Rooms without a strategy in their config were created with this one
+    fn default() -> Self {
+        Self::Count {
+            size: DEFAULT_BUCKET_SIZE,
+        }
+    }
+}
+
+impl Bucketing {
+    /// Index of the bucket a message belongs in, `0` is reserved for the room config
+    pub(crate) fn bucket(&self, seq: i64, timestamp: DateTime) -> u32 {
+        let ts = timestamp.to_chrono();
+        match self {
+            Self::Count { size } => ((seq - 1) / size + 1) as u32,
+            Self::Daily => ts.year() as u32 * 10_000 + ts.month() * 100 + ts.day(),
+            Self::Monthly => ts.year() as u32 * 100 + ts.month(),
+            Self::Single => 1,
+        }
+    }
+
+    /// Highest bucket that can hold messages with a sequence number below `before`, `None` if that depends on time
+    pub(crate) fn last_bucket_before(&self, before: i64) -> Option<u32> {
+        match self {
+            Self::Count { .. } | Self::Single if before > 1 => {
+                Some(self.bucket(before - 1, DateTime::MIN))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Self::Count { size } = self
+            && !(1..=MAX_BUCKET_SIZE).contains(size)
+        {
+            bail!("Bucket size has to be between 1 and {MAX_BUCKET_SIZE}, got {size}");
+        }
+        Ok(())
+    }
+}
+
+impl Display for Bucketing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Count { size } => write!(f, "count:{size}"),
+            Self::Daily => write!(f, "daily"),
+            Self::Monthly => write!(f, "monthly"),
+            Self::Single => write!(f, "single"),
+        }
+    }
+}
+
+/// Parses the format of [`Display`], e.g. `count:100` or `daily`
+impl FromStr for Bucketing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bucketing = match s.split_once(':') {
+            Some(("count", size)) => Self::Count {
+                size: size.parse().context("Invalid bucket size")?,
+            },
+            None if s == "daily" => Self::Daily,
+            None if s == "monthly" => Self::Monthly,
+            None if s == "single" => Self::Single,
+            _ => bail!("Unknown bucketing {s:?}, expected count:<size>, daily, monthly or single"),
+        };
+        bucketing.validate()?;
+        Ok(bucketing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(y: i32, m: u32, d: u32) -> DateTime {
+        let date = chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        DateTime::from_chrono(date.and_hms_opt(23, 59, 59).unwrap().and_utc())
+    }
+
+    #[test]
+    fn count_fills_buckets_in_order() {
+        let bucketing = Bucketing::default();
+        let now = DateTime::now();
+        assert_eq!(bucketing.bucket(1, now), 1);
+        assert_eq!(bucketing.bucket(DEFAULT_BUCKET_SIZE, now), 1);
+        assert_eq!(bucketing.bucket(DEFAULT_BUCKET_SIZE + 1, now), 2);
+        assert_eq!(Bucketing::Count { size: 1 }.bucket(7, now), 7);
+    }
+
+    #[test]
+    fn time_buckets_increase() {
+        assert_eq!(Bucketing::Daily.bucket(1, ts(2026, 10, 18)), 20261018);
+        assert_eq!(Bucketing::Monthly.bucket(1, ts(2026, 10, 18)), 202610);
+        assert!(
+            Bucketing::Daily.bucket(1, ts(2026, 12, 31))
+                < Bucketing::Daily.bucket(1, ts(2027, 1, 1))
+        );
+        assert_eq!(Bucketing::Single.bucket(12345, ts(2026, 10, 18)), 1);
+    }
+
+    #[test]
+    fn buckets_before_seq() {
+        let bucketing = Bucketing::Count { size: 10 };
+        assert_eq!(bucketing.last_bucket_before(11), Some(1));
+        assert_eq!(bucketing.last_bucket_before(12), Some(2));
+        assert_eq!(bucketing.last_bucket_before(1), None);
+        assert_eq!(Bucketing::Single.last_bucket_before(100), Some(1));
+        assert_eq!(Bucketing::Daily.last_bucket_before(100), None);
+    }
+
+    #[test]
+    fn parses_display_format() {
+        for bucketing in [
+            Bucketing::Count { size: 42 },
+            Bucketing::Daily,
+            Bucketing::Monthly,
+            Bucketing::Single,
+        ] {
+            assert_eq!(
+                bucketing.to_string().parse::<Bucketing>().unwrap(),
+                bucketing
+            );
+        }
+        assert!("count:0".parse::<Bucketing>().is_err());
+        assert!("count:abc".parse::<Bucketing>().is_err());
+        assert!("hourly".parse::<Bucketing>().is_err());
+    }
+
+    #[test]
+    fn missing_strategy_is_legacy_count() {
+        #[derive(Deserialize)]
+        struct Conf {
+            #[serde(default)]
+            bucketing: Bucketing,
+        }
+        let conf: Conf = bson::from_document(bson::doc! {}).unwrap();
+        assert_eq!(conf.bucketing, Bucketing::default());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::bucketing::Bucketing;
+use crate::messaging::{RoomConfig, RoomHead};
+use matrix_macros::get_env;
+use matrix_metrics::MetricsWrapper;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// Max age of a cached bucket list
+///
+/// Buckets are validated against the newest bucket of the room on every read, the TTL only bounds how long a bucket
+/// that was created out of order (e.g. a daily bucket written by a worker with a lagging clock) stays invisible
+pub(crate) static ROOM_CACHE_TTL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(get_env!("ROOM_CACHE_TTL_SECS", 30, u64)));
+/// Rooms cached per instance, expired ones are evicted first once it is reached
+const MAX_ENTRIES: usize = 10_000;
+/// Collections remembered as indexed before the set is started over
+const MAX_INDEXED: usize = 100_000;
+
+#[derive(Debug)]
+struct Entry {
+    config: RoomConfig,
+    /// Sorted indices of the chat collections, like `MongoManager::chat_collections`
+    buckets: Vec<u32>,
+    fetched: Instant,
+}
+
+/// Per-instance cache of room configs and their chat collections
+///
+/// Every replica has its own cache, so nothing in here is trusted without checking the head of the room first
+#[derive(Debug)]
+pub(crate) struct RoomCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Namespaces whose indexes were created by this instance
+    indexed: Mutex<HashSet<String>>,
+    ttl: Duration,
+    metrics: MetricsWrapper,
+}
+
+impl RoomCache {
+    pub(crate) fn new(metrics: MetricsWrapper, ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            indexed: Default::default(),
+            ttl,
+            metrics,
+        }
+    }
+
+    /// Cached buckets of a room, if they contain the newest bucket according to `head`
+    pub(crate) fn buckets(&self, room: &str, head: &RoomHead) -> Option<Vec<u32>> {
+        let entries = self.entries.lock();
+        let hit = entries.get(room).filter(|e| {
+            e.fetched.elapsed() < self.ttl
+                && head
+                    .newest_bucket(e.config.bucketing)
+                    .is_none_or(|newest| e.buckets.binary_search(&newest).is_ok())
+        });
+
+        match hit {
+            Some(e) => {
+                trace!(room, "Room cache hit");
+                self.metrics.room_cache_hit();
+                Some(e.buckets.clone())
+            }
+            None => {
+                trace!(room, "Room cache miss");
+                self.metrics.room_cache_miss();
+                None
+            }
+        }
+    }
+
+    /// Bucketing of a cached room, it never changes, so the entry doesn't have to be fresh
+    pub(crate) fn bucketing(&self, room: &str) -> Option<Bucketing> {
+        self.entries.lock().get(room).map(|e| e.config.bucketing)
+    }
+
+    pub(crate) fn insert(&self, room: &str, config: RoomConfig, mut buckets: Vec<u32>) {
+        buckets.sort_unstable();
+        let entry = Entry {
+            config,
+            buckets,
+            fetched: Instant::now(),
+        };
+
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(room) {
+            entries.retain(|_, e| e.fetched.elapsed() < self.ttl);
+            // Everything is fresh, starting over only costs a round trip per room
+            if entries.len() >= MAX_ENTRIES {
+                trace!("Room cache is full");
+                entries.clear();
+            }
+        }
+        entries.insert(room.to_string(), entry);
+    }
+
+    /// Keeps an existing entry valid after this instance wrote to `bucket`
+    ///
+    /// The config comes straight from the write, so membership changes are picked up as well
+    pub(crate) fn record_write(&self, room: &str, config: RoomConfig, bucket: u32) {
+        let mut entries = self.entries.lock();
+        let Some(entry) = entries.get_mut(room) else {
+            return;
+        };
+        entry.config = config;
+        if let Err(pos) = entry.buckets.binary_search(&bucket) {
+            trace!(room, bucket, "Bucket rolled over");
+            entry.buckets.insert(pos, bucket);
+        }
+    }
+
+    pub(crate) fn invalidate(&self, room: &str) {
+        self.entries.lock().remove(room);
+    }
+
+    /// Whether the indexes of `namespace` still have to be created, claims them if so
+    ///
+    /// Creating an index is idempotent, forgetting about one only costs a round trip
+    pub(crate) fn claim_index(&self, namespace: &str) -> bool {
+        let mut indexed = self.indexed.lock();
+        if indexed.contains(namespace) {
+            return false;
+        }
+        if indexed.len() >= MAX_INDEXED {
+            indexed.clear();
+        }
+        indexed.insert(namespace.to_string())
+    }
+
+    /// Lets the next caller of [`Self::claim_index`] try again
+    pub(crate) fn release_index(&self, namespace: &str) {
+        self.indexed.lock().remove(namespace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucketing::DEFAULT_BUCKET_SIZE;
+    use bson::DateTime;
+    use matrix_metrics::Metrics;
+
+    const ROOM: &str = "room";
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn config(bucketing: Bucketing) -> RoomConfig {
+        RoomConfig {
+            allowed_users: vec!["user".to_string()],
+            bucketing,
+            public: false,
+            retention: None,
+        }
+    }
+
+    fn head(seq: i64, last_ts: DateTime) -> RoomHead {
+        RoomHead {
+            seq,
+            last_ts: Some(last_ts),
+        }
+    }
+
+    #[test]
+    fn hit_until_bucket_rolls_over() {
+        let metrics = Metrics::new();
+        let cache = RoomCache::new(metrics.clone(), TTL);
+        let now = DateTime::now();
+        assert_eq!(cache.buckets(ROOM, &head(1, now)), None);
+
+        cache.insert(ROOM, config(Bucketing::default()), vec![1, 0]);
+        assert_eq!(
+            cache.buckets(ROOM, &head(DEFAULT_BUCKET_SIZE, now)),
+            Some(vec![0, 1])
+        );
+        assert_eq!(
+            cache.buckets(ROOM, &head(DEFAULT_BUCKET_SIZE + 1, now)),
+            None
+        );
+
+        assert_eq!(metrics.get_room_cache_hits(), 1);
+        assert_eq!(metrics.get_room_cache_misses(), 2);
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        for room in 0..=MAX_ENTRIES {
+            cache.insert(&room.to_string(), config(Bucketing::default()), vec![0]);
+        }
+        assert!(cache.entries.lock().len() <= MAX_ENTRIES);
+        assert!(cache.bucketing(&MAX_ENTRIES.to_string()).is_some());
+    }
+
+    #[test]
+    fn expired_entries_are_evicted_first() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        for room in 0..MAX_ENTRIES {
+            cache.insert(&room.to_string(), config(Bucketing::default()), vec![0]);
+        }
+        for e in cache.entries.lock().values_mut().skip(1) {
+            e.fetched -= TTL;
+        }
+
+        cache.insert(ROOM, config(Bucketing::default()), vec![0]);
+        assert_eq!(cache.entries.lock().len(), 2);
+    }
+
+    #[test]
+    fn rollover_on_other_replica_is_a_miss() {
+        let (a, b) = (
+            RoomCache::new(Metrics::new(), TTL),
+            RoomCache::new(Metrics::new(), TTL),
+        );
+        let now = DateTime::now();
+        for cache in [&a, &b] {
+            cache.insert(ROOM, config(Bucketing::default()), vec![0, 1]);
+        }
+
+        // Replica a writes the first message of the second bucket
+        let rolled = head(DEFAULT_BUCKET_SIZE + 1, now);
+        a.record_write(ROOM, config(Bucketing::default()), 2);
+
+        assert_eq!(a.buckets(ROOM, &rolled), Some(vec![0, 1, 2]));
+        assert_eq!(b.buckets(ROOM, &rolled), None);
+    }
+
+    #[test]
+    fn pending_write_is_not_trusted() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        let rolled = head(DEFAULT_BUCKET_SIZE + 1, DateTime::now());
+
+        // The sequence number is taken before the message is inserted, so the bucket may not be listed yet
+        cache.insert(ROOM, config(Bucketing::default()), vec![0, 1]);
+        assert_eq!(cache.buckets(ROOM, &rolled), None);
+
+        cache.insert(ROOM, config(Bucketing::default()), vec![0, 1, 2]);
+        assert_eq!(cache.buckets(ROOM, &rolled), Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn time_buckets_roll_over_by_timestamp() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        let today = DateTime::now();
+        let tomorrow = DateTime::from_millis(today.timestamp_millis() + 24 * 60 * 60 * 1000);
+        let bucket = Bucketing::Daily.bucket(1, today);
+
+        cache.insert(ROOM, config(Bucketing::Daily), vec![0, bucket]);
+        assert!(cache.buckets(ROOM, &head(2, today)).is_some());
+        assert_eq!(cache.buckets(ROOM, &head(3, tomorrow)), None);
+    }
+
+    #[test]
+    fn expired_and_invalidated_entries_miss() {
+        let now = DateTime::now();
+        let expired = RoomCache::new(Metrics::new(), Duration::ZERO);
+        expired.insert(ROOM, config(Bucketing::Single), vec![0, 1]);
+        assert_eq!(expired.buckets(ROOM, &head(1, now)), None);
+
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        cache.insert(ROOM, config(Bucketing::Single), vec![0, 1]);
+        assert_eq!(cache.bucketing(ROOM), Some(Bucketing::Single));
+        cache.invalidate(ROOM);
+        assert_eq!(cache.buckets(ROOM, &head(1, now)), None);
+        assert_eq!(cache.bucketing(ROOM), None);
+    }
+
+    #[test]
+    fn writes_refresh_membership() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        cache.insert(ROOM, config(Bucketing::Single), vec![0, 1]);
+
+        let mut updated = config(Bucketing::Single);
+        updated.allowed_users.push("new".to_string());
+        cache.record_write(ROOM, updated, 1);
+
+        let entries = cache.entries.lock();
+        assert_eq!(entries[ROOM].config.allowed_users, ["user", "new"]);
+    }
+
+    #[test]
+    fn indexes_are_claimed_once() {
+        let cache = RoomCache::new(Metrics::new(), TTL);
+        assert!(cache.claim_index("room.chat_1"));
+        assert!(!cache.claim_index("room.chat_1"));
+        assert!(cache.claim_index("room.chat_2"));
+
+        cache.release_index("room.chat_1");
+        assert!(cache.claim_index("room.chat_1"));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::messaging::{Attachment, Message, MessageKind};
+use matrix_errors::ContentErr;
+use matrix_macros::get_env;
+use std::sync::LazyLock;
+
+/// Upper bound for the content of a single message, in chars
+pub static MAX_MESSAGE_CHARS: LazyLock<usize> =
+    LazyLock::new(|| get_env!("MAX_MESSAGE_CHARS", 4000, usize));
+/// Control characters that are still fine in a message
+const ALLOWED_CONTROL_CHARS: &[char] = &['\n', '\r', '\t'];
+/// A reaction is an emoji or a short word, not a message
+const MAX_REACTION_CHARS: usize = 32;
+pub const MAX_ATTACHMENTS: usize = 10;
+const MAX_ATTACHMENT_NAME_CHARS: usize = 255;
+const MAX_CLIENT_MSG_ID_CHARS: usize = 128;
+
+/// Rejects content that can't be stored or displayed sensibly
+pub fn check_content(content: &str) -> Result<(), ContentErr> {
+    check_content_with(content, *MAX_MESSAGE_CHARS)
+}
+
+/// [`check_content`] plus the rules of the message kind
+///
+/// References are only checked for presence here, whether they point to an earlier message is decided once the
+/// message has its sequence number
+pub fn check_message(message: &Message) -> Result<(), ContentErr> {
+    check_message_with(message, *MAX_MESSAGE_CHARS)
+}
+
+fn check_message_with(message: &Message, max_chars: usize) -> Result<(), ContentErr> {
+    if let Some(id) = &message.client_msg_id
+        && (id.is_empty()
+            || id.len() > MAX_CLIENT_MSG_ID_CHARS
+            || !id.chars().all(|c| c.is_ascii_graphic()))
+    {
+        return Err(ContentErr::InvalidClientMsgId(MAX_CLIENT_MSG_ID_CHARS));
+    }
+    match (message.kind, message.reply_to) {
+        (MessageKind::Reply | MessageKind::Reaction, None) => {
+            return Err(ContentErr::MissingReference(message.kind.as_str()));
+        }
+        (MessageKind::Text | MessageKind::System, Some(_)) => {
+            return Err(ContentErr::UnexpectedReference);
+        }
+        (_, Some(seq)) if seq <= 0 => return Err(ContentErr::InvalidReference(seq)),
+        _ => {}
+    }
+
+    if message.kind == MessageKind::Reaction {
+        if !message.attachments.is_empty() {
+            return Err(ContentErr::InvalidAttachment(
+                "reactions can't have attachments",
+            ));
+        }
+        return check_content_with(&message.content, MAX_REACTION_CHARS);
+    }
+
+    if message.attachments.len() > MAX_ATTACHMENTS {
+        return Err(ContentErr::InvalidAttachment("too many attachments"));
+    }
+    message.attachments.iter().try_for_each(check_attachment)?;
+
+    // The attachment is the message
+    if message.content.is_empty() && !message.attachments.is_empty() {
+        return Ok(());
+    }
+    check_content_with(&message.content, max_chars)
+}
+
+pub(crate) fn check_attachment(attachment: &Attachment) -> Result<(), ContentErr> {
+    let name = attachment.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ATTACHMENT_NAME_CHARS {
+        return Err(ContentErr::InvalidAttachment(
+            "the name has to be between 1 and 255 chars",
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ContentErr::InvalidAttachment(
+            "the name contains control characters",
+        ));
+    }
+    match attachment.mime.split_once('/') {
+        Some((kind, sub)) if !kind.is_empty() && !sub.is_empty() => {}
+        _ => return Err(ContentErr::InvalidAttachment("the mime type is invalid")),
+    }
+    if attachment.storage_key.is_empty() {
+        return Err(ContentErr::InvalidAttachment("the storage key is missing"));
+    }
+    Ok(())
+}
+
+fn check_content_with(content: &str, max_chars: usize) -> Result<(), ContentErr> {
+    if content.trim().is_empty() {
+        return Err(ContentErr::Empty);
+    }
+    let chars = content.chars().count();
+    if chars > max_chars {
+        return Err(ContentErr::TooLong(chars, max_chars));
+    }
+    if content
+        .chars()
+        .any(|c| c.is_control() && !ALLOWED_CONTROL_CHARS.contains(&c))
+    {
+        return Err(ContentErr::ControlCharacters);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 10;
+
+    fn message(kind: MessageKind, reply_to: Option<i64>, content: &str) -> Message {
+        Message {
+            content: content.to_string(),
+            kind,
+            reply_to,
+            ..Message::test(0)
+        }
+    }
+
+    fn attachment(name: &str, mime: &str) -> Attachment {
+        Attachment {
+            name: name.to_string(),
+            mime: mime.to_string(),
+            size: 1,
+            storage_key: "key".to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_regular_content() {
+        for content in [
+            "hi",
+            " padded ",
+            "line\nbreak",
+            "tab\there",
+            "crlf\r\n",
+            "üöäß🦀",
+        ] {
+            assert!(check_content_with(content, MAX).is_ok(), "{content:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_content() {
+        for content in ["", " ", "\n\t ", "\u{3000}"] {
+            assert!(matches!(
+                check_content_with(content, MAX),
+                Err(ContentErr::Empty)
+            ));
+        }
+    }
+
+    #[test]
+    fn counts_chars_not_bytes() {
+        assert!(check_content_with(&"🦀".repeat(MAX), MAX).is_ok());
+        assert!(matches!(
+            check_content_with(&"a".repeat(MAX + 1), MAX),
+            Err(ContentErr::TooLong(11, MAX))
+        ));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for content in [
+            "nul\0",
+            "bell\u{7}",
+            "esc\u{1b}[31m",
+            "del\u{7f}",
+            "c1\u{85}",
+        ] {
+            assert!(matches!(
+                check_content_with(content, MAX),
+                Err(ContentErr::ControlCharacters)
+            ));
+        }
+    }
+
+    #[test]
+    fn replies_and_reactions_need_a_reference() {
+        for kind in [MessageKind::Reply, MessageKind::Reaction] {
+            assert!(matches!(
+                check_message_with(&message(kind, None, "hi"), MAX),
+                Err(ContentErr::MissingReference(_))
+            ));
+            assert!(check_message_with(&message(kind, Some(1), "hi"), MAX).is_ok());
+            assert!(matches!(
+                check_message_with(&message(kind, Some(0), "hi"), MAX),
+                Err(ContentErr::InvalidReference(0))
+            ));
+        }
+        assert!(matches!(
+            check_message_with(&message(MessageKind::Text, Some(1), "hi"), MAX),
+            Err(ContentErr::UnexpectedReference)
+        ));
+    }
+
+    #[test]
+    fn reactions_are_short() {
+        let long = "a".repeat(MAX_REACTION_CHARS + 1);
+        assert!(matches!(
+            check_message_with(&message(MessageKind::Reaction, Some(1), &long), usize::MAX),
+            Err(ContentErr::TooLong(..))
+        ));
+
+        let mut reaction = message(MessageKind::Reaction, Some(1), "👍");
+        reaction.attachments.push(attachment("a.png", "image/png"));
+        assert!(matches!(
+            check_message_with(&reaction, MAX),
+            Err(ContentErr::InvalidAttachment(_))
+        ));
+    }
+
+    #[test]
+    fn attachments_can_replace_content() {
+        let mut msg = message(MessageKind::Text, None, "");
+        assert!(matches!(
+            check_message_with(&msg, MAX),
+            Err(ContentErr::Empty)
+        ));
+        msg.attachments.push(attachment("a.png", "image/png"));
+        assert!(check_message_with(&msg, MAX).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_attachments() {
+        for attachment in [
+            attachment("", "image/png"),
+            attachment(&"a".repeat(MAX_ATTACHMENT_NAME_CHARS + 1), "image/png"),
+            attachment("a\0.png", "image/png"),
+            attachment("a.png", "png"),
+            attachment("a.png", "image/"),
+            Attachment {
+                storage_key: String::new(),
+                ..attachment("a.png", "image/png")
+            },
+        ] {
+            let mut msg = message(MessageKind::Text, None, "hi");
+            msg.attachments.push(attachment.clone());
+            assert!(
+                matches!(
+                    check_message_with(&msg, MAX),
+                    Err(ContentErr::InvalidAttachment(_))
+                ),
+                "{attachment:?}"
+            );
+        }
+
+        let mut msg = message(MessageKind::Text, None, "hi");
+        msg.attachments = vec![attachment("a.png", "image/png"); MAX_ATTACHMENTS + 1];
+        assert!(check_message_with(&msg, MAX).is_err());
+    }
+
+    #[test]
+    fn checks_client_msg_id() {
+        let with_id = |id: &str| Message {
+            client_msg_id: Some(id.to_string()),
+            ..message(MessageKind::Text, None, "hi")
+        };
+        assert!(check_message_with(&with_id("3f2a-01"), MAX).is_ok());
+        for id in [
+            String::new(),
+            "a b".to_string(),
+            "ü".to_string(),
+            "a".repeat(MAX_CLIENT_MSG_ID_CHARS + 1),
+        ] {
+            assert!(matches!(
+                check_message_with(&with_id(&id), MAX),
+                Err(ContentErr::InvalidClientMsgId(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::bucketing::DEFAULT_BUCKETING;
+use crate::messaging::RoomConfig;
+use crate::room::RoomName;
+use anyhow::{Context, Result, bail};
+use matrix_errors::MatrixErr;
+use serde::Serialize;
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+/// Namespace of the names of direct rooms, changing it orphans every existing one
+const DIRECT_NAMESPACE: Uuid = Uuid::from_u128(0x6d61_7472_6978_2d64_6972_6563_7400_0001);
+const DIRECT_SUFFIX: &str = "-dm";
+/// Characters of canonical room names that sort like their bytes, the digits of the hash in names
+const NAME_DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// The room two users talk in
+#[derive(Debug, Serialize)]
+pub struct DirectRoom {
+    pub room: String,
+    /// `false` if the room existed already
+    pub created: bool,
+}
+
+impl MongoManager {
+    /// Creates the direct room of `user` and `target`, or returns the existing one
+    ///
+    /// The name only depends on the pair, so it is routed like any other room and both users end up in the same one
+    #[instrument]
+    pub async fn open_direct(user: &str, target: &str) -> Result<DirectRoom> {
+        let members = direct_members(user, target)?;
+        let room = direct_room_name(&members[0], &members[1]);
+
+        let config = RoomConfig {
+            allowed_users: members.to_vec(),
+            bucketing: *DEFAULT_BUCKETING,
+            public: false,
+            retention: None,
+        };
+        let err = match Self::add_any_room(room.clone(), config).await {
+            Ok(room) => {
+                debug!(room, "Created direct room");
+                return Ok(DirectRoom {
+                    room,
+                    created: true,
+                });
+            }
+            Err(e) => e,
+        };
+        if !matches!(
+            err.chain().find_map(|c| c.downcast_ref::<MatrixErr>()),
+            Some(MatrixErr::RoomAlreadyExists(_))
+        ) {
+            return Err(err);
+        }
+
+        // Someone could have created a regular room with the same name before
+        let manager = mappings::write_manager(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+        let state = manager
+            .member_state(&room, user)
+            .await
+            .context("Unable to check direct room")
+            .map_err(|e| fritz!(manager, e))?;
+        match state {
+            Ok(state) if is_direct(&state.config, &members) => Ok(DirectRoom {
+                room: room.to_string(),
+                created: false,
+            }),
+            Ok(_) | Err(MatrixErr::NotInRoom(_)) => {
+                bail!(MatrixErr::RoomAlreadyExists(room.to_string()))
+            }
+            Err(e) => bail!(e),
+        }
+    }
+}
+
+/// Both users sorted, so either of them gets the same room
+fn direct_members(user: &str, target: &str) -> Result<[String; 2], MatrixErr> {
+    if user.is_empty() || target.is_empty() {
+        return Err(MatrixErr::InvalidDirect("user names can't be empty"));
+    }
+    if user == target {
+        return Err(MatrixErr::InvalidDirect("users can't message themselves"));
+    }
+    let mut members = [user.to_string(), target.to_string()];
+    members.sort();
+    Ok(members)
+}
+
+/// Whether the name belongs to a direct room, regular rooms can't be created with one
+pub(crate) fn is_direct_name(room: &str) -> bool {
+    room.ends_with(DIRECT_SUFFIX)
+}
+
+/// Base-36 digits of a hash of the pair, so direct rooms spread evenly over `0` up to `z`
+///
+/// The least significant digit comes first, each leading character is about as likely as any other. Instances get
+/// the share of direct rooms their range has of `[0-9a-z]`, boundaries outside of it don't split them any further
+fn direct_room_name(first: &str, second: &str) -> RoomName {
+    // Length prefixed, so no two pairs share a key
+    let mut key = Vec::with_capacity(first.len() + second.len() + 16);
+    for name in [first, second] {
+        key.extend_from_slice(&(name.len() as u64).to_be_bytes());
+        key.extend_from_slice(name.as_bytes());
+    }
+    let mut hash = Uuid::new_v5(&DIRECT_NAMESPACE, &key).as_u128();
+    let mut name = String::with_capacity(25 + DIRECT_SUFFIX.len());
+    while hash > 0 {
+        name.push(NAME_DIGITS[(hash % 36) as usize] as char);
+        hash /= 36;
+    }
+    name.push_str(DIRECT_SUFFIX);
+    RoomName::parse(&name).expect("direct room names are valid")
+}
+
+fn is_direct(config: &RoomConfig, members: &[String; 2]) -> bool {
+    let mut allowed = config.allowed_users.clone();
+    allowed.sort();
+    allowed.dedup();
+    !config.public && allowed == members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucketing::Bucketing;
+
+    #[test]
+    fn names_depend_on_the_pair_only() {
+        let [a, b] = direct_members("bob", "alice").unwrap();
+        let [c, d] = direct_members("alice", "bob").unwrap();
+        assert_eq!(direct_room_name(&a, &b), direct_room_name(&c, &d));
+        assert!(direct_room_name(&a, &b).ends_with(DIRECT_SUFFIX));
+
+        assert_ne!(
+            direct_room_name("ab", "c"),
+            direct_room_name("a", "bc"),
+            "Pairs with the same concatenation collided"
+        );
+        // Room names are case-insensitive, user names aren't
+        assert_ne!(
+            direct_room_name("Alice", "bob"),
+            direct_room_name("alice", "bob")
+        );
+    }
+
+    #[test]
+    fn names_spread_over_letter_boundaries() {
+        // Ranges of four instances, the first also gets the digits
+        const BOUNDARIES: [&str; 4] = ["", "g", "n", "t"];
+        const PAIRS: usize = 36_000;
+        let mut rooms = [0_usize; BOUNDARIES.len()];
+        for i in 0..PAIRS {
+            let room = direct_room_name("alice", &format!("user{i}"));
+            rooms[BOUNDARIES.partition_point(|b| *b <= room.as_str()) - 1] += 1;
+        }
+
+        let expected = [16, 7, 6, 7].map(|chars| PAIRS * chars / NAME_DIGITS.len());
+        for ((from, rooms), expected) in BOUNDARIES.iter().zip(rooms).zip(expected) {
+            assert!(
+                rooms.abs_diff(expected) < expected / 10,
+                "{rooms} direct rooms from {from:?}, expected about {expected}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_names_are_reserved() {
+        let [a, b] = direct_members("alice", "bob").unwrap();
+        let room = direct_room_name(&a, &b);
+        assert!(is_direct_name(&room));
+        let config = RoomConfig {
+            allowed_users: vec!["eve".to_string()],
+            bucketing: Bucketing::Single,
+            public: false,
+            retention: None,
+        };
+
+        for name in [room.as_str(), "team-dm", "Team-DM"] {
+            let err = MongoManager::add_room(name, config.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<MatrixErr>(),
+                Some(MatrixErr::IllegalRoomName(..))
+            ));
+        }
+        assert!(!is_direct_name("dm-team"));
+    }
+
+    #[test]
+    fn rejects_invalid_pairs() {
+        assert!(direct_members("alice", "alice").is_err());
+        assert!(direct_members("", "bob").is_err());
+        // Any user name works, the room name doesn't contain it
+        assert!(direct_members("a.b c/d", "bob").is_ok());
+        direct_room_name("a.b c/d", &"x".repeat(1000));
+    }
+
+    #[test]
+    fn recognizes_direct_rooms() {
+        let members = direct_members("alice", "bob").unwrap();
+        let config = |users: &[&str], public| RoomConfig {
+            allowed_users: users.iter().map(|u| u.to_string()).collect(),
+            bucketing: Bucketing::Single,
+            public,
+            retention: None,
+        };
+        assert!(is_direct(&config(&["bob", "alice"], false), &members));
+        assert!(!is_direct(&config(&["bob", "alice"], true), &members));
+        assert!(!is_direct(
+            &config(&["bob", "alice", "eve"], false),
+            &members
+        ));
+        assert!(!is_direct(&config(&["alice"], false), &members));
+    }
+}
@@ -0,0 +1,528 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::layout::SHARED_DB;
+use crate::messaging::{CHAT_PREFIX, MOVED_KEY, Message, RoomConfig};
+use crate::room::{INVALID_ROOM_NAMES, RoomName};
+use anyhow::{Context, Result};
+use bson::{Document, doc};
+use futures::{StreamExt, TryStreamExt, stream};
+use matrix_errors::MatrixErr;
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use tracing::{debug, info, instrument, warn};
+
+/// One document per room, on the instance that holds the metadata of the room
+const ROOM_INDEX_COL: &str = "room_index";
+/// Rooms whose newest message is read at the same time
+const PREVIEW_LOOKUPS: usize = 16;
+pub const DEFAULT_DIRECTORY_LIMIT: usize = 50;
+pub const MAX_DIRECTORY_LIMIT: usize = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    #[serde(rename = "_id")]
+    room: String,
+    allowed_users: Vec<String>,
+    #[serde(default)]
+    public: bool,
+}
+
+impl IndexEntry {
+    fn new(room: &str, config: &RoomConfig) -> Self {
+        Self {
+            room: room.to_string(),
+            allowed_users: config.allowed_users.clone(),
+            public: config.public,
+        }
+    }
+}
+
+/// A room of a user
+#[derive(Debug, Serialize)]
+pub struct RoomSummary {
+    pub room: String,
+    pub public: bool,
+    /// Newest message, `None` if the room is still empty
+    pub last_message: Option<Message>,
+}
+
+/// A room of the public directory
+#[derive(Debug, Serialize)]
+pub struct DirectoryEntry {
+    pub room: String,
+    pub members: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct IndexReport {
+    pub indexed: usize,
+    pub failed: Vec<String>,
+}
+
+impl MongoManager {
+    /// Rooms `user` is a member of, most recently active first
+    ///
+    /// Asks every regular and migration instance, rooms that aren't routed to the instance holding their entry anymore
+    /// are left out
+    #[instrument]
+    pub async fn list_rooms(user: &str) -> Result<Vec<RoomSummary>> {
+        let entries = Self::find_entries(doc! { "allowed_users": user }, None).await?;
+
+        let mut rooms = stream::iter(entries)
+            .map(|entry| async move {
+                match Self::read_messages(&entry.room, 1, None).await {
+                    Ok((mut messages, _)) => Ok(Some(RoomSummary {
+                        room: entry.room,
+                        public: entry.public,
+                        last_message: messages.pop(),
+                    })),
+                    Err(e) if is_not_found(&e) => {
+                        debug!(entry.room, "Skipping room that isn't routed to its entry");
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+            .buffer_unordered(PREVIEW_LOOKUPS)
+            .try_filter_map(|room| async move { Ok(room) })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        sort_by_activity(&mut rooms);
+        Ok(rooms)
+    }
+
+    /// Public rooms sorted by name, starting after the room `after`
+    #[instrument]
+    pub async fn public_rooms(after: Option<&str>, limit: usize) -> Result<Vec<DirectoryEntry>> {
+        let limit = limit.clamp(1, MAX_DIRECTORY_LIMIT);
+        let mut filter = doc! { "public": true };
+        if let Some(after) = after {
+            filter.insert("_id", doc! { "$gt": after });
+        }
+
+        let mut entries = Self::find_entries(filter, Some(limit)).await?;
+        entries.truncate(limit);
+        Ok(entries
+            .into_iter()
+            .map(|entry| DirectoryEntry {
+                room: entry.room,
+                members: entry.allowed_users.len(),
+            })
+            .collect())
+    }
+
+    /// Entries of every instance, one per room sorted by name
+    async fn find_entries(filter: bson::Document, limit: Option<usize>) -> Result<Vec<IndexEntry>> {
+        let managers = mappings::all_managers().await;
+        let results = futures::future::join_all(
+            managers
+                .iter()
+                .map(|m| m.find_entries_on_instance(filter.clone(), limit)),
+        )
+        .await;
+
+        let mut by_room = BTreeMap::new();
+        for (manager, res) in managers.iter().zip(results) {
+            let entries = res
+                .context("Unable to read room index")
+                .map_err(|e| fritz!(manager, e))?;
+            merge_entries(&mut by_room, entries);
+        }
+        Ok(by_room.into_values().collect())
+    }
+
+    async fn find_entries_on_instance(
+        &self,
+        filter: bson::Document,
+        limit: Option<usize>,
+    ) -> Result<Vec<IndexEntry>> {
+        let index = self.room_index().await?;
+        let mut find = index.find(filter).sort(doc! { "_id": 1 });
+        if let Some(limit) = limit {
+            find = find.limit(limit as i64);
+        }
+        find.await
+            .context("Unable to find rooms")?
+            .try_collect()
+            .await
+            .context("Unable to read rooms")
+    }
+
+    /// Rooms of `user` with an entry on this instance, in both layouts
+    pub(crate) async fn indexed_rooms(&self, user: &str) -> Result<Vec<String>> {
+        let entries = self
+            .find_entries_on_instance(doc! { "allowed_users": user }, None)
+            .await?;
+        Ok(entries.into_iter().map(|entry| entry.room).collect())
+    }
+
+    /// Adds or replaces the entry of a room, called once the room was created
+    #[instrument(skip(self, config))]
+    pub(crate) async fn index_room(&self, room: &str, config: &RoomConfig) -> Result<()> {
+        self.room_index()
+            .await?
+            .replace_one(doc! { "_id": room }, IndexEntry::new(room, config))
+            .upsert(true)
+            .await
+            .context("Unable to index room")?;
+        Ok(())
+    }
+
+    /// Copies the entry of a migrating room from the instance it is moved away from, once per worker
+    ///
+    /// Called after writes, rooms created during the migration were indexed here already
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    pub(crate) async fn adopt_entry(&self, room: &RoomName, old: &MongoManager) -> Result<()> {
+        if self.url == old.url {
+            return Ok(());
+        }
+        let adopted = format!("{SHARED_DB}.{ROOM_INDEX_COL}.{room}");
+        if !self.cache.claim_index(&adopted) {
+            return Ok(());
+        }
+
+        let res = async {
+            let entry = old
+                .room_index()
+                .await?
+                .find_one(doc! { "_id": room.as_str() })
+                .await
+                .context("Unable to get entry")
+                .map_err(|e| fritz!(old, e))?;
+            if let Some(entry) = entry {
+                self.merge_entry(&entry).await?;
+                debug!("Adopted entry of migrating room");
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if res.is_err() {
+            self.cache.release_index(&adopted);
+        }
+        res
+    }
+
+    /// Moves the entries of this instance to the instance their room is written to now
+    ///
+    /// Run after the instance was removed or its migration ended, entries of rooms still written here stay
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    pub async fn relocate_entries(&self) -> Result<IndexReport> {
+        let index = self.room_index().await?;
+        let entries = index
+            .find(doc! {})
+            .await
+            .context("Unable to find rooms")?
+            .try_collect::<Vec<_>>()
+            .await
+            .context("Unable to read rooms")?;
+
+        let mut report = IndexReport::default();
+        for entry in entries {
+            let moved = async {
+                let room = RoomName::parse(&entry.room)?;
+                let target = mappings::write_manager(&room).await?;
+                if target.url == self.url {
+                    return anyhow::Ok(false);
+                }
+                self.move_entry(&entry, &target).await?;
+                Ok(true)
+            }
+            .await;
+            match moved {
+                Ok(true) => report.indexed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(entry.room, ?e, "Unable to move entry");
+                    report.failed.push(entry.room);
+                }
+            }
+        }
+
+        info!(
+            moved = report.indexed,
+            failed = report.failed.len(),
+            "Relocated room index"
+        );
+        Ok(report)
+    }
+
+    /// Merges the entry into the one of `target`, then removes it here
+    async fn move_entry(&self, entry: &IndexEntry, target: &MongoManager) -> Result<()> {
+        target.merge_entry(entry).await?;
+        self.room_index()
+            .await?
+            .delete_one(doc! { "_id": &entry.room })
+            .await
+            .context("Unable to remove moved entry")?;
+        Ok(())
+    }
+
+    /// Adds the members of `entry` to the entry of its room on this instance, creating it if needed
+    async fn merge_entry(&self, entry: &IndexEntry) -> Result<()> {
+        self.room_index()
+            .await?
+            .update_one(doc! { "_id": &entry.room }, merge_update(entry))
+            .upsert(true)
+            .await
+            .context("Unable to merge entry")
+            .map_err(|e| fritz!(self, e))?;
+        Ok(())
+    }
+
+    /// Indexes every room of the instance, for rooms created before the index existed or whose entry failed
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    pub async fn index_rooms(&self) -> Result<IndexReport> {
+        let mut rooms = vec![];
+
+        let mut shared = self
+            .rooms()?
+            .clone_with_type::<IndexEntry>()
+            .find(doc! {})
+            .await
+            .context("Unable to find shared rooms")?;
+        while let Some(entry) = shared.try_next().await.context("Unable to read room")? {
+            rooms.push(entry);
+        }
+
+        let names = backoff!(self)
+            .list_database_names()
+            .await
+            .context("Unable to list databases")?
+            .into_iter()
+            .filter(|name| !INVALID_ROOM_NAMES.contains(&name.as_str()));
+        let mut report = IndexReport::default();
+        for room in names {
+            match self.per_room_entry(&room).await {
+                Ok(Some(entry)) => rooms.push(entry),
+                // Converted to the shared layout, or not a room at all
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(room, ?e, "Unable to read room config");
+                    report.failed.push(room);
+                }
+            }
+        }
+
+        let index = self.room_index().await?;
+        for entry in rooms {
+            match index
+                .replace_one(doc! { "_id": &entry.room }, &entry)
+                .upsert(true)
+                .await
+            {
+                Ok(_) => report.indexed += 1,
+                Err(e) => {
+                    warn!(entry.room, ?e, "Unable to index room");
+                    report.failed.push(entry.room);
+                }
+            }
+        }
+
+        info!(
+            indexed = report.indexed,
+            failed = report.failed.len(),
+            "Indexed rooms"
+        );
+        Ok(report)
+    }
+
+    async fn per_room_entry(&self, room: &str) -> Result<Option<IndexEntry>> {
+        let config = backoff!(self)
+            .database(room)
+            .collection::<RoomConfig>(&format!("{CHAT_PREFIX}_0"))
+            .find_one(doc! { MOVED_KEY: { "$exists": false } })
+            .await
+            .context("Unable to get config")?;
+        Ok(config.map(|config| IndexEntry::new(room, &config)))
+    }
+
+    /// The room index, with its indexes created once per instance
+    async fn room_index(&self) -> Result<Collection<IndexEntry>> {
+        let col = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<IndexEntry>(ROOM_INDEX_COL);
+
+        let namespace = format!("{SHARED_DB}.{ROOM_INDEX_COL}");
+        if self.cache.claim_index(&namespace) {
+            let members = IndexModel::builder()
+                .keys(doc! { "allowed_users": 1 })
+                .build();
+            let public = IndexModel::builder()
+                .keys(doc! { "public": 1, "_id": 1 })
+                .build();
+            if let Err(e) = col.create_indexes([members, public]).await {
+                self.cache.release_index(&namespace);
+                return Err(e).context("Unable to create room index indexes");
+            }
+        }
+
+        Ok(col)
+    }
+}
+
+/// Rooms created twice during a migration have an entry on both instances, their members are combined
+fn merge_entries(by_room: &mut BTreeMap<String, IndexEntry>, entries: Vec<IndexEntry>) {
+    for entry in entries {
+        match by_room.get_mut(&entry.room) {
+            Some(existing) => {
+                existing.public |= entry.public;
+                for user in entry.allowed_users {
+                    if !existing.allowed_users.contains(&user) {
+                        existing.allowed_users.push(user);
+                    }
+                }
+            }
+            None => {
+                by_room.insert(entry.room.clone(), entry);
+            }
+        }
+    }
+}
+
+/// Update for [`merge_entries`] in place, for entries moved between instances
+fn merge_update(entry: &IndexEntry) -> Document {
+    doc! {
+        "$addToSet": { "allowed_users": { "$each": &entry.allowed_users } },
+        // `true` sorts after `false`
+        "$max": { "public": entry.public },
+    }
+}
+
+/// Newest message first, empty rooms last and rooms with the same activity by name
+fn sort_by_activity(rooms: &mut [RoomSummary]) {
+    rooms.sort_by(|a, b| {
+        let ts = |r: &RoomSummary| Reverse(r.last_message.as_ref().map(|m| m.timestamp));
+        ts(a).cmp(&ts(b)).then_with(|| a.room.cmp(&b.room))
+    });
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.chain().find_map(|c| c.downcast_ref::<MatrixErr>()),
+        Some(MatrixErr::RoomNotFound(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::DateTime;
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn entry(room: &str, users: &[&str], public: bool) -> IndexEntry {
+        IndexEntry {
+            room: room.to_string(),
+            allowed_users: users.iter().map(|u| u.to_string()).collect(),
+            public,
+        }
+    }
+
+    fn summary(room: &str, ts: Option<i64>) -> RoomSummary {
+        RoomSummary {
+            room: room.to_string(),
+            public: false,
+            last_message: ts.map(|ts| Message {
+                timestamp: DateTime::from_millis(ts),
+                ..Message::test(1)
+            }),
+        }
+    }
+
+    #[test]
+    fn merges_entries_of_instances() {
+        let mut by_room = BTreeMap::new();
+        merge_entries(
+            &mut by_room,
+            vec![entry("b", &["x"], false), entry("a", &["x"], false)],
+        );
+        merge_entries(&mut by_room, vec![entry("b", &["x", "y"], true)]);
+
+        let merged = by_room.into_values().collect::<Vec<_>>();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].room, "a");
+        assert_eq!(merged[1].allowed_users, ["x", "y"]);
+        assert!(merged[1].public);
+    }
+
+    #[test]
+    fn moved_entries_are_merged() {
+        let update = merge_update(&entry("b", &["x", "y"], true));
+        assert_eq!(
+            update,
+            doc! {
+                "$addToSet": { "allowed_users": { "$each": ["x", "y"] } },
+                "$max": { "public": true },
+            }
+        );
+        // A private entry never hides a public one
+        let update = merge_update(&entry("b", &[], false));
+        assert_eq!(
+            update.get_document("$max").unwrap(),
+            &doc! { "public": false }
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs two local Mongo instances at MONGO_URL and MONGO_OTHER_URL"]
+    async fn entries_follow_their_room() {
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        let mut managers = vec![];
+        for var in ["MONGO_URL", "MONGO_OTHER_URL"] {
+            let url = std::env::var(var).unwrap();
+            managers.push(
+                MongoManager::new(&url, Uuid::new_v4(), err_tx.clone(), Metrics::new()).await,
+            );
+        }
+        let (old, new) = (&managers[0], &managers[1]);
+        let room = RoomName::parse(&format!("index-{}", Uuid::new_v4().simple())).unwrap();
+        let get = |manager: &MongoManager| {
+            let room = room.to_string();
+            let manager = manager.clone();
+            async move {
+                let index = manager.room_index().await.unwrap();
+                index.find_one(doc! { "_id": room }).await.unwrap()
+            }
+        };
+
+        // Created twice during a migration, the old entry is adopted by the first write on the new instance
+        old.merge_entry(&entry(&room, &["x"], true)).await.unwrap();
+        new.merge_entry(&entry(&room, &["y"], false)).await.unwrap();
+        new.adopt_entry(&room, old).await.unwrap();
+        let adopted = get(new).await.unwrap();
+        assert_eq!(adopted.allowed_users, ["y", "x"]);
+        assert!(adopted.public);
+        assert!(get(old).await.is_some());
+
+        // Moved back once the migration is cancelled
+        new.move_entry(&adopted, old).await.unwrap();
+        assert!(get(new).await.is_none());
+        let moved = get(old).await.unwrap();
+        assert_eq!(moved.allowed_users, ["x", "y"]);
+        assert!(moved.public);
+
+        old.room_index()
+            .await
+            .unwrap()
+            .delete_one(doc! { "_id": room.as_str() })
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn sorts_by_newest_message() {
+        let mut rooms = [
+            summary("empty", None),
+            summary("old", Some(1)),
+            summary("b", Some(5)),
+            summary("a", Some(5)),
+        ];
+        sort_by_activity(&mut rooms);
+        let names = rooms.iter().map(|r| r.room.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "old", "empty"]);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::layout::{Layout, MESSAGES_COL, ROOMS_COL, SHARED_DB};
+use crate::messaging::{CHAT_PREFIX, Message};
+use crate::room::RoomName;
+use crate::{Health, MongoManager, mappings};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::{Span, debug, instrument, warn};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct RouteExplanation {
+    pub room: RoomName,
+    pub instance: InstanceExplanation,
+    /// Set while a migration covers the room, writes go here and reads are merged from both
+    pub migration_instance: Option<InstanceExplanation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstanceExplanation {
+    pub id: Uuid,
+    pub health: Health,
+    pub in_state_for_ms: u128,
+    /// Unset if the layout could not be fetched
+    pub layout: Option<Layout>,
+    /// Empty if the room does not exist on this instance
+    pub buckets: Vec<Bucket>,
+    /// Set if the buckets could not be listed, the rest of the explanation is still valid
+    pub err: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub name: String,
+    pub documents: u64,
+}
+
+impl MongoManager {
+    /// Explain where a room lives, for debugging only
+    ///
+    /// Failures on a single instance are reported in the explanation instead of tripping the instance
+    #[instrument(skip_all, fields(room))]
+    pub async fn explain_route(room: &str) -> Result<RouteExplanation> {
+        let room = RoomName::parse(room)?;
+        Span::current().record("room", room.as_str());
+
+        let (manager, migration_manager) = match mappings::read_manager(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?
+        {
+            either::Left(manager) => (manager, None),
+            either::Right((manager, mig_m)) => (manager, Some(mig_m)),
+        };
+
+        let (instance, migration_instance) = tokio::join!(manager.explain(&room), async {
+            match &migration_manager {
+                Some(mig_m) => Some(mig_m.explain(&room).await),
+                None => None,
+            }
+        });
+
+        Ok(RouteExplanation {
+            room,
+            instance,
+            migration_instance,
+        })
+    }
+
+    #[instrument(skip(self, room), fields(id = ?self.db_id))]
+    async fn explain(&self, room: &str) -> InstanceExplanation {
+        let res = async {
+            let layout = self.layout().await?;
+            let buckets = self.buckets(room, layout).await?;
+            anyhow::Ok((layout, buckets))
+        };
+        let (layout, buckets, err) = match res.await {
+            Ok((layout, buckets)) => (Some(layout), buckets, None),
+            Err(e) => {
+                warn!(?e, "Unable to list buckets");
+                (None, vec![], Some(format!("{e:#}")))
+            }
+        };
+
+        InstanceExplanation {
+            id: self.db_id,
+            health: self.health(),
+            in_state_for_ms: self.breaker.in_state_for().as_millis(),
+            layout,
+            buckets,
+            err,
+        }
+    }
+
+    /// Buckets of the room, a room that is being converted to the shared layout has both kinds
+    #[instrument(skip_all)]
+    async fn buckets(&self, room: &str, layout: Layout) -> Result<Vec<Bucket>> {
+        let indices = self.chat_collections(room).await?;
+        let client = backoff!(self);
+        let db = client.database(room);
+
+        let mut buckets = Vec::with_capacity(indices.len() + 1);
+        if layout == Layout::Shared {
+            let shared_db = client.database(SHARED_DB);
+            let exists = shared_db
+                .collection::<bson::Document>(ROOMS_COL)
+                .find_one(bson::doc! { "_id": room })
+                .await
+                .context("Can't get shared room")?
+                .is_some();
+            if exists {
+                let documents = shared_db
+                    .collection::<Message>(MESSAGES_COL)
+                    .count_documents(bson::doc! { "room": room })
+                    .await
+                    .context("Can't count shared messages")?;
+                let name = format!("{SHARED_DB}.{MESSAGES_COL}");
+                debug!(name, documents, "Counted shared bucket");
+                buckets.push(Bucket { name, documents });
+            }
+        }
+        for index in indices {
+            let name = format!("{CHAT_PREFIX}_{index}");
+            let documents = db
+                .collection::<Message>(&name)
+                .count_documents(bson::doc! {})
+                .await
+                .with_context(|| format!("Can't count documents in {name:?}"))?;
+            debug!(name, documents, "Counted bucket");
+            buckets.push(Bucket { name, documents });
+        }
+
+        Ok(buckets)
+    }
+}
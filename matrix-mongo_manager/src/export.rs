@@ -0,0 +1,849 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::attachment::{FileMeta, MAX_ATTACHMENT_BYTES, sniff_mime};
+use crate::content::check_attachment;
+use crate::layout::{Layout, MESSAGES_COL, SHARED_DB};
+use crate::messaging::{
+    Attachment, CHAT_PREFIX, MOVED_KEY, Message, RoomConfig, RoomHead, RoomState, SEQ_KEY,
+    check_config,
+};
+use crate::room::RoomName;
+use crate::shared::{LEGACY_KEY, SharedMessage};
+use anyhow::{Context, Result, bail};
+use bson::spec::BinarySubtype;
+use bson::{Binary, DateTime, Document, doc};
+use futures::{Stream, TryStreamExt};
+use matrix_errors::MatrixErr;
+use matrix_macros::get_env;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tokio::sync::mpsc;
+use tracing::{Instrument, debug, info, instrument, warn};
+use uuid::Uuid;
+
+/// Bumped whenever the records change incompatibly
+pub const EXPORT_VERSION: u32 = 2;
+/// Largest import the admin API accepts
+pub static MAX_IMPORT_BYTES: LazyLock<usize> =
+    LazyLock::new(|| get_env!("MAX_IMPORT_BYTES", 1024 * 1024 * 1024, usize));
+/// Records an export runs ahead of its reader
+const EXPORT_BUFFER: usize = 256;
+const IMPORT_BATCH: usize = 1000;
+/// Mongo documents are at most 16 MiB, records add a few bytes around them
+const MAX_RECORD_BYTES: usize = 17 * 1024 * 1024;
+
+/// How exports are encoded, one record after another
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// One JSON object per line, messages in extended JSON
+    #[default]
+    JsonLines,
+    /// Concatenated BSON documents, like `mongodump` writes them
+    Bson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "application/x-ndjson",
+            ExportFormat::Bson => "application/bson",
+        }
+    }
+
+    pub fn encode(&self, record: &ExportRecord) -> Result<Vec<u8>> {
+        match self {
+            ExportFormat::JsonLines => {
+                let mut line = serde_json::to_vec(record).context("Unable to encode record")?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            ExportFormat::Bson => bson::to_vec(record).context("Unable to encode record"),
+        }
+    }
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::JsonLines => write!(f, "jsonl"),
+            ExportFormat::Bson => write!(f, "bson"),
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            "bson" => Ok(ExportFormat::Bson),
+            _ => bail!("Unknown export format {s:?}, expected jsonl or bson"),
+        }
+    }
+}
+
+/// One entry of an export, the room comes first and its messages follow in the order they were written
+///
+/// Receipts and attachments come after the messages. The sends the room still remembers by client message id are not
+/// exported, retries of sends from before the export are written again
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Room {
+        version: u32,
+        room: String,
+        config: RoomConfig,
+        seq: i64,
+        last_ts: Option<DateTime>,
+    },
+    Message {
+        /// Index of the `chat_N` collection the message was stored in
+        bucket: u32,
+        message: Message,
+    },
+    /// Newest message `user` has read
+    Receipt { user: String, seq: i64 },
+    /// File of an attachment, referenced by `storage_key` in the messages
+    Attachment {
+        key: String,
+        author: String,
+        name: String,
+        #[serde(with = "file_data")]
+        data: Binary,
+    },
+}
+
+/// Files are binary in BSON and `$binary` in extended JSON, serde would write them as an array of numbers
+mod file_data {
+    use bson::{Binary, Bson};
+    use serde::de::{Error, MapAccess, Visitor, value::MapAccessDeserializer};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt::Formatter;
+
+    pub(super) fn serialize<S: Serializer>(
+        data: &Binary,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            Bson::Binary(data.clone())
+                .into_canonical_extjson()
+                .serialize(serializer)
+        } else {
+            data.serialize(serializer)
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Binary, D::Error> {
+        deserializer.deserialize_any(FileVisitor)
+    }
+
+    struct FileVisitor;
+
+    impl<'de> Visitor<'de> for FileVisitor {
+        type Value = Binary;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            write!(f, "binary data")
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Binary, E> {
+            Ok(Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: bytes.to_vec(),
+            })
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Binary, A::Error> {
+            match Bson::deserialize(MapAccessDeserializer::new(map))? {
+                Bson::Binary(data) => Ok(data),
+                other => Err(A::Error::custom(format!(
+                    "expected binary data, got {other}"
+                ))),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub room: String,
+    pub messages: usize,
+    pub buckets: usize,
+    pub receipts: usize,
+    pub attachments: usize,
+}
+
+/// Splits a byte stream of the given format into records, no matter where the chunks end
+#[derive(Debug)]
+pub struct RecordDecoder {
+    format: ExportFormat,
+    buf: Vec<u8>,
+}
+
+impl RecordDecoder {
+    pub fn new(format: ExportFormat) -> Self {
+        Self {
+            format,
+            buf: vec![],
+        }
+    }
+
+    /// The records that are complete with `chunk`
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<ExportRecord>, MatrixErr> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut records = vec![];
+        let mut start = 0;
+        match self.format {
+            ExportFormat::JsonLines => {
+                while let Some(len) = self.buf[start..].iter().position(|&b| b == b'\n') {
+                    let line = &self.buf[start..start + len];
+                    start += len + 1;
+                    if let Some(record) = parse_line(line)? {
+                        records.push(record);
+                    }
+                }
+                if self.buf.len() - start > MAX_RECORD_BYTES {
+                    return Err(invalid("a line is too long"));
+                }
+            }
+            ExportFormat::Bson => {
+                while let Some(len_bytes) = self.buf.get(start..start + 4) {
+                    let len = i32::from_le_bytes(len_bytes.try_into().unwrap());
+                    let len = usize::try_from(len).unwrap_or_default();
+                    if !(5..=MAX_RECORD_BYTES).contains(&len) {
+                        return Err(invalid("a document has an invalid length"));
+                    }
+                    let Some(document) = self.buf.get(start..start + len) else {
+                        break;
+                    };
+                    records.push(bson::from_slice(document).map_err(|e| invalid(e.to_string()))?);
+                    start += len;
+                }
+            }
+        }
+
+        self.buf.drain(..start);
+        Ok(records)
+    }
+
+    /// The last record, if the export doesn't end with a newline
+    pub fn finish(self) -> Result<Option<ExportRecord>, MatrixErr> {
+        match self.format {
+            ExportFormat::JsonLines => parse_line(&self.buf),
+            ExportFormat::Bson if self.buf.is_empty() => Ok(None),
+            ExportFormat::Bson => Err(invalid("the last document is truncated")),
+        }
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<Option<ExportRecord>, MatrixErr> {
+    if line.trim_ascii().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_slice(line)
+        .map(Some)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+fn invalid(reason: impl Into<String>) -> MatrixErr {
+    MatrixErr::InvalidExport(reason.into())
+}
+
+/// Records of a stream of chunks
+struct Records<S> {
+    chunks: S,
+    decoder: Option<RecordDecoder>,
+    pending: VecDeque<ExportRecord>,
+}
+
+impl<S, B> Records<S>
+where
+    S: Stream<Item = Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    async fn next(&mut self) -> Result<Option<ExportRecord>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+            let Some(decoder) = self.decoder.as_mut() else {
+                return Ok(None);
+            };
+            match self.chunks.try_next().await? {
+                Some(chunk) => self.pending.extend(decoder.push(chunk.as_ref())?),
+                None => {
+                    let decoder = self.decoder.take().unwrap();
+                    self.pending.extend(decoder.finish()?);
+                }
+            }
+        }
+    }
+}
+
+impl MongoManager {
+    /// Streams the config and every message of a room, the room record comes first
+    ///
+    /// Rooms that are being migrated or converted are spread over two places, so they can't be exported until that
+    /// is done. The export stops once the receiver is dropped
+    #[instrument]
+    pub async fn export_room(room: &str) -> Result<mpsc::Receiver<Result<ExportRecord>>> {
+        let room = RoomName::parse(room)?;
+        let manager = match mappings::read_manager(&room).await? {
+            either::Left(manager) => manager,
+            either::Right(_) => bail!(MatrixErr::RoomMigrating(room.to_string())),
+        };
+        let (state, shared) = manager
+            .export_source(&room)
+            .await
+            .context("Unable to get room")
+            .map_err(|e| fritz!(manager, e))??;
+
+        let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+        tx.send(Ok(ExportRecord::Room {
+            version: EXPORT_VERSION,
+            room: room.to_string(),
+            config: state.config.clone(),
+            seq: state.head.seq,
+            last_ts: state.head.last_ts,
+        }))
+        .await
+        .ok();
+        tokio::spawn(
+            async move {
+                let exported = async {
+                    let messages = if shared {
+                        manager.export_shared(&room, &state, &tx).await?
+                    } else {
+                        manager.export_per_room(&room, &tx).await?
+                    };
+                    let files = manager.export_files(&room, shared, &tx).await?;
+                    Ok::<_, anyhow::Error>((messages, files))
+                };
+                match exported.await {
+                    Ok((messages, (receipts, attachments))) => {
+                        info!(messages, receipts, attachments, "Exported room")
+                    }
+                    Err(e) => {
+                        warn!(?e, "Export failed");
+                        tx.send(Err(fritz!(manager, e).into())).await.ok();
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(rx)
+    }
+
+    /// Recreates an exported room on the instance its name is routed to
+    ///
+    /// The room must not exist yet. Imports that fail remove what they wrote, so they can simply be retried
+    #[instrument(skip_all)]
+    pub async fn import_room<S, B>(format: ExportFormat, chunks: S) -> Result<ImportReport>
+    where
+        S: Stream<Item = Result<B>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        let mut records = Records {
+            chunks,
+            decoder: Some(RecordDecoder::new(format)),
+            pending: VecDeque::new(),
+        };
+        let Some(ExportRecord::Room {
+            version,
+            room,
+            config,
+            seq,
+            last_ts,
+        }) = records.next().await?
+        else {
+            bail!(invalid("the first record has to be the room"));
+        };
+        if version != EXPORT_VERSION {
+            bail!(invalid(format!(
+                "version {version} is not supported, expected {EXPORT_VERSION}"
+            )));
+        }
+        let room = RoomName::parse(&room)?;
+        check_config(&config)?;
+
+        // During migrations the room may still be on the regular instance
+        let (manager, old) = mappings::write_managers(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+        if let Some(regular) = old
+            && regular
+                .room_exists(&room)
+                .await
+                .map_err(|e| fritz!(regular, e))?
+        {
+            bail!(MatrixErr::RoomAlreadyExists(room.to_string()));
+        }
+
+        let shared = manager.layout().await.map_err(|e| fritz!(manager, e))? == Layout::Shared;
+        let head = RoomHead { seq, last_ts };
+        let created = if shared {
+            manager.create_shared_room(&room, &config, &head).await
+        } else {
+            manager.create_room(&room, &config, &head).await
+        };
+        created
+            .context("Failed to create room")
+            .map_err(|e| fritz!(manager, e))??;
+
+        let report = match manager
+            .import_records(&room, seq, shared, &mut records)
+            .await
+        {
+            Ok(report) => report,
+            Err(e) => {
+                if let Err(e) = manager.discard_import(&room, shared).await {
+                    warn!(?e, "Unable to remove partial import");
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = manager.index_room(&room, &config).await {
+            warn!(?e, "Unable to index room");
+        }
+
+        info!(
+            report.messages,
+            report.buckets, report.receipts, report.attachments, "Imported room"
+        );
+        Ok(report)
+    }
+
+    /// State of the room and whether it is in the shared layout
+    async fn export_source(&self, room: &str) -> Result<Result<(RoomState, bool), MatrixErr>> {
+        if self.layout().await? == Layout::Shared
+            && let Some(room_doc) = self
+                .rooms()?
+                .find_one(doc! { "_id": room })
+                .await
+                .context("Unable to get shared room")?
+        {
+            if room_doc.get_bool(LEGACY_KEY).unwrap_or_default() {
+                return Ok(Err(MatrixErr::RoomMigrating(room.to_string())));
+            }
+            let state = bson::from_document(room_doc).context("Unable to parse room")?;
+            return Ok(Ok((state, true)));
+        }
+
+        let Some(config_doc) = backoff!(self)
+            .database(room)
+            .collection::<Document>(&format!("{CHAT_PREFIX}_0"))
+            .find_one(doc! {})
+            .await
+            .context("Unable to get room config")?
+        else {
+            return Ok(Err(MatrixErr::RoomNotFound(room.to_string())));
+        };
+        if config_doc.contains_key(MOVED_KEY) {
+            return Ok(Err(MatrixErr::RoomMigrating(room.to_string())));
+        }
+        let mut state = bson::from_document::<RoomState>(config_doc.clone())
+            .context("Unable to parse room config")?;
+        if !config_doc.contains_key(SEQ_KEY) {
+            state.head.seq = self.initial_seq(room).await?;
+        }
+        Ok(Ok((state, false)))
+    }
+
+    /// Number of exported messages, stops early if nobody reads them anymore
+    async fn export_per_room(
+        &self,
+        room: &str,
+        tx: &mpsc::Sender<Result<ExportRecord>>,
+    ) -> Result<usize> {
+        let db = backoff!(self).database(room);
+        let mut exported = 0;
+        for bucket in self.chat_collections(room).await? {
+            if bucket == 0 {
+                continue;
+            }
+            let mut cursor = db
+                .collection::<Message>(&format!("{CHAT_PREFIX}_{bucket}"))
+                .find(doc! {})
+                .sort(doc! { SEQ_KEY: 1, "_id": 1 })
+                .await
+                .with_context(|| format!("Unable to read bucket {bucket}"))?;
+            while let Some(message) = cursor.try_next().await.context("Unable to read message")? {
+                if tx
+                    .send(Ok(ExportRecord::Message { bucket, message }))
+                    .await
+                    .is_err()
+                {
+                    debug!("Export was abandoned");
+                    return Ok(exported);
+                }
+                exported += 1;
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Like [`Self::export_per_room`], buckets are derived from the bucketing of the room
+    async fn export_shared(
+        &self,
+        room: &str,
+        state: &RoomState,
+        tx: &mpsc::Sender<Result<ExportRecord>>,
+    ) -> Result<usize> {
+        let mut cursor = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Message>(MESSAGES_COL)
+            .find(doc! { "room": room })
+            .sort(doc! { SEQ_KEY: 1, "_id": 1 })
+            .await
+            .context("Unable to read messages")?;
+        let mut exported = 0;
+        while let Some(message) = cursor.try_next().await.context("Unable to read message")? {
+            let bucket = state
+                .config
+                .bucketing
+                .bucket(message.seq, message.timestamp);
+            if tx
+                .send(Ok(ExportRecord::Message { bucket, message }))
+                .await
+                .is_err()
+            {
+                debug!("Export was abandoned");
+                return Ok(exported);
+            }
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    /// Number of exported receipts and attachments, stops early if nobody reads them anymore
+    async fn export_files(
+        &self,
+        room: &str,
+        shared: bool,
+        tx: &mpsc::Sender<Result<ExportRecord>>,
+    ) -> Result<(usize, usize)> {
+        let receipts = self.room_receipts(room, shared).await?;
+        let receipt_count = receipts.len();
+        for (user, seq) in receipts {
+            if tx
+                .send(Ok(ExportRecord::Receipt { user, seq }))
+                .await
+                .is_err()
+            {
+                debug!("Export was abandoned");
+                return Ok((0, 0));
+            }
+        }
+
+        let mut exported = 0;
+        for key in self.file_keys(room).await? {
+            // Deleted since it was listed
+            let Some((meta, data)) = self.load_file(room, &key).await? else {
+                continue;
+            };
+            let record = ExportRecord::Attachment {
+                key,
+                author: meta.author,
+                name: meta.name,
+                data: Binary {
+                    subtype: BinarySubtype::Generic,
+                    bytes: data,
+                },
+            };
+            if tx.send(Ok(record)).await.is_err() {
+                debug!("Export was abandoned");
+                break;
+            }
+            exported += 1;
+        }
+        Ok((receipt_count, exported))
+    }
+
+    async fn import_records<S, B>(
+        &self,
+        room: &str,
+        head_seq: i64,
+        shared: bool,
+        records: &mut Records<S>,
+    ) -> Result<ImportReport>
+    where
+        S: Stream<Item = Result<B>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        let mut report = ImportReport {
+            room: room.to_string(),
+            messages: 0,
+            buckets: 0,
+            receipts: 0,
+            attachments: 0,
+        };
+        let mut buckets = BTreeSet::new();
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        let mut batch_bucket = 0;
+
+        while let Some(record) = records.next().await? {
+            let (bucket, message) = match record {
+                ExportRecord::Message { bucket, message } => (bucket, message),
+                ExportRecord::Room { .. } => {
+                    bail!(invalid("the room can only be the first record"))
+                }
+                ExportRecord::Receipt { user, seq } => {
+                    if !(1..=head_seq).contains(&seq) {
+                        bail!(invalid(format!(
+                            "receipt {seq} of {user} is outside the room"
+                        )));
+                    }
+                    self.store_receipt(room, &user, seq).await?;
+                    report.receipts += 1;
+                    continue;
+                }
+                ExportRecord::Attachment {
+                    key,
+                    author,
+                    name,
+                    data,
+                } => {
+                    self.import_file(room, key, author, name, data.bytes)
+                        .await?;
+                    report.attachments += 1;
+                    continue;
+                }
+            };
+            if bucket == 0 {
+                bail!(invalid("messages can't be in bucket 0"));
+            }
+            if message.seq > head_seq {
+                bail!(invalid(format!(
+                    "message {} is newer than the room",
+                    message.seq
+                )));
+            }
+
+            if bucket != batch_bucket || batch.len() >= IMPORT_BATCH {
+                report.messages += self
+                    .insert_batch(room, batch_bucket, shared, mem::take(&mut batch))
+                    .await?;
+                batch_bucket = bucket;
+            }
+            buckets.insert(bucket);
+            batch.push(message);
+        }
+        report.messages += self.insert_batch(room, batch_bucket, shared, batch).await?;
+
+        report.buckets = buckets.len();
+        Ok(report)
+    }
+
+    async fn insert_batch(
+        &self,
+        room: &str,
+        bucket: u32,
+        shared: bool,
+        batch: Vec<Message>,
+    ) -> Result<usize> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let len = batch.len();
+
+        let (db, col) = if shared {
+            let docs = batch
+                .iter()
+                .map(|message| bson::to_document(&SharedMessage { room, message }))
+                .collect::<Result<Vec<_>, _>>()
+                .context("Unable to serialize messages")?;
+            backoff!(self)
+                .database(SHARED_DB)
+                .collection::<Document>(MESSAGES_COL)
+                .insert_many(docs)
+                .await
+                .context("Unable to import messages")?;
+            (SHARED_DB.to_string(), MESSAGES_COL.to_string())
+        } else {
+            let col = format!("{CHAT_PREFIX}_{bucket}");
+            backoff!(self)
+                .database(room)
+                .collection::<Message>(&col)
+                .insert_many(batch)
+                .await
+                .with_context(|| format!("Unable to import bucket {bucket}"))?;
+            (room.to_string(), col)
+        };
+        if let Err(e) = self.ensure_indexes(&db, &col).await {
+            warn!(?e, "Unable to create bucket indexes");
+        }
+
+        Ok(len)
+    }
+
+    /// Checked like uploads, the mime type is sniffed again
+    async fn import_file(
+        &self,
+        room: &str,
+        key: String,
+        author: String,
+        name: String,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let Ok(key) = Uuid::try_parse(&key).map(|k| k.simple().to_string()) else {
+            bail!(invalid(format!("attachment key {key:?} is invalid")));
+        };
+        if data.is_empty() || data.len() > *MAX_ATTACHMENT_BYTES {
+            bail!(invalid(format!(
+                "attachment {key} has {} bytes",
+                data.len()
+            )));
+        }
+        let attachment = Attachment {
+            name,
+            mime: sniff_mime(&data).to_string(),
+            size: data.len() as u64,
+            storage_key: key,
+        };
+        check_attachment(&attachment).map_err(|e| invalid(e.to_string()))?;
+
+        let meta = FileMeta {
+            room: room.to_string(),
+            author,
+            name: attachment.name,
+            mime: attachment.mime,
+        };
+        self.store_file(&attachment.storage_key, &meta, &data).await
+    }
+
+    async fn discard_import(&self, room: &str, shared: bool) -> Result<()> {
+        // Local files and those of shared rooms are outside of the database of the room
+        for key in self.file_keys(room).await? {
+            self.delete_file(room, &key).await?;
+        }
+        if shared {
+            self.discard_receipts(room).await?;
+            backoff!(self)
+                .database(SHARED_DB)
+                .collection::<Document>(MESSAGES_COL)
+                .delete_many(doc! { "room": room })
+                .await
+                .context("Unable to remove messages")?;
+            self.rooms()?
+                .delete_one(doc! { "_id": room })
+                .await
+                .context("Unable to remove room")?;
+        } else {
+            backoff!(self)
+                .database(room)
+                .drop()
+                .await
+                .context("Unable to remove room")?;
+        }
+        self.cache.invalidate(room);
+        Ok(())
+    }
+
+    async fn room_exists(&self, room: &str) -> Result<bool> {
+        Ok(self.shared_state(room).await?.is_some()
+            || !self.chat_collections(room).await?.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucketing::Bucketing;
+
+    fn records() -> Vec<ExportRecord> {
+        let message = |seq| Message {
+            timestamp: DateTime::from_millis(1_700_000_000_000 + seq),
+            content: format!("message {seq}\nwith a newline"),
+            ..Message::test(seq)
+        };
+        vec![
+            ExportRecord::Room {
+                version: EXPORT_VERSION,
+                room: "room".to_string(),
+                config: RoomConfig {
+                    allowed_users: vec!["user".to_string()],
+                    bucketing: Bucketing::Count { size: 2 },
+                    public: false,
+                    retention: None,
+                },
+                seq: 3,
+                last_ts: Some(DateTime::from_millis(1_700_000_000_003)),
+            },
+            ExportRecord::Message {
+                bucket: 1,
+                message: message(1),
+            },
+            ExportRecord::Message {
+                bucket: 1,
+                message: message(2),
+            },
+            ExportRecord::Message {
+                bucket: 2,
+                message: message(3),
+            },
+            ExportRecord::Receipt {
+                user: "user".to_string(),
+                seq: 2,
+            },
+            ExportRecord::Attachment {
+                key: Uuid::nil().simple().to_string(),
+                author: "user".to_string(),
+                name: "cat.png".to_string(),
+                data: Binary {
+                    subtype: BinarySubtype::Generic,
+                    // Not valid UTF-8, and a newline that must not end the line
+                    bytes: b"\x89PNG\r\n\x1a\n\xff".to_vec(),
+                },
+            },
+        ]
+    }
+
+    fn encode(format: ExportFormat) -> Vec<u8> {
+        records()
+            .iter()
+            .flat_map(|r| format.encode(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_split_chunks() {
+        for format in [ExportFormat::JsonLines, ExportFormat::Bson] {
+            let encoded = encode(format);
+            for chunk_size in [1, 7, encoded.len()] {
+                let mut decoder = RecordDecoder::new(format);
+                let mut decoded = vec![];
+                for chunk in encoded.chunks(chunk_size) {
+                    decoded.extend(decoder.push(chunk).unwrap());
+                }
+                decoded.extend(decoder.finish().unwrap());
+                assert_eq!(decoded, records(), "{format} in chunks of {chunk_size}");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_exports() {
+        let encoded = encode(ExportFormat::Bson);
+        let mut decoder = RecordDecoder::new(ExportFormat::Bson);
+        decoder.push(&encoded[..encoded.len() - 1]).unwrap();
+        assert!(decoder.finish().is_err());
+
+        let mut decoder = RecordDecoder::new(ExportFormat::JsonLines);
+        assert!(decoder.push(b"{\"type\": \"room\"\n").is_err());
+
+        // Last line without a newline
+        let encoded = encode(ExportFormat::JsonLines);
+        let mut decoder = RecordDecoder::new(ExportFormat::JsonLines);
+        let mut decoded = decoder.push(&encoded[..encoded.len() - 1]).unwrap();
+        decoded.extend(decoder.finish().unwrap());
+        assert_eq!(decoded.len(), records().len());
+    }
+}
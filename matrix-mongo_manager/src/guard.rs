@@ -1,87 +1,75 @@
 use crate::ClientWrapper;
+use crate::breaker::CircuitBreaker;
 use anyhow::Result;
 use bson::doc;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Receiver as StdReceiver;
-use std::thread;
 use std::time::Duration;
-use tokio::sync::oneshot::{self, Receiver};
+use tokio::select;
+use tokio::sync::watch::Receiver;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tracing::{debug, info, instrument, warn};
 use uuid::Uuid;
 
-const SLEEP_DUR: Duration = Duration::from_secs(1);
-
 #[derive(Debug)]
 pub(super) struct MongoGuard {
     client: ClientWrapper,
     db_id: Uuid,
-    db_has_problem: Arc<AtomicBool>,
-    rx: Receiver<()>,
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl MongoGuard {
+    /// Runs until the channel of `shutdown_rx` is closed, which happens when the `MongoHook` is dropped
     #[instrument(skip_all)]
     pub(super) fn start(
         client: ClientWrapper,
         db_id: Uuid,
-        db_has_problem: Arc<AtomicBool>,
-        std_rx: StdReceiver<()>,
-    ) {
-        let (tx, rx) = oneshot::channel();
-
-        thread::spawn(move || {
-            while std_rx.try_recv().is_err() {
-                thread::sleep(SLEEP_DUR);
-            }
-            let _ = tx.send(());
-        });
-
+        breaker: Arc<CircuitBreaker>,
+        shutdown_rx: Receiver<()>,
+    ) -> JoinHandle<()> {
         let guard = Self {
             client,
             db_id,
-            db_has_problem,
-            rx,
+            breaker,
         };
-        tokio::spawn(guard.run());
+        tokio::spawn(guard.run(shutdown_rx))
     }
 
     #[instrument(skip_all, fields(id = ?self.db_id))]
-    async fn run(mut self) {
+    async fn run(self, mut shutdown_rx: Receiver<()>) {
         loop {
-            if self.db_has_problem.load(Ordering::SeqCst) {
-                warn!("Detected faulty Mongo instance");
-                // There might be a day when I understand this ordering, but today ain't it
-                if self.handle_problem().await {
-                    info!("Aborting error handling as instance is down");
+            select! {
+                // Nothing is ever sent, so this only resolves once the hook is dropped
+                _ = shutdown_rx.changed() => {
+                    debug!("Stopping guard as instance was shut down");
                     return;
                 }
-                info!("Mongo is alive again");
-                self.db_has_problem.store(false, Ordering::SeqCst);
+                _ = self.breaker.opened() => {}
             }
-            sleep(SLEEP_DUR).await;
-            if self.rx.try_recv().is_ok() {
-                debug!("Killing thread as instance was shut down");
-                return;
+
+            warn!("Detected faulty Mongo instance");
+            select! {
+                _ = shutdown_rx.changed() => {
+                    info!("Aborting error handling as instance is down");
+                    return;
+                }
+                _ = self.handle_problem() => {}
             }
+            info!("Mongo is alive again, letting probes through");
+            self.breaker.half_open();
         }
     }
 
-    /// Returns true if instance is down, false otherwise
+    /// Returns once the instance is reachable again
     #[instrument(skip_all)]
-    async fn handle_problem(&mut self) -> bool {
+    async fn handle_problem(&self) {
         let mut backoff_millis = matrix_commons::DEFAULT_BACKOFF;
         let mut sleep_dur = Duration::from_millis(backoff_millis);
         loop {
             warn!("Mongo is down, backing off for {backoff_millis}ms");
             sleep(sleep_dur).await;
-            if self.rx.try_recv().is_ok() {
-                debug!("Manager is down, returning");
-                return true;
-            }
             if self.check_conn().await.is_ok() {
-                return false;
+                return;
             }
             (backoff_millis, sleep_dur) = matrix_commons::jitter(backoff_millis);
         }
@@ -101,3 +89,58 @@ impl MongoGuard {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::BreakerState;
+    use crate::hook::MongoHook;
+    use matrix_metrics::Metrics;
+    use mongodb::Client;
+    use tokio::time::timeout;
+
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+    // Nothing listens on port 1, the long selection timeout keeps the ping hanging
+    const DEAD_URL: &str = "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=60000";
+
+    async fn client() -> ClientWrapper {
+        Arc::new(Some(Client::with_uri_str(DEAD_URL).await.unwrap()))
+    }
+
+    #[tokio::test]
+    async fn guard_stops_when_hook_is_dropped() {
+        for _ in 0..50 {
+            let (hook, rx) = MongoHook::new();
+            let breaker = Arc::new(CircuitBreaker::new(Metrics::new()));
+            let handle = MongoGuard::start(client().await, Uuid::new_v4(), breaker, rx);
+
+            drop(hook);
+            timeout(SHUTDOWN_TIMEOUT, handle)
+                .await
+                .expect("guard did not stop")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn guard_stops_while_recovering() {
+        let (hook, rx) = MongoHook::new();
+        let breaker = Arc::new(CircuitBreaker::new(Metrics::new()));
+        let handle = MongoGuard::start(client().await, Uuid::new_v4(), breaker.clone(), rx);
+
+        while breaker.state() != BreakerState::Open {
+            breaker.admit();
+            breaker.record_failure();
+        }
+        // Past the first backoff, so the guard is stuck pinging the dead instance
+        sleep(Duration::from_millis(matrix_commons::DEFAULT_BACKOFF + 100)).await;
+        assert!(!handle.is_finished());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        drop(hook);
+        timeout(SHUTDOWN_TIMEOUT, handle)
+            .await
+            .expect("guard did not stop")
+            .unwrap();
+    }
+}
@@ -1,21 +1,24 @@
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
+use tokio::sync::watch::{self, Receiver, Sender};
 
 pub(super) type MongoHookT = Arc<MongoHook>;
 
+/// Shared by all clones of a `MongoManager`
+///
+/// Nothing is ever sent, dropping the last clone closes the channel, which stops the guard instantly
 #[derive(Debug)]
 pub(super) struct MongoHook {
-    tx: Sender<()>,
+    _tx: Sender<()>,
 }
 
 impl MongoHook {
-    pub(super) fn new(tx: Sender<()>) -> Self {
-        Self { tx }
+    pub(super) fn new() -> (Self, Receiver<()>) {
+        let (tx, rx) = watch::channel(());
+        (Self { _tx: tx }, rx)
     }
-}
 
-impl Drop for MongoHook {
-    fn drop(&mut self) {
-        let _ = self.tx.send(());
+    #[cfg(test)]
+    pub(super) fn subscribe(&self) -> Receiver<()> {
+        self._tx.subscribe()
     }
 }
@@ -0,0 +1,296 @@
+use crate::MongoManager;
+use crate::layout::{Layout, MESSAGES_COL, SHARED_DB};
+use crate::messaging::{CHAT_PREFIX, MAX_WRITE_DURATION, Message};
+use crate::shared::only_duplicates;
+use anyhow::{Context, Result};
+use bson::{DateTime, Document, doc};
+use matrix_macros::get_env;
+use mongodb::error::ErrorKind;
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, instrument};
+
+/// One document per send with a client message id, unique per room and author
+const CLIENT_IDS_COL: &str = "client_msg_ids";
+/// Unique index of claims before they were keyed by author, ids of different authors collided with it
+const UNAUTHORED_INDEX: &str = "room_1_client_msg_id_1";
+const INDEX_NOT_FOUND: i32 = 27;
+const NAMESPACE_NOT_FOUND: i32 = 26;
+/// How long a retry returns the original message
+pub(crate) static CLIENT_MSG_ID_TTL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(get_env!("CLIENT_MSG_ID_TTL_SECS", 24 * 60 * 60, u64)));
+/// Claims without a message after this long belong to a send that died, the next retry takes over
+///
+/// Writes are abandoned after [`MAX_WRITE_DURATION`], the rest covers storing the message and clock skew between workers
+const STALE_CLAIM: Duration = MAX_WRITE_DURATION.saturating_add(Duration::from_secs(20));
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClaimDoc {
+    room: String,
+    author: String,
+    client_msg_id: String,
+    created: DateTime,
+    /// Set once the message was written
+    message: Option<Message>,
+}
+
+/// Outcome of [`MongoManager::claim_send`]
+#[derive(Debug)]
+pub(crate) enum Claim {
+    /// First send with this id, the message has to be written
+    New,
+    /// Retry of a send that went through
+    Sent(Message),
+    /// Retry of a send that is still being written
+    InProgress,
+    /// Took over the claim of a send that died, its message may have been written nonetheless
+    TakenOver,
+}
+
+impl MongoManager {
+    /// Claims a client message id of an author in a room for the calling send
+    #[instrument(skip(self, room))]
+    pub(crate) async fn claim_send(
+        &self,
+        room: &str,
+        author: &str,
+        client_msg_id: &str,
+    ) -> Result<Claim> {
+        let col = self.client_ids().await?;
+        let now = DateTime::now();
+        let claim = ClaimDoc {
+            room: room.to_string(),
+            author: author.to_string(),
+            client_msg_id: client_msg_id.to_string(),
+            created: now,
+            message: None,
+        };
+        match col.insert_one(&claim).await {
+            Ok(_) => return Ok(Claim::New),
+            Err(e) if only_duplicates(&e) => {}
+            Err(e) => return Err(e).context("Unable to claim client message id"),
+        }
+
+        let filter = claim_filter(room, author, client_msg_id);
+        let existing = col
+            .find_one(filter.clone())
+            .await
+            .context("Unable to get claim")?;
+        match existing {
+            Some(ClaimDoc {
+                message: Some(message),
+                ..
+            }) => Ok(Claim::Sent(message)),
+            // Expired in the meantime, the client just has to retry
+            None => Ok(Claim::InProgress),
+            Some(_) => {
+                let mut takeover = filter;
+                takeover.insert("message", doc! { "$eq": null });
+                takeover.insert("created", doc! { "$lt": stale_before(now) });
+                let taken = col
+                    .update_one(takeover, doc! { "$set": { "created": now } })
+                    .await
+                    .context("Unable to take over claim")?;
+                if taken.modified_count == 1 {
+                    debug!("Took over stale claim");
+                    return Ok(Claim::TakenOver);
+                }
+                Ok(Claim::InProgress)
+            }
+        }
+    }
+
+    /// Looks up a claim without taking it, stale claims count as none unless their message was written
+    ///
+    /// For the instance a migrating room is moved away from, sends before the migration claimed there
+    #[instrument(skip(self, room))]
+    pub(crate) async fn find_claim(
+        &self,
+        room: &str,
+        author: &str,
+        client_msg_id: &str,
+    ) -> Result<Option<Claim>> {
+        let existing = self
+            .client_ids()
+            .await?
+            .find_one(claim_filter(room, author, client_msg_id))
+            .await
+            .context("Unable to get claim")?;
+        Ok(match existing {
+            Some(ClaimDoc {
+                message: Some(message),
+                ..
+            }) => Some(Claim::Sent(message)),
+            Some(claim) if claim.created >= stale_before(DateTime::now()) => {
+                Some(Claim::InProgress)
+            }
+            Some(_) => self
+                .find_sent(room, author, client_msg_id)
+                .await?
+                .map(Claim::Sent),
+            None => None,
+        })
+    }
+
+    /// The message of a send whose claim was never finished, looked up by its `_id`, see [`sent_id`]
+    #[instrument(skip(self, room))]
+    pub(crate) async fn find_sent(
+        &self,
+        room: &str,
+        author: &str,
+        client_msg_id: &str,
+    ) -> Result<Option<Message>> {
+        let id = doc! { "_id": id_doc(room, author, client_msg_id) };
+        let client = backoff!(self);
+        if self.layout().await? == Layout::Shared
+            && let Some(message) = client
+                .database(SHARED_DB)
+                .collection::<Message>(MESSAGES_COL)
+                .find_one(id.clone())
+                .await
+                .context("Unable to find sent message")?
+        {
+            return Ok(Some(message));
+        }
+
+        let Some(buckets) = self.cached_chat_collections(room).await? else {
+            return Ok(None);
+        };
+        // Newest first, the message was written shortly after the claim
+        for bucket in buckets.into_iter().rev().filter(|&b| b != 0) {
+            let found = client
+                .database(room)
+                .collection::<Message>(&format!("{CHAT_PREFIX}_{bucket}"))
+                .find_one(id.clone())
+                .await
+                .context("Unable to find sent message")?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stores the written message, retries get it from now on
+    #[instrument(skip_all)]
+    pub(crate) async fn finish_send(&self, room: &str, message: &Message) -> Result<()> {
+        let Some(client_msg_id) = &message.client_msg_id else {
+            return Ok(());
+        };
+        self.client_ids()
+            .await?
+            .update_one(
+                claim_filter(room, &message.author, client_msg_id),
+                doc! { "$set": { "message": bson::to_bson(message)? } },
+            )
+            .await
+            .context("Unable to finish claim")?;
+        Ok(())
+    }
+
+    /// Gives up a claim after the send failed, so a retry can try again
+    #[instrument(skip(self, room))]
+    pub(crate) async fn release_send(
+        &self,
+        room: &str,
+        author: &str,
+        client_msg_id: &str,
+    ) -> Result<()> {
+        let mut filter = claim_filter(room, author, client_msg_id);
+        filter.insert("message", doc! { "$eq": null });
+        self.client_ids()
+            .await?
+            .delete_one(filter)
+            .await
+            .context("Unable to release claim")?;
+        Ok(())
+    }
+
+    /// The claims collection, with its indexes created once per instance
+    async fn client_ids(&self) -> Result<Collection<ClaimDoc>> {
+        let col = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<ClaimDoc>(CLIENT_IDS_COL);
+
+        let namespace = format!("{SHARED_DB}.{CLIENT_IDS_COL}");
+        if self.cache.claim_index(&namespace) {
+            if let Err(e) = col.drop_index(UNAUTHORED_INDEX).await
+                && !is_missing_index(&e)
+            {
+                self.cache.release_index(&namespace);
+                return Err(e).context("Unable to drop client message id index");
+            }
+            let unique = IndexModel::builder()
+                .keys(doc! { "room": 1, "author": 1, "client_msg_id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            let expiry = IndexModel::builder()
+                .keys(doc! { "created": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(*CLIENT_MSG_ID_TTL)
+                        .build(),
+                )
+                .build();
+            if let Err(e) = col.create_indexes([unique, expiry]).await {
+                self.cache.release_index(&namespace);
+                return Err(e).context("Unable to create client message id indexes");
+            }
+        }
+
+        Ok(col)
+    }
+}
+
+/// `_id` of a message sent with a client message id, so it can be found if its claim was never finished
+pub(crate) fn sent_id(room: &str, message: &Message) -> Option<Document> {
+    let client_msg_id = message.client_msg_id.as_deref()?;
+    Some(id_doc(room, &message.author, client_msg_id))
+}
+
+fn id_doc(room: &str, author: &str, client_msg_id: &str) -> Document {
+    doc! { "room": room, "author": author, "client_msg_id": client_msg_id }
+}
+
+fn claim_filter(room: &str, author: &str, client_msg_id: &str) -> Document {
+    id_doc(room, author, client_msg_id)
+}
+
+fn is_missing_index(e: &mongodb::error::Error) -> bool {
+    matches!(&*e.kind, ErrorKind::Command(e) if e.code == INDEX_NOT_FOUND || e.code == NAMESPACE_NOT_FOUND)
+}
+
+/// Unfinished claims created before this are stale
+fn stale_before(now: DateTime) -> DateTime {
+    DateTime::from_millis(now.timestamp_millis() - STALE_CLAIM.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_of_authors_do_not_collide() {
+        let mut message = Message::test(1);
+        message.client_msg_id = Some("1".to_string());
+        let mut other = message.clone();
+        other.author = "other".to_string();
+
+        assert_eq!(sent_id("room", &message), sent_id("room", &message.clone()));
+        assert_ne!(sent_id("room", &message), sent_id("room", &other));
+        assert_ne!(sent_id("room", &message), sent_id("other", &message));
+        message.client_msg_id = None;
+        assert_eq!(sent_id("room", &message), None);
+    }
+
+    #[test]
+    fn slow_sends_keep_their_claim() {
+        let now = DateTime::now();
+        let slowest =
+            DateTime::from_millis(now.timestamp_millis() - MAX_WRITE_DURATION.as_millis() as i64);
+        assert!(slowest > stale_before(now));
+    }
+}
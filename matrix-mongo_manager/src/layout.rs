@@ -0,0 +1,166 @@
+use crate::MongoManager;
+use anyhow::{Context, Result, bail};
+use bson::doc;
+use mongodb::IndexModel;
+use mongodb::options::IndexOptions;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
+
+/// Database of the shared layout, also holds the layout of the instance
+pub(crate) const SHARED_DB: &str = "matrix";
+const META_COL: &str = "meta";
+const LAYOUT_ID: &str = "layout";
+/// One document per room, `_id` is the room name
+pub(crate) const ROOMS_COL: &str = "rooms";
+/// Messages of all rooms, keyed by `(room, seq)`
+pub(crate) const MESSAGES_COL: &str = "messages";
+const UNIQUE_SEQ_INDEX: &str = "room_1_seq_1_unique";
+/// How long a worker keeps using a layout before asking the instance again
+pub(crate) const LAYOUT_TTL: Duration = Duration::from_secs(10);
+
+pub(crate) type LayoutCache = parking_lot::Mutex<Option<(Layout, Instant)>>;
+
+/// How rooms are stored on a Mongo instance
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// One database per room, messages are split into `chat_N` collections by the bucketing of the room
+    #[default]
+    PerRoom,
+    /// Rooms are documents in `matrix.rooms`, messages live in `matrix.messages`
+    ///
+    /// Rooms that weren't converted yet are still served from their own database
+    Shared,
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::PerRoom => write!(f, "per_room"),
+            Layout::Shared => write!(f, "shared"),
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "per_room" => Ok(Layout::PerRoom),
+            "shared" => Ok(Layout::Shared),
+            _ => bail!("Unknown layout {s:?}, expected per_room or shared"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LayoutDoc {
+    layout: Layout,
+}
+
+impl MongoManager {
+    /// Layout of the instance, instances without one use [`Layout::PerRoom`]
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    pub(crate) async fn layout(&self) -> Result<Layout> {
+        if let Some((layout, fetched)) = *self.layout.lock()
+            && fetched.elapsed() < LAYOUT_TTL
+        {
+            return Ok(layout);
+        }
+
+        let layout = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<LayoutDoc>(META_COL)
+            .find_one(doc! { "_id": LAYOUT_ID })
+            .await
+            .context("Unable to get layout")?
+            .map(|doc| doc.layout)
+            .unwrap_or_default();
+        debug!(%layout, "Fetched layout");
+
+        *self.layout.lock() = Some((layout, Instant::now()));
+        Ok(layout)
+    }
+
+    /// Switches the layout of the instance, other workers follow within [`LAYOUT_TTL`]
+    ///
+    /// Only new rooms are affected, existing ones are moved by the converter
+    #[instrument(skip_all, fields(id = ?self.db_id, %layout))]
+    pub(crate) async fn set_layout(&self, layout: Layout) -> Result<()> {
+        let db = backoff!(self).database(SHARED_DB);
+
+        if layout == Layout::Shared {
+            let index = IndexModel::builder()
+                .keys(doc! { "room": 1, "seq": 1 })
+                .build();
+            db.collection::<bson::Document>(MESSAGES_COL)
+                .create_indexes([index, unique_seq_index()])
+                .await
+                .context("Unable to create message indexes")?;
+
+            // Only works on sharded clusters, everything else just uses the index
+            let shard_cmd = doc! {
+                "shardCollection": format!("{SHARED_DB}.{MESSAGES_COL}"),
+                "key": { "room": 1, "seq": 1 },
+            };
+            match backoff!(self)
+                .database("admin")
+                .run_command(shard_cmd)
+                .await
+            {
+                Ok(_) => info!("Sharded message collection"),
+                Err(e) => debug!(?e, "Not sharding message collection"),
+            }
+        }
+
+        db.collection::<LayoutDoc>(META_COL)
+            .update_one(
+                doc! { "_id": LAYOUT_ID },
+                doc! { "$set": { "layout": bson::to_bson(&layout)? } },
+            )
+            .upsert(true)
+            .await
+            .context("Unable to set layout")?;
+
+        *self.layout.lock() = Some((layout, Instant::now()));
+        info!("Set layout");
+        Ok(())
+    }
+}
+
+/// Two messages of a room never get the same sequence number, not even from writers that still used the `chat_0` of a
+/// room that was converted in the meantime
+///
+/// Messages from before sequence numbers have none, the plain `(room, seq)` index serves the reads of all of them
+pub(crate) fn unique_seq_index() -> IndexModel {
+    let options = IndexOptions::builder()
+        .name(UNIQUE_SEQ_INDEX.to_string())
+        .unique(true)
+        .partial_filter_expression(doc! { "seq": { "$gt": 0_i64 } })
+        .build();
+    IndexModel::builder()
+        .keys(doc! { "room": 1, "seq": 1 })
+        .options(options)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_display_format() {
+        for layout in [Layout::PerRoom, Layout::Shared] {
+            assert_eq!(layout.to_string().parse::<Layout>().unwrap(), layout);
+            assert_eq!(
+                bson::to_bson(&layout).unwrap().as_str(),
+                Some(layout.to_string().as_str())
+            );
+        }
+        assert!("sharded".parse::<Layout>().is_err());
+    }
+}
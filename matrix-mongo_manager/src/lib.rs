@@ -1,34 +1,89 @@
 #[macro_use]
 mod macros;
+pub mod attachment;
+mod breaker;
+pub mod bucketing;
+mod cache;
+pub mod content;
+pub mod direct;
+pub mod directory;
+pub mod explain;
+pub mod export;
 pub mod guard;
 mod hook;
+mod idempotency;
+pub mod layout;
 pub mod mappings;
 pub mod messaging;
+pub mod receipts;
+pub mod retention;
+pub mod room;
+pub mod search;
+pub mod shared;
+pub mod thread;
 pub mod user;
 
+use crate::breaker::{Admission, BreakerState, CircuitBreaker};
+use crate::cache::{ROOM_CACHE_TTL, RoomCache};
 use crate::guard::MongoGuard;
 use crate::hook::{MongoHook, MongoHookT};
+use crate::layout::LayoutCache;
+use anyhow::{Context, Result};
+use bson::doc;
+use matrix_metrics::MetricsWrapper;
 use mongodb::Client;
 use mongodb::options::ClientOptions;
-use serde::Deserialize;
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, mpsc};
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
 use tracing::{debug, error, instrument};
 use uuid::Uuid;
 
 type ClientWrapper = Arc<Option<Client>>;
 const MONGO_TIMEOUT: Duration = Duration::from_secs(1);
+/// Server selection alone waits 30s by default
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parses every setting that is read lazily
+///
+/// Call before serving, `get_env!` exits on invalid values, which would kill the worker on the first request
+/// that reads them otherwise
+pub fn load_config() {
+    LazyLock::force(&bucketing::DEFAULT_BUCKETING);
+    LazyLock::force(&cache::ROOM_CACHE_TTL);
+    LazyLock::force(&content::MAX_MESSAGE_CHARS);
+    LazyLock::force(&attachment::MAX_ATTACHMENT_BYTES);
+    LazyLock::force(&attachment::ATTACHMENT_STORE);
+    LazyLock::force(&idempotency::CLIENT_MSG_ID_TTL);
+    LazyLock::force(&export::MAX_IMPORT_BYTES);
+}
 
 #[derive(Clone, Debug)]
 pub struct MongoManager {
     client: ClientWrapper,
     pub db_id: Uuid,
-    db_has_problem: Arc<AtomicBool>,
+    breaker: Arc<CircuitBreaker>,
+    cache: Arc<RoomCache>,
+    layout: Arc<LayoutCache>,
     url: String,
     tx: Sender<String>,
     _hook: MongoHookT,
+    /// Set on the managers handed out for a request, see [`Self::admit`]
+    admission: Option<Arc<Admission>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    /// The instance answers pings again, only a fraction of the requests is let through
+    Recovering,
+    /// The guard is waiting for the instance to respond to pings again
+    Unreachable,
+    /// The URL could not be turned into a client
+    InvalidUrl,
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,18 +93,20 @@ struct User {
 }
 
 impl MongoManager {
-    #[instrument]
-    pub async fn new(url: &str, id: Uuid, err_tx: Sender<String>) -> Self {
+    #[instrument(skip(metrics))]
+    pub async fn new(url: &str, id: Uuid, err_tx: Sender<String>, metrics: MetricsWrapper) -> Self {
         debug!("Connecting to mongo");
-        let (tx, rx) = mpsc::channel();
-        let db_has_problem = Arc::new(AtomicBool::new(false));
+        let (hook, shutdown_rx) = MongoHook::new();
         let mut manager = Self {
             client: Arc::new(None),
             db_id: id,
-            db_has_problem: db_has_problem.clone(),
+            breaker: Arc::new(CircuitBreaker::new(metrics.clone())),
+            cache: Arc::new(RoomCache::new(metrics, *ROOM_CACHE_TTL)),
+            layout: Default::default(),
             url: url.to_string(),
             tx: err_tx,
-            _hook: Arc::new(MongoHook::new(tx)),
+            _hook: Arc::new(hook),
+            admission: None,
         };
 
         let mut opts = match ClientOptions::parse(url).await {
@@ -71,7 +128,12 @@ impl MongoManager {
                 } else {
                     error!("How tf can't we get a mut ref, we should be unique???");
                 }
-                MongoGuard::start(manager.client.clone(), id, db_has_problem.clone(), rx);
+                MongoGuard::start(
+                    manager.client.clone(),
+                    id,
+                    manager.breaker.clone(),
+                    shutdown_rx,
+                );
                 manager
             }
             Err(e) => {
@@ -80,4 +142,96 @@ impl MongoManager {
             }
         }
     }
+
+    pub fn health(&self) -> Health {
+        if self.client.is_none() {
+            return Health::InvalidUrl;
+        }
+        match self.breaker.state() {
+            BreakerState::Closed => Health::Healthy,
+            BreakerState::HalfOpen => Health::Recovering,
+            BreakerState::Open => Health::Unreachable,
+        }
+    }
+
+    /// A clone for one logical request, the breaker admits or rejects all of its operations together
+    pub(crate) fn admit(&self) -> Self {
+        Self {
+            admission: Some(Arc::new(Admission::new(self.breaker.clone()))),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the breaker lets an operation through, managers without an admission ask for every operation
+    pub(crate) fn allows(&self) -> bool {
+        match &self.admission {
+            Some(admission) => admission.allows(),
+            None => self.breaker.admit().is_some(),
+        }
+    }
+
+    pub(crate) fn record_failure(&self) {
+        match &self.admission {
+            Some(admission) => admission.fail(),
+            None => self.breaker.record_failure(),
+        }
+    }
+
+    /// Round trip of a ping, for one-off checks that can't wait for the guard to notice an outage
+    #[instrument(skip(self), fields(id = ?self.db_id))]
+    pub async fn ping(&self) -> Result<Duration> {
+        let client = backoff!(self);
+        let start = Instant::now();
+        timeout(
+            PING_TIMEOUT,
+            client.database("admin").run_command(doc! { "ping": 1 }),
+        )
+        .await
+        .context("Ping timed out")?
+        .context("Ping failed")?;
+        Ok(start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    const URL: &str = "mongodb://127.0.0.1:1/";
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn dropping_managers_closes_hook() {
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        for _ in 0..50 {
+            let manager =
+                MongoManager::new(URL, Uuid::new_v4(), err_tx.clone(), Metrics::new()).await;
+            assert_eq!(manager.health(), Health::Healthy);
+            let mut shutdown_rx = manager._hook.subscribe();
+
+            let clone = manager.clone();
+            drop(manager);
+            assert!(shutdown_rx.has_changed().is_ok(), "hook closed too early");
+
+            drop(clone);
+            timeout(SHUTDOWN_TIMEOUT, shutdown_rx.changed())
+                .await
+                .expect("hook was not closed")
+                .expect_err("nothing should be sent");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_url_has_no_client() {
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        for _ in 0..50 {
+            let manager =
+                MongoManager::new("not a url", Uuid::new_v4(), err_tx.clone(), Metrics::new())
+                    .await;
+            assert_eq!(manager.health(), Health::InvalidUrl);
+        }
+    }
 }
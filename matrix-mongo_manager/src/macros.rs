@@ -3,18 +3,17 @@ macro_rules! backoff {
         #[allow(unused_imports)]
         use ::tracing::warn;
         use anyhow::{anyhow, bail};
-        use core::sync::atomic::Ordering;
         use matrix_errors::MongoErr;
 
-        if $manager.db_has_problem.load(Ordering::SeqCst) {
-            bail!(MongoErr::Unreachable(anyhow!("Unreachable")));
-        }
         let Some(client) = &*$manager.client else {
             if let Err(e) = $manager.tx.try_send($manager.url.clone()) {
                 warn!(?e, "Failed to send url to db_manager");
             }
             bail!(MongoErr::InvalidUrl($manager.db_id.to_string()));
         };
+        if !$manager.allows() {
+            bail!(MongoErr::Unreachable(anyhow!("Unreachable")));
+        }
 
         client
     }};
@@ -22,20 +21,28 @@ macro_rules! backoff {
 
 macro_rules! fritz {
     ($manager:expr, $e:expr) => {{
-        #[allow(unused_imports)]
-        use crate::guard::MongoGuard;
-        use ::tracing::warn;
-        use core::sync::atomic::Ordering;
+        use crate::breaker::ErrKind;
+        use ::tracing::{debug, warn};
         use matrix_errors::MongoErr;
 
-        if let Err(e) = $manager.tx.try_send($manager.url.clone()) {
-            warn!(?e, "Failed to send url to db_manager");
+        let e = $e;
+        match ErrKind::of(&e) {
+            ErrKind::Transport => {
+                if let Err(e) = $manager.tx.try_send($manager.url.clone()) {
+                    warn!(?e, "Failed to send url to db_manager");
+                }
+                $manager.record_failure();
+                MongoErr::Unreachable(e)
+            }
+            // Already accounted for by `backoff!`
+            ErrKind::Rejected if $manager.client.is_none() => {
+                MongoErr::InvalidUrl($manager.db_id.to_string())
+            }
+            ErrKind::Rejected => MongoErr::Unreachable(e),
+            ErrKind::Application => {
+                debug!(?e, "Not counting application error against instance");
+                MongoErr::Operation(e)
+            }
         }
-        if $manager.client.is_none() {
-            return MongoErr::InvalidUrl($manager.db_id.to_string());
-        };
-
-        $manager.db_has_problem.store(true, Ordering::SeqCst);
-        MongoErr::Unreachable($e)
     }};
 }
@@ -1,6 +1,9 @@
 use crate::MongoManager;
+use crate::room::{RoomName, canonicalize};
 use anyhow::{Context, Result, anyhow, bail};
 use either::Either;
+use matrix_errors::MappingErr;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::LazyLock;
 use tokio::sync::{RwLock, RwLockReadGuard};
@@ -17,14 +20,44 @@ pub struct Mappings {
     pub managers: HashMap<String, MongoManager>,
 }
 
-#[derive(Debug)]
+impl Mappings {
+    /// Sorts the instances by their boundaries, which have to be canonical like room names
+    ///
+    /// Postgres sorts by its collation, routing expects the byte order of canonical names. Boundaries are never
+    /// canonicalized here, that would move the rooms around them to another instance without their data
+    pub fn set_instances(
+        &mut self,
+        mut instances: Vec<Instance>,
+        mut migration_instances: Vec<MigrationInstance>,
+    ) -> Result<(), MappingErr> {
+        let boundaries = instances
+            .iter()
+            .map(|i| &i.from)
+            .chain(migration_instances.iter().flat_map(|i| [&i.from, &i.to]));
+        for boundary in boundaries {
+            let canonical = canonicalize(boundary);
+            if *boundary != canonical {
+                return Err(MappingErr::NotCanonical(boundary.clone(), canonical));
+            }
+        }
+
+        instances.sort_by(|a, b| a.from.cmp(&b.from));
+        migration_instances.sort_by(|a, b| a.from.cmp(&b.from));
+
+        self.instances = instances;
+        self.migration_instances = migration_instances;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Instance {
     pub id: Uuid,
     pub url: String,
     pub from: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MigrationInstance {
     pub id: Uuid,
     pub url: String,
@@ -46,25 +79,51 @@ pub struct MigrationInstance {
 /// - Err: If no suitable MongoDB instance is found or other errors occur
 ///
 #[instrument]
-pub(super) async fn write_manager(namespace: &str) -> Result<MongoManager> {
+pub(super) async fn write_manager(namespace: &RoomName) -> Result<MongoManager> {
     let guard = MONGO_MAPPINGS_MANAGER.read().await;
     debug!(instances = ?guard.instances);
 
-    if let Some(manager) = guard
-        .migration_instances
-        .iter()
-        .find(|m| *m.from <= *namespace && *m.to >= *namespace)
-        .and_then(|m| guard.managers.get(&m.url))
+    if let Some(manager) =
+        find_migration_instance(namespace, &guard).and_then(|m| guard.managers.get(&m.url))
     // NOTE Not sure how much I like it, technically it should be impossible to not find one, but still...
     {
         debug!(?manager, "Found migration manager");
-        return Ok(manager.clone());
+        return Ok(manager.admit());
     }
 
-    let manager = get_manager_for_instance(&namespace, &guard)
+    let manager = get_manager_for_instance(namespace, &guard)
         .context("Unable to get write instance manager")?;
 
-    Ok(manager)
+    Ok(manager.admit())
+}
+
+/// Gets the write manager together with the instance a migrating room is moved away from.
+///
+/// Both are resolved under one lookup and admitted once, asking [`write_manager`] and [`read_manager`] one after
+/// the other would admit the write instance twice and could see a migration start in between.
+///
+/// # Returns
+///
+/// A `Result` containing:
+/// - `Ok((MongoManager, None))`: The regular MongoManager instance when no migration is in progress.
+/// - `Ok((MongoManager, Some(MongoManager)))`: The migration MongoManager and the regular MongoManager when a
+///   migration is in progress **(in that order)**.
+/// - `Err`: If no suitable instance is found or other errors occur.
+#[instrument]
+pub(super) async fn write_managers(
+    namespace: &RoomName,
+) -> Result<(MongoManager, Option<MongoManager>)> {
+    let guard = MONGO_MAPPINGS_MANAGER.read().await;
+    debug!(instances = ?guard.instances);
+
+    let manager = get_manager_for_instance(namespace, &guard)
+        .context("Unable to get write instance manager")?;
+    let res =
+        match find_migration_instance(namespace, &guard).and_then(|m| guard.managers.get(&m.url)) {
+            Some(mig_man) => (mig_man.admit(), Some(manager.admit())),
+            None => (manager.admit(), None),
+        };
+    Ok(res)
 }
 
 /// Fetches the relevant MongoManager instances for read operations based on the namespace.
@@ -85,20 +144,18 @@ pub(super) async fn write_manager(namespace: &str) -> Result<MongoManager> {
 /// - `Err`: If no suitable instance is found or other errors occur.
 #[instrument]
 pub(super) async fn read_manager(
-    namespace: &str,
+    namespace: &RoomName,
 ) -> Result<Either<MongoManager, (MongoManager, MongoManager)>> {
     let guard = MONGO_MAPPINGS_MANAGER.read().await;
     debug!(instances = ?guard.instances);
 
-    let migration_manager = guard
-        .migration_instances
-        .iter()
-        .find(|m| *m.from <= *namespace && *m.to >= *namespace)
+    let migration_manager = find_migration_instance(namespace, &guard)
         .and_then(|m| guard.managers.get(&m.url))
-        .map(|m| m.clone());
+        .map(MongoManager::admit);
 
-    let manager = get_manager_for_instance(&namespace, &guard)
-        .context("Unable to get read instance manager")?;
+    let manager = get_manager_for_instance(namespace, &guard)
+        .context("Unable to get read instance manager")?
+        .admit();
 
     let res = match migration_manager {
         Some(mig_man) => either::Right((manager, mig_man)),
@@ -107,6 +164,39 @@ pub(super) async fn read_manager(
     Ok(res)
 }
 
+/// Manager of a regular or migration instance, `None` until the next mapping refresh picked it up
+///
+/// Admitted for one request, long-running jobs count as one
+#[instrument]
+pub async fn manager_by_id(id: Uuid) -> Option<MongoManager> {
+    let guard = MONGO_MAPPINGS_MANAGER.read().await;
+    // Instances sharing a url share the manager, which only carries the id of one of them
+    let url = guard
+        .instances
+        .iter()
+        .map(|i| (i.id, &i.url))
+        .chain(guard.migration_instances.iter().map(|i| (i.id, &i.url)))
+        .find_map(|(instance, url)| (instance == id).then_some(url))?;
+    guard.managers.get(url).map(MongoManager::admit)
+}
+
+/// Managers of every regular and migration instance, admitted for one request each
+pub async fn all_managers() -> Vec<MongoManager> {
+    let guard = MONGO_MAPPINGS_MANAGER.read().await;
+    guard.managers.values().map(MongoManager::admit).collect()
+}
+
+#[instrument(skip_all)]
+fn find_migration_instance<'a>(
+    namespace: &str,
+    guard: &'a RwLockReadGuard<'_, Mappings>,
+) -> Option<&'a MigrationInstance> {
+    guard
+        .migration_instances
+        .iter()
+        .find(|m| *m.from <= *namespace && *m.to >= *namespace)
+}
+
 /// Retrieves the appropriate MongoManager instance based on the provided namespace
 /// by searching through available instances in the guard.
 ///
@@ -154,16 +244,146 @@ fn get_manager_for_instance(
     }
 
     // Unwrap because instances can't be empty
-    let instance = instance.unwrap_or(&guard.instances.last().unwrap());
+    let instance = instance.unwrap_or(guard.instances.last().unwrap());
     debug!(?instance, "Found instance");
 
     let manager = guard
         .managers
         .get(&instance.url)
         .ok_or_else(|| anyhow!("No instance for url (this should not be possible)"))
-        .map(|m| m.clone())?;
+        .cloned()?;
 
     debug!(?manager, "Found manager");
 
     Ok(manager)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+
+    /// Canonical boundaries as stored by `add_instance`, in no particular order
+    const BOUNDARIES: [&str; 3] = ["m", "", "ü"];
+
+    async fn manager(url: &str) -> MongoManager {
+        let (err_tx, _) = mpsc::channel(1);
+        MongoManager::new(url, Uuid::new_v4(), err_tx, Metrics::new()).await
+    }
+
+    async fn mappings(migration: Option<(&str, &str)>) -> RwLock<Mappings> {
+        let mut mappings = Mappings::default();
+        let mut instances = vec![];
+        for from in BOUNDARIES {
+            let url = format!("mongodb://127.0.0.1:1/?appName=from{from}");
+            let manager = manager(&url).await;
+            instances.push(Instance {
+                id: manager.db_id,
+                url: url.clone(),
+                from: from.to_string(),
+            });
+            mappings.managers.insert(url, manager);
+        }
+
+        let migration_instances = match migration {
+            Some((from, to)) => {
+                let url = "mongodb://127.0.0.1:1/?appName=migration".to_string();
+                let manager = manager(&url).await;
+                let instance = MigrationInstance {
+                    id: manager.db_id,
+                    url: url.clone(),
+                    from: from.to_string(),
+                    to: to.to_string(),
+                };
+                mappings.managers.insert(url, manager);
+                vec![instance]
+            }
+            None => vec![],
+        };
+
+        mappings
+            .set_instances(instances, migration_instances)
+            .unwrap();
+        RwLock::new(mappings)
+    }
+
+    async fn route(mappings: &RwLock<Mappings>, name: &str) -> String {
+        let room = RoomName::parse(name).unwrap();
+        let guard = mappings.read().await;
+        get_manager_for_instance(&room, &guard).unwrap().url
+    }
+
+    async fn is_migrating(mappings: &RwLock<Mappings>, name: &str) -> bool {
+        let room = RoomName::parse(name).unwrap();
+        let guard = mappings.read().await;
+        find_migration_instance(&room, &guard).is_some()
+    }
+
+    #[tokio::test]
+    async fn boundaries_are_sorted() {
+        let mappings = mappings(Some(("k", "n"))).await;
+        let guard = mappings.read().await;
+
+        let boundaries = guard.instances.iter().map(|i| i.from.as_str());
+        assert!(boundaries.eq(["", "m", "ü"]));
+    }
+
+    #[tokio::test]
+    async fn non_canonical_boundaries_are_kept_out() {
+        let mappings = mappings(None).await;
+        let mut guard = mappings.write().await;
+        let instance = |from: &str| Instance {
+            id: Uuid::new_v4(),
+            url: "mongodb://127.0.0.1:1/".to_string(),
+            from: from.to_string(),
+        };
+
+        let res = guard.set_instances(vec![instance(""), instance("M")], vec![]);
+        assert!(
+            matches!(res, Err(MappingErr::NotCanonical(stored, canonical)) if stored == "M" && canonical == "m")
+        );
+        let migration = MigrationInstance {
+            id: Uuid::new_v4(),
+            url: "mongodb://127.0.0.1:1/".to_string(),
+            from: "k".to_string(),
+            to: "N".to_string(),
+        };
+        let res = guard.set_instances(vec![instance("")], vec![migration]);
+        assert!(matches!(res, Err(MappingErr::NotCanonical(..))));
+
+        // The last valid boundaries keep routing
+        let boundaries = guard.instances.iter().map(|i| i.from.as_str());
+        assert!(boundaries.eq(["", "m", "ü"]));
+    }
+
+    #[tokio::test]
+    async fn spellings_of_a_room_route_to_the_same_instance() {
+        let mappings = mappings(None).await;
+
+        for (spellings, from) in [
+            (["lobby", "Lobby", "LOBBY"], ""),
+            (["m", "M", "\u{FF2D}"], "m"),
+            (["mango", "Mango", "MANGO"], "m"),
+            (["zebra", "Zebra", "ZEBRA"], "m"),
+            (["über", "Über", "U\u{0308}BER"], "ü"),
+        ] {
+            let expected = format!("mongodb://127.0.0.1:1/?appName=from{from}");
+            for name in spellings {
+                assert_eq!(route(&mappings, name).await, expected, "{name:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spellings_of_a_room_are_migrated_together() {
+        let mappings = mappings(Some(("k", "n"))).await;
+
+        for name in ["kiwi", "KIWI", "Lobby", "n", "N"] {
+            assert!(is_migrating(&mappings, name).await, "{name:?}");
+        }
+        for name in ["jam", "JAM", "Nest", "Orange"] {
+            assert!(!is_migrating(&mappings, name).await, "{name:?}");
+        }
+    }
+}
@@ -1,94 +1,398 @@
 use super::mappings;
 use crate::MongoManager;
+use crate::bucketing::{Bucketing, DEFAULT_BUCKET_SIZE};
+use crate::content::check_message;
+use crate::direct::is_direct_name;
+use crate::idempotency::{Claim, sent_id};
+use crate::layout::{Layout, MESSAGES_COL, SHARED_DB};
+use crate::retention::Retention;
+use crate::room::RoomName;
 use anyhow::{Context, Result, bail};
-use bson::DateTime;
-use matrix_errors::MatrixErr;
+use bson::{DateTime, Document, doc};
+use matrix_errors::{ContentErr, MatrixErr};
+use mongodb::options::ReturnDocument;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::timeout;
 use tracing::{debug, error, info, instrument, trace, warn};
 
 const INTERNAL_ERR_MSG: &str = "Internal server error";
-const INVALID_ROOM_NAMES: &[&str] = &["admin", "config", "local"];
-const CHAT_PREFIX: &str = "chat";
-const MAX_MSGS_PER_COL: u64 = 100;
+pub(crate) const CHAT_PREFIX: &str = "chat";
+/// Per-room message counter, stored next to the [`RoomConfig`] in `chat_0`
+pub(crate) const SEQ_KEY: &str = "seq";
+/// Timestamp of the newest message, stored next to the [`RoomConfig`] in `chat_0`
+pub(crate) const LAST_TS_KEY: &str = "last_ts";
+/// Set in `chat_0` once the room was moved to the shared layout
+pub(crate) const MOVED_KEY: &str = "moved";
+/// Read receipts of the members, next to the metadata collection
+pub(crate) const RECEIPTS_COL: &str = "chat_receipts";
+/// Attempts to get a sequence number, only rooms created before sequence numbers need a second one
+const SEQ_ATTEMPTS: usize = 2;
+/// Writes are abandoned after this, from taking the sequence number to the insert
+///
+/// Sealing a room and taking over stale send claims rely on no write landing later than that
+pub(crate) const MAX_WRITE_DURATION: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RoomConfig {
     pub allowed_users: Vec<String>,
+    /// Can't be changed after the room was created
+    #[serde(default)]
+    pub bucketing: Bucketing,
+    /// Listed in the public room directory
+    #[serde(default)]
+    pub public: bool,
+    /// Keeps every message if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
 }
 
+/// The part of `chat_0` that changes with every message
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct RoomHead {
+    #[serde(default)]
+    pub(crate) seq: i64,
+    pub(crate) last_ts: Option<DateTime>,
+}
+
+impl RoomHead {
+    /// Bucket of the newest message, `None` if the head doesn't tell
+    pub(crate) fn newest_bucket(&self, bucketing: Bucketing) -> Option<u32> {
+        if self.seq == 0 {
+            return None;
+        }
+        Some(bucketing.bucket(self.seq, self.last_ts?))
+    }
+}
+
+/// The whole `chat_0` document
+#[derive(Debug, Deserialize)]
+pub(crate) struct RoomState {
+    #[serde(flatten)]
+    pub(crate) config: RoomConfig,
+    #[serde(flatten)]
+    pub(crate) head: RoomHead,
+}
+
+/// Ordered by `seq` first, which is unique per room
+///
+/// Everything after `content` is optional, so messages written before it existed are plain text messages
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Message {
+    /// Assigned by [`MongoManager::write_message`], `0` for messages written before sequence numbers
+    #[serde(default)]
+    pub seq: i64,
     pub timestamp: DateTime,
     pub author: String,
+    /// Text, or the emoji of a reaction
     pub content: String,
+    #[serde(default)]
+    pub kind: MessageKind,
+    /// `seq` of the message a reply or reaction refers to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Chosen by the client, a retried send with the same id returns the original message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+}
+
+#[cfg(test)]
+impl Message {
+    /// Text message `"hi"` of `user`, written now
+    pub(crate) fn test(seq: i64) -> Self {
+        Self {
+            seq,
+            timestamp: DateTime::now(),
+            author: "user".to_string(),
+            content: "hi".to_string(),
+            kind: MessageKind::Text,
+            reply_to: None,
+            attachments: vec![],
+            client_msg_id: None,
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    Reply,
+    Reaction,
+    /// Written by the worker itself, e.g. when a room is created
+    System,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::Reply => "reply",
+            MessageKind::Reaction => "reaction",
+            MessageKind::System => "system",
+        }
+    }
+}
+
+/// Metadata of a file, the file itself is kept by the attachment store
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    /// In bytes
+    pub size: u64,
+    pub storage_key: String,
 }
 
 impl MongoManager {
+    /// Creates a regular room, names of direct rooms are reserved for [`MongoManager::open_direct`]
     #[instrument(skip_all)]
     pub async fn add_room(room_name: &str, room_conf: RoomConfig) -> Result<String> {
-        let room_name = room_name.to_lowercase();
+        let room_name = RoomName::parse(room_name)?;
+        if is_direct_name(&room_name) {
+            bail!(MatrixErr::IllegalRoomName(
+                room_name.to_string(),
+                "the -dm suffix is reserved for direct rooms"
+            ));
+        }
+        Self::add_any_room(room_name, room_conf).await
+    }
+
+    /// Creates a room of any kind
+    pub(crate) async fn add_any_room(room_name: RoomName, room_conf: RoomConfig) -> Result<String> {
         let manager = mappings::write_manager(&room_name)
             .await
             .with_context(|| format!("Can't get manager for room {room_name}"))?;
 
-        manager
-            .create_room(&room_name, &room_conf)
-            .await
+        check_config(&room_conf)?;
+        let created = match manager.layout().await.map_err(|e| fritz!(manager, e))? {
+            Layout::PerRoom => {
+                manager
+                    .create_room(&room_name, &room_conf, &RoomHead::default())
+                    .await
+            }
+            Layout::Shared => {
+                manager
+                    .create_shared_room(&room_name, &room_conf, &RoomHead::default())
+                    .await
+            }
+        };
+        created
             .context("Failed to create room")
             .map_err(|e| fritz!(manager, e))??;
+        if let Err(e) = manager.index_room(&room_name, &room_conf).await {
+            // The room works without its entry, it is only missing from listings until the instance is reindexed
+            warn!(?e, "Unable to index room");
+        }
 
-        Ok(room_name)
+        Ok(room_name.to_string())
     }
 
-    #[instrument(skip_all)]
-    pub async fn write_message(room: &str, message: Message) -> Result<()> {
-        let room = room.to_lowercase();
+    /// Canonical name of the room if `user` is a member, for state that is kept outside of Mongo
+    #[instrument]
+    pub async fn member_room(room: &str, user: &str) -> Result<String> {
+        let room = RoomName::parse(room)?;
         let manager = mappings::write_manager(&room)
             .await
             .with_context(|| format!("Can't get manager for room {room}"))?;
+        manager
+            .check_member(&room, user)
+            .await
+            .context("Unable to check membership")
+            .map_err(|e| fritz!(manager, e))??;
+        Ok(room.to_string())
+    }
 
-        if !manager
-            .check_access(&room, &message.author)
+    /// Returns the message as it was stored, with its sequence number and the attachments as they were uploaded
+    #[instrument(skip_all)]
+    pub async fn write_message(room: &str, mut message: Message) -> Result<Message> {
+        let room = RoomName::parse(room)?;
+        check_message(&message).map_err(MatrixErr::from)?;
+        Self::resolve_attachments(&room, &mut message.attachments).await?;
+        // The instance a migrating room is moved away from comes along as old
+        let (manager, old) = mappings::write_managers(&room)
             .await
-            .context("Unable to check access")
-            .map_err(|e| fritz!(manager, e))?
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+
+        let message = manager.send(&room, old.as_ref(), message).await?;
+        if let Some(old) = &old
+            && let Err(e) = manager.adopt_entry(&room, old).await
         {
-            bail!("No access")
+            warn!(?e, "Unable to adopt room index entry");
         }
+        Ok(message)
+    }
 
-        let (col, next_id) = manager
-            .get_chat_collection(&room)
+    /// Writes the message once per client message id
+    async fn send(
+        &self,
+        room: &RoomName,
+        old: Option<&MongoManager>,
+        message: Message,
+    ) -> Result<Message> {
+        let Some(client_msg_id) = message.client_msg_id.clone() else {
+            return self.insert_message(room, message).await;
+        };
+        let author = message.author.clone();
+        // Stored messages are only handed out to members
+        self.member_state(room, &author)
             .await
-            .context(INTERNAL_ERR_MSG)
-            .map_err(|e| fritz!(manager, e))??;
+            .context("Unable to check membership")
+            .map_err(|e| fritz!(self, e))??;
+
+        // Sends from before the migration started claimed on the old instance
+        if let Some(old) = old {
+            match old
+                .find_claim(room, &author, &client_msg_id)
+                .await
+                .context("Unable to check client message id")
+                .map_err(|e| fritz!(old, e))?
+            {
+                Some(Claim::Sent(original)) => {
+                    info!(
+                        client_msg_id,
+                        original.seq, "Send was retried during migration"
+                    );
+                    return Ok(original);
+                }
+                Some(_) => bail!(MatrixErr::SendInProgress(client_msg_id)),
+                None => {}
+            }
+        }
+        match self
+            .claim_send(room, &author, &client_msg_id)
+            .await
+            .context("Unable to claim client message id")
+            .map_err(|e| fritz!(self, e))?
+        {
+            Claim::New => {}
+            Claim::Sent(original) => {
+                info!(client_msg_id, original.seq, "Send was retried");
+                return Ok(original);
+            }
+            Claim::InProgress => bail!(MatrixErr::SendInProgress(client_msg_id)),
+            // The send before may have written the message, but failed to store it in the claim
+            Claim::TakenOver => {
+                if let Some(original) = self
+                    .find_sent(room, &author, &client_msg_id)
+                    .await
+                    .context("Unable to look up sent message")
+                    .map_err(|e| fritz!(self, e))?
+                {
+                    info!(
+                        client_msg_id,
+                        original.seq, "Send was retried after a failure"
+                    );
+                    self.finish_send(room, &original)
+                        .await
+                        .context("Unable to store sent message")
+                        .map_err(|e| fritz!(self, e))?;
+                    return Ok(original);
+                }
+            }
+        }
+
+        match self.insert_message(room, message).await {
+            Ok(message) => {
+                if let Err(e) = self.finish_send(room, &message).await {
+                    // The claim goes stale, the retry taking it over finds the message by its id
+                    warn!(?e, "Unable to store sent message");
+                }
+                Ok(message)
+            }
+            Err(e) => {
+                if let Err(e) = self.release_send(room, &author, &client_msg_id).await {
+                    warn!(?e, "Unable to release client message id");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes the next sequence number and stores the message in whichever layout the room is in
+    ///
+    /// Gives up after [`MAX_WRITE_DURATION`]
+    #[instrument(skip_all)]
+    async fn insert_message(&self, room: &RoomName, message: Message) -> Result<Message> {
+        timeout(MAX_WRITE_DURATION, self.insert_unbounded(room, message))
+            .await
+            .context("Write took too long, abandoned it")?
+    }
 
-        info!(col);
+    async fn insert_unbounded(&self, room: &RoomName, mut message: Message) -> Result<Message> {
+        if self.layout().await.map_err(|e| fritz!(self, e))? == Layout::Shared
+            && let Some(state) = self
+                .next_shared_seq(room, &message.author, message.timestamp)
+                .await
+                .context("Unable to get sequence number")
+                .map_err(|e| fritz!(self, e))??
+        {
+            message.seq = state.head.seq;
+            check_reference(&message)?;
+            info!(message.seq, "Writing to shared room");
+            self.write_shared(room, &message)
+                .await
+                .context("Can't perform write")
+                .map_err(|e| fritz!(self, e))?;
+            if let Err(e) = self.ensure_indexes(SHARED_DB, MESSAGES_COL).await {
+                warn!(?e, "Unable to create bucket indexes");
+            }
+            return Ok(message);
+        }
 
-        let col = manager
-            .actual_col_name(&room, col, next_id)
+        let state = self
+            .next_seq(room, &message.author, message.timestamp)
             .await
-            .context("Can't get actual collection name")?;
+            .context("Unable to get sequence number")
+            .map_err(|e| fritz!(self, e))??;
+        message.seq = state.head.seq;
+        check_reference(&message)?;
 
-        manager
-            .write(&room, &col, &message)
+        let index = state
+            .config
+            .bucketing
+            .bucket(message.seq, message.timestamp);
+        let col = format!("{CHAT_PREFIX}_{index}");
+        info!(col, message.seq);
+
+        self.write(room, &col, &message)
             .await
-            .context("Can't perform write")?;
+            .context("Can't perform write")
+            .map_err(|e| fritz!(self, e))?;
+        self.cache.record_write(room, state.config, index);
+        if let Err(e) = self.ensure_indexes(room, &col).await {
+            warn!(?e, "Unable to create bucket indexes");
+        }
 
-        Ok(())
+        Ok(message)
     }
 
+    /// Reads the newest `n` messages of a room, or the `n` before the cursor `before` from [`next_before`]
+    ///
+    /// Sorted by sequence number, messages written before sequence numbers come first
     #[instrument(skip_all)]
-    pub async fn read_messages(room: &str, n: usize) -> Result<(Vec<Message>, u32)> {
+    pub async fn read_messages(
+        room: &str,
+        n: usize,
+        before: Option<i64>,
+    ) -> Result<(Vec<Message>, u32)> {
+        let room = RoomName::parse(room)?;
         let (messages, cnt) = match mappings::read_manager(&room).await {
             Ok(either::Left(manager)) => manager
-                .read_n(&room, n)
+                .read_n(&room, n, before)
                 .await
                 .context(INTERNAL_ERR_MSG)
                 .map_err(|e| fritz!(manager, e))??,
             Ok(either::Right((man, mig_m))) => {
                 // Not the optimal approach, but the only one that guarantees that no messages are lost
-                let (res, mig_res) = tokio::join!(man.read_n(&room, n), mig_m.read_n(&room, n),);
+                let (res, mig_res) =
+                    tokio::join!(man.read_n(&room, n, before), mig_m.read_n(&room, n, before));
                 let (mut messages, collections_read) = res
                     .context("Failed to read from manager")
                     .context(INTERNAL_ERR_MSG) // First context internal, second for return val
@@ -114,20 +418,21 @@ impl MongoManager {
             .into_iter()
             .collect::<Vec<_>>();
         messages.sort_unstable();
+        // Both instances may have returned `n` messages during migrations
+        let excess = messages.len().saturating_sub(n);
+        messages.drain(..excess);
 
         Ok((messages, cnt))
     }
 
     #[instrument(skip(self, room_name), level = "debug")]
-    async fn create_room(
+    pub(crate) async fn create_room(
         &self,
         room_name: &str,
         room_config: &RoomConfig,
+        head: &RoomHead,
     ) -> Result<Result<(), MatrixErr>> {
-        if INVALID_ROOM_NAMES.contains(&room_name) {
-            return Ok(Err(MatrixErr::IllegalRoomName(room_name.to_string())));
-        }
-        match self.get_chat_collection(&room_name).await {
+        match self.get_chat_collection(room_name).await {
             Ok(Err(MatrixErr::RoomNotFound(_))) => {}
             Ok(Err(e)) => {
                 error!(
@@ -143,19 +448,70 @@ impl MongoManager {
             Err(e) => return Err(e),
         }
 
+        let mut config_doc =
+            bson::to_document(room_config).context("Unable to serialize room config")?;
+        config_doc.insert(SEQ_KEY, head.seq);
+        if let Some(last_ts) = head.last_ts {
+            config_doc.insert(LAST_TS_KEY, last_ts);
+        }
+
         let col_name = format!("{CHAT_PREFIX}_0");
-        backoff!(self)
-            .database(&room_name)
-            .collection::<RoomConfig>(&col_name)
-            .insert_one(room_config)
+        let db = backoff!(self).database(room_name);
+        db.collection::<Document>(&col_name)
+            .insert_one(config_doc)
             .await
             .context("Unable to create room")?;
 
-        debug!("Created room");
+        debug!(%room_config.bucketing, "Created room");
 
         Ok(Ok(()))
     }
 
+    /// Checks that `user_name` is a member of the room, in whichever layout the room is in
+    ///
+    /// returns: Result<Result<(), MatrixErr>>
+    /// - Outer Err: Mongo Error
+    /// - Inner Err: Mongo works, but the room does not exist or the user is not a member
+    #[instrument(skip(self, room))]
+    pub(crate) async fn check_member(
+        &self,
+        room: &str,
+        user_name: &str,
+    ) -> Result<Result<(), MatrixErr>> {
+        Ok(self.member_state(room, user_name).await?.map(|_| ()))
+    }
+
+    /// Like [`Self::check_member`], but returns the state of the room
+    #[instrument(skip(self, room))]
+    pub(crate) async fn member_state(
+        &self,
+        room: &str,
+        user_name: &str,
+    ) -> Result<Result<RoomState, MatrixErr>> {
+        let shared = if self.layout().await? == Layout::Shared {
+            self.shared_state(room).await?
+        } else {
+            None
+        };
+        let state = match shared {
+            Some(state) => Some(state),
+            None => backoff!(self)
+                .database(room)
+                .collection::<RoomState>(&format!("{CHAT_PREFIX}_0"))
+                .find_one(doc! {})
+                .await
+                .context("Unable to get config")?,
+        };
+
+        match state {
+            None => Ok(Err(MatrixErr::RoomNotFound(room.to_string()))),
+            Some(state) if !state.config.allowed_users.iter().any(|u| u == user_name) => {
+                Ok(Err(MatrixErr::NotInRoom(room.to_string())))
+            }
+            Some(state) => Ok(Ok(state)),
+        }
+    }
+
     /// Get Collection of a room with the newest messages
     ///
     /// # Arguments
@@ -170,8 +526,57 @@ impl MongoManager {
     /// - Error: Something went wrong
     #[instrument(skip_all)]
     async fn get_chat_collection(&self, room: &str) -> Result<Result<(String, u32), MatrixErr>> {
+        let indices = self.chat_collections(room).await?;
+
+        match indices.last() {
+            None => Ok(Err(MatrixErr::RoomNotFound(room.to_string()))),
+            Some(&last) => {
+                let count = last.max(1);
+                Ok(Ok((format!("{CHAT_PREFIX}_{count}"), count + 1)))
+            }
+        }
+    }
+
+    /// Like [`Self::chat_collections`], but only lists the collections if the cache is missing the newest one
+    ///
+    /// `None` if the room does not exist
+    #[instrument(skip_all)]
+    pub(crate) async fn cached_chat_collections(&self, room: &str) -> Result<Option<Vec<u32>>> {
+        let col = backoff!(self)
+            .database(room)
+            .collection::<RoomHead>(&format!("{CHAT_PREFIX}_0"));
+        let Some(head) = col
+            .find_one(doc! {})
+            .projection(doc! { SEQ_KEY: 1, LAST_TS_KEY: 1 })
+            .await
+            .context("Unable to get room head")?
+        else {
+            self.cache.invalidate(room);
+            return Ok(None);
+        };
+        if let Some(indices) = self.cache.buckets(room, &head) {
+            return Ok(Some(indices));
+        }
+
+        let config = col
+            .clone_with_type::<RoomConfig>()
+            .find_one(doc! {})
+            .await
+            .context("Unable to get config")?
+            .context("Room config vanished")?;
+        let indices = self.chat_collections(room).await?;
+        self.cache.insert(room, config, indices.clone());
+
+        Ok(Some(indices))
+    }
+
+    /// Sorted indices of all chat collections of a room, including the metadata collection (`0`)
+    ///
+    /// Empty if the room does not exist
+    #[instrument(skip_all)]
+    pub(crate) async fn chat_collections(&self, room: &str) -> Result<Vec<u32>> {
         let client = backoff!(self);
-        let db = client.database(&room);
+        let db = client.database(room);
         let mut col_cursor = db
             .list_collections()
             .await
@@ -188,17 +593,20 @@ impl MongoManager {
                         .context("Can't execute get call for collection")?
                         .context("Name of collection is not set")?;
                     let name = name.as_str().unwrap_or("").to_string();
+                    if name == RECEIPTS_COL {
+                        continue;
+                    }
                     if !name.starts_with(CHAT_PREFIX) {
                         error!(name, "Invalid collection name found");
                         bail!("Internal server error");
                     }
                     let index = name[CHAT_PREFIX.len() + 1..]
                         .parse::<u32>()
-                        .map_err(|e| {
+                        .inspect_err(|_e| {
                             error!(name, "Invalid collection name found (no '_' after prefix, or invalid num at end)");
-                            e
                         })
                         .context("Internal server error")?;
+                    trace!(index, "Pushing index");
                     names.push(index);
                 }
                 Ok(false) => break,
@@ -209,128 +617,174 @@ impl MongoManager {
             };
         }
         names.sort_unstable();
-        if names.is_empty() {
-            Ok(Err(MatrixErr::RoomNotFound(room.to_string())))
-        } else {
-            let count = names[names.len() - 1].max(1);
-            Ok(Ok((format!("{CHAT_PREFIX}_{count}"), count + 1)))
-        }
+
+        Ok(names)
     }
 
-    #[instrument(skip(self, room))]
-    async fn actual_col_name(&self, room: &str, col_name: String, next_num: u32) -> Result<String> {
-        let client = backoff!(self);
-        let col = client.database(&room).collection::<Message>(&col_name);
-        let doc_count = col
-            .estimated_document_count()
-            .await
-            .context("Can't get doc count")?;
+    /// Atomically checks access and takes the next sequence number of a room
+    ///
+    /// returns: Result<Result<RoomState, MatrixErr>>
+    /// - Outer Err: Mongo Error
+    /// - Inner Err: Mongo works, but the room does not exist or the user is not a member
+    /// - RoomState: Config after the update, the sequence number is unique and gapless per room (unless a write
+    ///   fails afterwards)
+    #[instrument(skip(self, room, timestamp))]
+    async fn next_seq(
+        &self,
+        room: &str,
+        user_name: &str,
+        timestamp: DateTime,
+    ) -> Result<Result<RoomState, MatrixErr>> {
+        let col_name = format!("{CHAT_PREFIX}_0");
+        let col = backoff!(self)
+            .database(room)
+            .collection::<RoomState>(&col_name);
 
-        if doc_count < MAX_MSGS_PER_COL {
-            // If == it's already full
-            debug!(doc_count, "Returning original name");
-            Ok(col_name)
-        } else {
-            let new_name = format!("{CHAT_PREFIX}_{next_num}");
-            debug!(
-                new_name,
-                doc_count, "Maximum reached, writing in new collection"
-            );
-            Ok(new_name)
+        for _ in 0..SEQ_ATTEMPTS {
+            let state = col
+                .find_one_and_update(
+                    doc! {
+                        SEQ_KEY: { "$exists": true },
+                        MOVED_KEY: { "$exists": false },
+                        "allowed_users": user_name,
+                    },
+                    doc! {
+                        "$inc": { SEQ_KEY: 1_i64 },
+                        "$max": { LAST_TS_KEY: timestamp },
+                    },
+                )
+                .return_document(ReturnDocument::After)
+                .await
+                .context("Unable to increment sequence number")?;
+
+            if let Some(state) = state {
+                return Ok(Ok(state));
+            }
+
+            // Slow path, find out why nothing matched
+            let Some(conf) = col
+                .clone_with_type::<Document>()
+                .find_one(doc! {})
+                .await
+                .context("Unable to get config")?
+            else {
+                return Ok(Err(MatrixErr::RoomNotFound(room.to_string())));
+            };
+            let is_allowed = conf
+                .get_array("allowed_users")
+                .context("Room config has no allowed users")?
+                .iter()
+                .any(|u| u.as_str() == Some(user_name));
+            if !is_allowed {
+                return Ok(Err(MatrixErr::NotInRoom(room.to_string())));
+            }
+            // Sealed by the converter, which creates the shared room right after
+            if conf.contains_key(MOVED_KEY) {
+                return Ok(Err(MatrixErr::RoomMigrating(room.to_string())));
+            }
+            if !conf.contains_key(SEQ_KEY) {
+                self.init_seq(room).await?;
+            }
         }
+
+        bail!("Unable to get sequence number after {SEQ_ATTEMPTS} attempts")
     }
 
-    #[instrument(skip(self, room))]
-    async fn check_access(&self, room: &str, user_name: &String) -> Result<bool> {
+    /// Rooms created before sequence numbers continue after their last bucket
+    ///
+    /// These rooms can only use the default [`Bucketing::Count`]
+    #[instrument(skip_all)]
+    async fn init_seq(&self, room: &str) -> Result<()> {
+        let seq = self.initial_seq(room).await?;
+
         let col_name = format!("{CHAT_PREFIX}_0");
-        let is_allowed = backoff!(self)
-            .database(&room)
-            .collection::<RoomConfig>(&col_name)
-            .find_one(bson::doc! {})
+        backoff!(self)
+            .database(room)
+            .collection::<Document>(&col_name)
+            // Only the first writer initializes, everybody else just retries
+            .update_one(
+                doc! { SEQ_KEY: { "$exists": false } },
+                doc! { "$set": { SEQ_KEY: seq } },
+            )
             .await
-            .context("Unable to get config")?
-            .context("No conf in room (how?)")?
-            .allowed_users
-            .contains(user_name);
+            .context("Unable to initialize sequence number")?;
+
+        info!(seq, "Initialized sequence number");
+        Ok(())
+    }
 
-        Ok(is_allowed)
+    /// First sequence number of a room created before sequence numbers, continues after its last bucket
+    pub(crate) async fn initial_seq(&self, room: &str) -> Result<i64> {
+        let last_bucket = self
+            .chat_collections(room)
+            .await?
+            .last()
+            .copied()
+            .unwrap_or_default();
+        Ok(i64::from(last_bucket) * DEFAULT_BUCKET_SIZE)
     }
 
     #[instrument(skip(self, room, msg))]
     async fn write(&self, room: &str, collection: &str, msg: &Message) -> Result<()> {
         debug!("Writing message");
+        let mut doc = bson::to_document(msg).context("Unable to serialize message")?;
+        if let Some(id) = sent_id(room, msg) {
+            doc.insert("_id", id);
+        }
         let client = backoff!(self);
-        let col = client.database(&room).collection::<Message>(&collection);
-        col.insert_one(msg).await.context("Failed to insert msg")?;
+        let col = client.database(room).collection::<Document>(collection);
+        col.insert_one(doc).await.context("Failed to insert msg")?;
 
         Ok(())
     }
 
     #[instrument(skip_all)]
-    async fn read_n(&self, room: &str, n: usize) -> Result<Result<(Vec<Message>, u32), MatrixErr>> {
-        debug!("Trying to read up to n");
-        let client = backoff!(self);
-
-        let db = client.database(&room);
-        if !self
-            .room_exists(&room)
-            .await
-            .context("Unable to check if room exists")?
+    async fn read_n(
+        &self,
+        room: &str,
+        n: usize,
+        before: Option<i64>,
+    ) -> Result<Result<(Vec<Message>, u32), MatrixErr>> {
+        if self.layout().await? == Layout::Shared
+            && let Some(read) = self.read_n_shared(room, n, before).await?
         {
-            return Ok(Err(MatrixErr::RoomNotFound(room.to_string())));
+            return Ok(Ok(read));
         }
-        let mut col_cursor = db
-            .list_collections()
-            .await
-            .context("Can't list connections")?;
-
-        let mut names = vec![];
+        self.read_n_per_room(room, n, before).await
+    }
 
-        loop {
-            match col_cursor.advance().await {
-                Ok(true) => {
-                    let collection_name = col_cursor
-                        .current()
-                        .get("name")
-                        .context("Can't execute get call for collection")?
-                        .context("Name of collection is not set")?
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-                    if !collection_name.starts_with(CHAT_PREFIX) {
-                        error!(collection_name, "Invalid collection name found");
-                        bail!("Internal server error");
-                    }
-                    let index = collection_name[CHAT_PREFIX.len() + 1..]
-                        .parse::<u32>()
-                        .map_err(|e| {
-                            error!(collection_name, "Invalid collection name found (no '_' after prefix, or invalid num at end)");
-                            e
-                        })
-                        .context("Internal server error")?;
-                    if index != 0 {
-                        // Skip metadata collection
-                        trace!(index, "Pushing index");
-                        names.push(index);
-                    }
-                }
-                Ok(false) => break,
-                Err(e) => {
-                    error!(?e, "Error while advancing");
-                    bail!("Can't get collection for chat because of an error");
-                }
-            };
-        }
-        names.sort_unstable();
+    pub(crate) async fn read_n_per_room(
+        &self,
+        room: &str,
+        n: usize,
+        before: Option<i64>,
+    ) -> Result<Result<(Vec<Message>, u32), MatrixErr>> {
+        debug!("Trying to read up to n");
+        let Some(indices) = self.cached_chat_collections(room).await? else {
+            return Ok(Err(MatrixErr::RoomNotFound(room.to_string())));
+        };
+        // Buckets after `before` can't contain anything older, unless they are split by time
+        let highest = before
+            .and_then(|b| self.cache.bucketing(room)?.last_bucket_before(b))
+            .unwrap_or(u32::MAX);
+        // Skip metadata collection
+        let names = indices
+            .into_iter()
+            .filter(|&i| i != 0 && i <= highest)
+            .collect::<Vec<_>>();
+        let filter = before_filter(before);
 
         let mut actual_read = 0;
         let mut collections_read = 0;
         let mut messages = vec![];
 
         for i in (0..names.len()).rev() {
+            if actual_read >= n {
+                break;
+            }
             let read_col = format!("{CHAT_PREFIX}_{col_idx}", col_idx = names[i]);
             let new_messages = self
-                .read_collection(&room, &read_col)
+                .read_collection(room, &read_col, filter.clone(), n - actual_read)
                 .await
                 .with_context(|| format!("Failed to read collection {read_col:?}"))?;
 
@@ -344,40 +798,29 @@ impl MongoManager {
                 total_read = actual_read,
                 "nth run"
             );
-            if actual_read >= n {
-                break;
-            }
         }
 
         Ok(Ok((messages, collections_read)))
     }
 
-    #[instrument(skip_all)]
-    async fn room_exists(&self, room: &str) -> Result<bool> {
-        debug!("We are checking");
-        let mut col_cursor = backoff!(self)
-            .database(&room)
-            .list_collections()
-            .await
-            .context("Can't list connections")?;
-
-        let exists = col_cursor.advance().await.context("Can't advance cursor")?;
-        debug!(exists, "We have checked");
-        Ok(exists)
-    }
-
+    /// Reads the newest `limit` messages of a collection that match `filter`, messages without sequence number by time
     #[instrument(skip(self, room))]
-    async fn read_collection(&self, room: &str, col: &str) -> Result<Vec<Message>> {
-        let col = backoff!(self).database(&room).collection::<Message>(&col);
+    async fn read_collection(
+        &self,
+        room: &str,
+        col: &str,
+        filter: Document,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let col = backoff!(self).database(room).collection::<Message>(col);
         let mut msg_cursor = col
-            .find(bson::doc! {})
+            .find(filter)
+            .sort(doc! { SEQ_KEY: -1, "timestamp": -1 })
+            .limit(limit as i64)
             .await
             .with_context(|| format!("Can't read messages from db {room:?} with col {col:?}"))?;
 
-        let col_size = col.estimated_document_count().await.with_context(|| {
-            format!("Can't get estimated doc count for db {room:?} with col {col:?}")
-        })?;
-        let mut messages = Vec::with_capacity(col_size as usize);
+        let mut messages = Vec::with_capacity(limit);
 
         loop {
             match msg_cursor.advance().await {
@@ -396,3 +839,208 @@ impl MongoManager {
         Ok(messages)
     }
 }
+
+/// Matches the messages before the cursor `before`, or every message
+///
+/// Positive cursors are sequence numbers. Messages written before sequence numbers are older than any of them and
+/// are paged by time instead, with their negated timestamp in milliseconds as cursor
+pub(crate) fn before_filter(before: Option<i64>) -> Document {
+    match before {
+        Some(before) if before > 0 => doc! {
+            "$or": [
+                { SEQ_KEY: { "$lt": before } },
+                { SEQ_KEY: { "$exists": false } },
+            ]
+        },
+        Some(before) => doc! {
+            SEQ_KEY: { "$in": [null, 0_i64] },
+            "timestamp": { "$lt": DateTime::from_millis(before.saturating_neg()) },
+        },
+        None => doc! {},
+    }
+}
+
+/// Cursor for the page before `messages`, if there may be one, see [`before_filter`]
+///
+/// Messages without sequence number written in the same millisecond as the oldest one on the page are skipped
+pub fn next_before(messages: &[Message], n: usize) -> Option<i64> {
+    if messages.len() < n {
+        return None;
+    }
+    let oldest = messages.first()?;
+    let millis = oldest.timestamp.timestamp_millis();
+    match oldest.seq {
+        seq if seq > 1 => Some(seq),
+        0 if millis > 0 => Some(-millis),
+        _ => None,
+    }
+}
+
+/// Rules every layout has in common
+pub(crate) fn check_config(room_config: &RoomConfig) -> Result<(), MatrixErr> {
+    if let Err(e) = room_config.bucketing.validate() {
+        return Err(MatrixErr::InvalidRoomConfig(e.to_string()));
+    }
+    if let Some(retention) = room_config.retention
+        && let Err(e) = retention.validate(room_config.bucketing)
+    {
+        return Err(MatrixErr::InvalidRoomConfig(e.to_string()));
+    }
+    Ok(())
+}
+
+/// Replies and reactions can only refer to messages that were written before them
+///
+/// The referenced message itself isn't looked up, it may have been removed in the meantime
+fn check_reference(message: &Message) -> Result<(), MatrixErr> {
+    match message.reply_to {
+        Some(seq) if seq >= message.seq => Err(ContentErr::InvalidReference(seq).into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[test]
+    fn plain_messages_still_deserialize() {
+        let legacy = doc! {
+            "timestamp": DateTime::now(),
+            "author": "user",
+            "content": "hi",
+        };
+        let message = bson::from_document::<Message>(legacy).unwrap();
+        assert_eq!(message.seq, 0);
+        assert_eq!(message.kind, MessageKind::Text);
+        assert_eq!(message.reply_to, None);
+        assert!(message.attachments.is_empty());
+    }
+
+    #[test]
+    fn optional_fields_are_omitted() {
+        let message = Message::test(1);
+        let doc = bson::to_document(&message).unwrap();
+        assert_eq!(doc.get_str("kind"), Ok("text"));
+        assert!(!doc.contains_key("reply_to"));
+        assert!(!doc.contains_key("attachments"));
+
+        let reaction = Message {
+            kind: MessageKind::Reaction,
+            reply_to: Some(1),
+            ..message
+        };
+        let doc = bson::to_document(&reaction).unwrap();
+        assert_eq!(bson::from_document::<Message>(doc).unwrap(), reaction);
+    }
+
+    #[test]
+    fn pages_by_sequence_number() {
+        let at = Message::test;
+        assert_eq!(next_before(&[at(5), at(6)], 2), Some(5));
+        // Short page, nothing older
+        assert_eq!(next_before(&[at(5), at(6)], 3), None);
+        assert_eq!(next_before(&[at(1), at(2)], 2), None);
+        assert_eq!(next_before(&[], 0), None);
+
+        assert_eq!(before_filter(None), doc! {});
+        // Messages from before sequence numbers are older than every numbered one
+        let filter = before_filter(Some(5));
+        let branches = filter.get_array("$or").unwrap();
+        assert!(branches.contains(&bson::bson!({ SEQ_KEY: { "$lt": 5_i64 } })));
+        assert!(branches.contains(&bson::bson!({ SEQ_KEY: { "$exists": false } })));
+    }
+
+    #[test]
+    fn pages_legacy_messages_by_time() {
+        let at = |millis| Message {
+            timestamp: DateTime::from_millis(millis),
+            ..Message::test(0)
+        };
+        let numbered = Message {
+            seq: 101,
+            ..at(3_000)
+        };
+
+        let before = next_before(&[at(2_000), numbered], 2);
+        assert_eq!(before, Some(-2_000));
+        assert_eq!(
+            before_filter(before),
+            doc! {
+                SEQ_KEY: { "$in": [null, 0_i64] },
+                "timestamp": { "$lt": DateTime::from_millis(2_000) },
+            }
+        );
+        assert_eq!(next_before(&[at(1_000), at(2_000)], 2), Some(-1_000));
+        assert_eq!(next_before(&[at(1_000)], 2), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Mongo at MONGO_URL"]
+    async fn pages_through_a_legacy_bucket() {
+        let url = std::env::var("MONGO_URL").unwrap();
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        let manager = MongoManager::new(&url, Uuid::new_v4(), err_tx, Metrics::new()).await;
+        let room = format!("legacy-{}", Uuid::new_v4().simple());
+        let db = manager.client.as_ref().as_ref().unwrap().database(&room);
+
+        // Written before sequence numbers, the room continues after its last bucket
+        db.collection::<Document>(&format!("{CHAT_PREFIX}_0"))
+            .insert_one(doc! { "allowed_users": ["user"], SEQ_KEY: 103_i64 })
+            .await
+            .unwrap();
+        let legacy = (1..=5).map(|i| {
+            doc! {
+                "timestamp": DateTime::from_millis(i * 1_000),
+                "author": "user",
+                "content": format!("legacy {i}"),
+            }
+        });
+        db.collection::<Document>(&format!("{CHAT_PREFIX}_1"))
+            .insert_many(legacy)
+            .await
+            .unwrap();
+        let numbered = (101..=103).map(|seq| Message {
+            timestamp: DateTime::from_millis(seq * 1_000),
+            content: format!("numbered {seq}"),
+            ..Message::test(seq)
+        });
+        db.collection::<Message>(&format!("{CHAT_PREFIX}_2"))
+            .insert_many(numbered)
+            .await
+            .unwrap();
+
+        let mut read = vec![];
+        let mut before = None;
+        loop {
+            let (mut page, _) = manager
+                .read_n_per_room(&room, 2, before)
+                .await
+                .unwrap()
+                .unwrap();
+            page.sort_unstable();
+            page.drain(..page.len().saturating_sub(2));
+            before = next_before(&page, 2);
+            read.splice(0..0, page);
+            if before.is_none() {
+                break;
+            }
+        }
+        db.drop().await.unwrap();
+
+        let contents = read.iter().map(|m| m.content.as_str());
+        assert!(contents.eq([
+            "legacy 1",
+            "legacy 2",
+            "legacy 3",
+            "legacy 4",
+            "legacy 5",
+            "numbered 101",
+            "numbered 102",
+            "numbered 103",
+        ]));
+    }
+}
@@ -0,0 +1,347 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::layout::{Layout, SHARED_DB};
+use crate::messaging::{CHAT_PREFIX, MOVED_KEY, RECEIPTS_COL, SEQ_KEY};
+use crate::room::RoomName;
+use anyhow::{Context, Result, bail};
+use bson::doc;
+use futures::{StreamExt, TryStreamExt, stream};
+use matrix_errors::{ContentErr, MatrixErr};
+use mongodb::options::ReturnDocument;
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{debug, info, instrument};
+
+/// Read receipts of shared rooms, `_id` is `<room>/<user>`
+const SHARED_RECEIPTS_COL: &str = "receipts";
+/// Rooms of an instance whose head and receipt are read at the same time
+const ROOM_LOOKUPS: usize = 16;
+
+#[derive(Debug, Deserialize)]
+struct Receipt {
+    #[serde(rename = "_id")]
+    id: String,
+    seq: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SharedReceipt {
+    room: String,
+    user: String,
+    seq: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Head {
+    #[serde(rename = "_id", default)]
+    id: String,
+    #[serde(default)]
+    seq: i64,
+}
+
+/// Unread messages of a user in one room
+///
+/// Counted by sequence number, so the own messages of the user count as well until they are marked as read
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct UnreadCount {
+    pub room: String,
+    /// Sequence number of the newest message
+    pub last_seq: i64,
+    /// Sequence number of the newest message the user has read, `0` if none
+    pub read_seq: i64,
+    pub unread: i64,
+}
+
+impl UnreadCount {
+    fn new(room: String, last_seq: i64, read_seq: i64) -> Self {
+        Self {
+            room,
+            last_seq,
+            read_seq,
+            unread: (last_seq - read_seq).max(0),
+        }
+    }
+
+    /// Rooms can be on two instances during migrations, whichever is further counts
+    fn merge(self, other: Self) -> Self {
+        Self::new(
+            self.room,
+            self.last_seq.max(other.last_seq),
+            self.read_seq.max(other.read_seq),
+        )
+    }
+}
+
+impl MongoManager {
+    /// Marks the messages of a room up to and including `seq` as read by `user`
+    ///
+    /// Receipts never move backwards, returns the sequence number the user has read up to afterwards
+    #[instrument]
+    pub async fn mark_read(room: &str, user: &str, seq: i64) -> Result<i64> {
+        let room = RoomName::parse(room)?;
+        let manager = mappings::write_manager(&room)
+            .await
+            .with_context(|| format!("Can't get manager for room {room}"))?;
+        let state = manager
+            .member_state(&room, user)
+            .await
+            .context("Unable to check membership")
+            .map_err(|e| fritz!(manager, e))??;
+        if seq <= 0 || seq > state.head.seq {
+            bail!(MatrixErr::from(ContentErr::InvalidReference(seq)));
+        }
+
+        let read_seq = manager
+            .store_receipt(&room, user, seq)
+            .await
+            .context("Unable to store read receipt")
+            .map_err(|e| fritz!(manager, e))?;
+        debug!(read_seq, "Marked room as read");
+        Ok(read_seq)
+    }
+
+    /// Unread messages of every room `user` is a member of, sorted by room
+    ///
+    /// Asks every instance, rooms are only found on the instances that hold their entry in the room index
+    #[instrument]
+    pub async fn unread_counts(user: &str) -> Result<Vec<UnreadCount>> {
+        let managers = mappings::all_managers().await;
+        let results =
+            futures::future::join_all(managers.iter().map(|m| m.unread_on_instance(user))).await;
+
+        let mut by_room = BTreeMap::<String, UnreadCount>::new();
+        for (manager, res) in managers.iter().zip(results) {
+            let counts = res
+                .context("Unable to get unread counts")
+                .map_err(|e| fritz!(manager, e))?;
+            for count in counts {
+                let merged = match by_room.remove(&count.room) {
+                    Some(existing) => existing.merge(count),
+                    None => count,
+                };
+                by_room.insert(merged.room.clone(), merged);
+            }
+        }
+
+        Ok(by_room.into_values().collect())
+    }
+
+    #[instrument(skip(self, room))]
+    pub(crate) async fn store_receipt(&self, room: &str, user: &str, seq: i64) -> Result<i64> {
+        if self.layout().await? == Layout::Shared && self.shared_state(room).await?.is_some() {
+            let receipt = self
+                .shared_receipts()
+                .await?
+                .find_one_and_update(
+                    doc! { "_id": format!("{room}/{user}") },
+                    doc! {
+                        "$max": { SEQ_KEY: seq },
+                        "$setOnInsert": { "room": room, "user": user },
+                    },
+                )
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .await
+                .context("Unable to update receipt")?
+                .context("Upserted receipt vanished")?;
+            return Ok(receipt.seq);
+        }
+
+        let receipt = backoff!(self)
+            .database(room)
+            .collection::<Receipt>(RECEIPTS_COL)
+            .find_one_and_update(doc! { "_id": user }, doc! { "$max": { SEQ_KEY: seq } })
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await
+            .context("Unable to update receipt")?
+            .context("Upserted receipt vanished")?;
+        Ok(receipt.seq)
+    }
+
+    /// Unread counts of the rooms of `user` on this instance, in both layouts
+    ///
+    /// Found through the room index, rooms created before it only show up once the instance was reindexed
+    #[instrument(skip(self))]
+    async fn unread_on_instance(&self, user: &str) -> Result<Vec<UnreadCount>> {
+        let rooms = self.indexed_rooms(user).await?;
+        if rooms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut heads = self
+            .rooms()?
+            .clone_with_type::<Head>()
+            .find(doc! { "_id": { "$in": &rooms }, "allowed_users": user })
+            .projection(doc! { SEQ_KEY: 1 })
+            .await
+            .context("Unable to find shared rooms")?;
+        let mut counts = vec![];
+        let mut read = self
+            .shared_receipts()
+            .await?
+            .find(doc! { "user": user })
+            .await
+            .context("Unable to find receipts")?
+            .map_ok(|r| (r.room, r.seq))
+            .try_collect::<BTreeMap<_, _>>()
+            .await
+            .context("Unable to read receipts")?;
+        let mut shared = BTreeSet::new();
+        while let Some(head) = heads.try_next().await.context("Unable to read room")? {
+            let read_seq = read.remove(&head.id).unwrap_or_default();
+            shared.insert(head.id.clone());
+            counts.push(UnreadCount::new(head.id, head.seq, read_seq));
+        }
+
+        let per_room = stream::iter(rooms.into_iter().filter(|room| !shared.contains(room)))
+            .map(|room| self.room_unread(room, user))
+            .buffer_unordered(ROOM_LOOKUPS)
+            .try_filter_map(|count| async move { Ok(count) })
+            .try_collect::<Vec<_>>()
+            .await?;
+        counts.extend(per_room);
+
+        Ok(counts)
+    }
+
+    /// `None` if `user` is not a member of the room, or the room was moved to the shared layout
+    async fn room_unread(&self, room: String, user: &str) -> Result<Option<UnreadCount>> {
+        let db = backoff!(self).database(&room);
+        let Some(head) = db
+            .collection::<Head>(&format!("{CHAT_PREFIX}_0"))
+            .find_one(doc! { "allowed_users": user, MOVED_KEY: { "$exists": false } })
+            .projection(doc! { SEQ_KEY: 1 })
+            .await
+            .with_context(|| format!("Unable to get head of room {room:?}"))?
+        else {
+            return Ok(None);
+        };
+        let read_seq = db
+            .collection::<Receipt>(RECEIPTS_COL)
+            .find_one(doc! { "_id": user })
+            .await
+            .with_context(|| format!("Unable to get receipt of room {room:?}"))?
+            .map(|r| r.seq)
+            .unwrap_or_default();
+
+        Ok(Some(UnreadCount::new(room, head.seq, read_seq)))
+    }
+
+    /// Users of a room and the sequence numbers they have read up to, sorted by user
+    #[instrument(skip(self))]
+    pub(crate) async fn room_receipts(
+        &self,
+        room: &str,
+        shared: bool,
+    ) -> Result<Vec<(String, i64)>> {
+        let mut receipts = if shared {
+            self.shared_receipts()
+                .await?
+                .find(doc! { "room": room })
+                .await
+                .context("Unable to find receipts")?
+                .map_ok(|r| (r.user, r.seq))
+                .try_collect::<Vec<_>>()
+                .await
+        } else {
+            backoff!(self)
+                .database(room)
+                .collection::<Receipt>(RECEIPTS_COL)
+                .find(doc! {})
+                .await
+                .context("Unable to find receipts")?
+                .map_ok(|r| (r.id, r.seq))
+                .try_collect::<Vec<_>>()
+                .await
+        }
+        .context("Unable to read receipts")?;
+        receipts.sort_unstable();
+        Ok(receipts)
+    }
+
+    /// Removes the receipts of a shared room, those of other rooms go with their database
+    pub(crate) async fn discard_receipts(&self, room: &str) -> Result<()> {
+        self.shared_receipts()
+            .await?
+            .delete_many(doc! { "room": room })
+            .await
+            .context("Unable to remove receipts")?;
+        Ok(())
+    }
+
+    /// Moves the receipts of a room that is converted to the shared layout
+    #[instrument(skip(self))]
+    pub(crate) async fn copy_receipts(&self, room: &str) -> Result<()> {
+        let receipts = backoff!(self)
+            .database(room)
+            .collection::<Receipt>(RECEIPTS_COL)
+            .find(doc! {})
+            .await
+            .context("Unable to read receipts")?
+            .try_collect::<Vec<_>>()
+            .await
+            .context("Unable to read receipts")?;
+
+        let shared = self.shared_receipts().await?;
+        for receipt in &receipts {
+            shared
+                .update_one(
+                    doc! { "_id": format!("{room}/{}", receipt.id) },
+                    doc! {
+                        "$max": { SEQ_KEY: receipt.seq },
+                        "$setOnInsert": { "room": room, "user": &receipt.id },
+                    },
+                )
+                .upsert(true)
+                .await
+                .context("Unable to copy receipt")?;
+        }
+
+        info!(copied = receipts.len(), "Copied receipts");
+        Ok(())
+    }
+
+    /// The receipts of shared rooms, indexed by user once per instance
+    async fn shared_receipts(&self) -> Result<Collection<SharedReceipt>> {
+        let col = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<SharedReceipt>(SHARED_RECEIPTS_COL);
+
+        let namespace = format!("{SHARED_DB}.{SHARED_RECEIPTS_COL}");
+        if self.cache.claim_index(&namespace) {
+            let index = IndexModel::builder().keys(doc! { "user": 1 }).build();
+            if let Err(e) = col.create_index(index).await {
+                self.cache.release_index(&namespace);
+                return Err(e).context("Unable to create receipt index");
+            }
+        }
+
+        Ok(col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unread_messages() {
+        let count = UnreadCount::new("room".to_string(), 10, 4);
+        assert_eq!(count.unread, 6);
+        // Receipts of a newer copy of the room
+        assert_eq!(UnreadCount::new("room".to_string(), 3, 5).unread, 0);
+    }
+
+    #[test]
+    fn merges_copies_of_a_room() {
+        let old = UnreadCount::new("room".to_string(), 10, 8);
+        let new = UnreadCount::new("room".to_string(), 12, 2);
+        let merged = old.merge(new);
+        assert_eq!(
+            (merged.last_seq, merged.read_seq, merged.unread),
+            (12, 8, 4)
+        );
+    }
+}
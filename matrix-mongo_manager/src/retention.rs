@@ -0,0 +1,423 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::bucketing::Bucketing;
+use crate::layout::{MESSAGES_COL, SHARED_DB};
+use crate::messaging::{CHAT_PREFIX, MOVED_KEY, Message, RoomState, SEQ_KEY};
+use crate::room::INVALID_ROOM_NAMES;
+use anyhow::{Context, Result, bail};
+use bson::{Bson, DateTime, Document, doc};
+use futures::TryStreamExt;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tracing::{debug, info, instrument, warn};
+
+const RETENTION_KEY: &str = "retention";
+const MAX_RETENTION_DAYS: u32 = 100 * 365;
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long the messages of a room are kept
+///
+/// Rooms in their own database only drop whole buckets, so they keep up to one bucket more than their retention asks
+/// for. Shared rooms delete exactly the expired messages. Files of expired messages go with them, unless newer
+/// messages still refer to them
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Retention {
+    /// Messages older than `days`, buckets once their newest message is
+    MaxAge { days: u32 },
+    /// Messages older than the newest `messages`, buckets once all of theirs are
+    MaxCount { messages: i64 },
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RetentionReport {
+    pub rooms: usize,
+    pub dropped_buckets: usize,
+    pub deleted_messages: u64,
+    pub deleted_attachments: usize,
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SharedRetention {
+    #[serde(rename = "_id")]
+    room: String,
+    retention: Retention,
+    #[serde(default)]
+    seq: i64,
+}
+
+impl Retention {
+    pub(crate) fn validate(&self, bucketing: Bucketing) -> Result<()> {
+        match self {
+            Self::MaxAge { days } if !(1..=MAX_RETENTION_DAYS).contains(days) => {
+                bail!("Retention has to be between 1 and {MAX_RETENTION_DAYS} days, got {days}")
+            }
+            Self::MaxCount { messages } if *messages < 1 => {
+                bail!("Retention has to keep at least one message, got {messages}")
+            }
+            _ if bucketing == Bucketing::Single => {
+                bail!(
+                    "Rooms with a single bucket can't drop buckets, use a count or time based bucketing"
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a bucket can be dropped, by the newest message in it
+    fn expired(&self, newest: &Message, head_seq: i64, now: DateTime) -> bool {
+        match self {
+            Self::MaxAge { .. } => newest.timestamp < self.age_cutoff(now),
+            Self::MaxCount { messages } => newest.seq <= head_seq - messages,
+        }
+    }
+
+    fn age_cutoff(&self, now: DateTime) -> DateTime {
+        let Self::MaxAge { days } = self else {
+            return DateTime::MIN;
+        };
+        let age = DAY * *days;
+        DateTime::from_millis(now.timestamp_millis() - age.as_millis() as i64)
+    }
+
+    /// Messages of a shared room that can be deleted, `None` if there are none
+    fn shared_filter(&self, head_seq: i64, now: DateTime) -> Option<Document> {
+        match self {
+            Self::MaxAge { .. } => Some(doc! { "timestamp": { "$lt": self.age_cutoff(now) } }),
+            Self::MaxCount { messages } => {
+                let cutoff = head_seq - messages;
+                (cutoff > 0).then(|| doc! { SEQ_KEY: { "$lte": cutoff } })
+            }
+        }
+    }
+}
+
+impl MongoManager {
+    /// Drops the expired buckets of every room with a retention, on every instance
+    ///
+    /// Should only run on one worker at a time, the sweeps of two would only get in each other's way
+    #[instrument]
+    pub async fn sweep_retention() -> Result<RetentionReport> {
+        let mut report = RetentionReport::default();
+        for manager in mappings::all_managers().await {
+            if let Err(e) = manager.sweep_instance(&mut report).await {
+                warn!(id = ?manager.db_id, ?e, "Unable to sweep instance");
+                report.failed.push(manager.db_id.to_string());
+            }
+        }
+
+        info!(
+            rooms = report.rooms,
+            dropped_buckets = report.dropped_buckets,
+            deleted_messages = report.deleted_messages,
+            deleted_attachments = report.deleted_attachments,
+            failed = report.failed.len(),
+            "Swept expired messages"
+        );
+        Ok(report)
+    }
+
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    async fn sweep_instance(&self, report: &mut RetentionReport) -> Result<()> {
+        let now = DateTime::now();
+
+        let shared = self
+            .rooms()?
+            .clone_with_type::<SharedRetention>()
+            .find(doc! { RETENTION_KEY: { "$exists": true } })
+            .await
+            .context("Unable to find shared rooms")?
+            .try_collect::<Vec<_>>()
+            .await
+            .context("Unable to read shared rooms")?;
+        for room in shared {
+            report.rooms += 1;
+            match self.sweep_shared_room(&room, now).await {
+                Ok((messages, files)) => {
+                    report.deleted_messages += messages;
+                    report.deleted_attachments += files;
+                }
+                Err(e) => {
+                    warn!(room.room, ?e, "Unable to delete expired messages");
+                    report.failed.push(room.room);
+                }
+            }
+        }
+
+        let rooms = backoff!(self)
+            .list_database_names()
+            .await
+            .context("Unable to list databases")?
+            .into_iter()
+            .filter(|name| !INVALID_ROOM_NAMES.contains(&name.as_str()));
+        for room in rooms {
+            match self.sweep_room(&room, now).await {
+                Ok(None) => {}
+                Ok(Some((dropped, files))) => {
+                    report.rooms += 1;
+                    report.dropped_buckets += dropped;
+                    report.deleted_attachments += files;
+                }
+                Err(e) => {
+                    warn!(room, ?e, "Unable to sweep room");
+                    report.failed.push(room);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Numbers of deleted messages and files
+    #[instrument(skip_all, fields(room = room.room))]
+    async fn sweep_shared_room(
+        &self,
+        room: &SharedRetention,
+        now: DateTime,
+    ) -> Result<(u64, usize)> {
+        let Some(mut filter) = room.retention.shared_filter(room.seq, now) else {
+            return Ok((0, 0));
+        };
+        filter.insert("room", &room.room);
+        let messages = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Document>(MESSAGES_COL);
+
+        let mut files = file_refs(&messages, filter.clone()).await?;
+        if !files.is_empty() {
+            let kept = doc! {
+                "room": &room.room,
+                "$nor": [&filter],
+                "attachments.storage_key": { "$in": files.iter().collect::<Vec<_>>() },
+            };
+            for key in file_refs(&messages, kept).await? {
+                files.remove(&key);
+            }
+        }
+        // Files first, messages whose files are gone are deleted again by the next sweep
+        let deleted_files = self.delete_files(&room.room, files).await?;
+
+        let deleted = messages
+            .delete_many(filter)
+            .await
+            .context("Unable to delete expired messages")?;
+        Ok((deleted.deleted_count, deleted_files))
+    }
+
+    /// Numbers of dropped buckets and deleted files, `None` if the room has no retention
+    #[instrument(skip(self, now))]
+    async fn sweep_room(&self, room: &str, now: DateTime) -> Result<Option<(usize, usize)>> {
+        let db = backoff!(self).database(room);
+        let Some(state) = db
+            .collection::<RoomState>(&format!("{CHAT_PREFIX}_0"))
+            .find_one(doc! { RETENTION_KEY: { "$exists": true }, MOVED_KEY: { "$exists": false } })
+            .await
+            .context("Unable to get room config")?
+        else {
+            return Ok(None);
+        };
+        let Some(retention) = state.config.retention else {
+            return Ok(None);
+        };
+
+        let buckets = self.chat_collections(room).await?;
+        // The newest bucket is still written to
+        let Some((_, older)) = buckets.split_last() else {
+            return Ok(Some((0, 0)));
+        };
+
+        let mut dropped = 0;
+        let mut deleted_files = 0;
+        for &index in older.iter().filter(|&&i| i != 0) {
+            let col = format!("{CHAT_PREFIX}_{index}");
+            let bucket = db.collection::<Message>(&col);
+            let newest = bucket
+                .find_one(doc! {})
+                .sort(doc! { SEQ_KEY: -1, "timestamp": -1 })
+                .await
+                .with_context(|| format!("Unable to get newest message of {col:?}"))?;
+            // Buckets only get newer, the ones after this one are kept as well
+            if !newest.is_some_and(|m| retention.expired(&m, state.head.seq, now)) {
+                break;
+            }
+
+            let mut files = file_refs(&bucket.clone_with_type(), doc! {}).await?;
+            for newer in buckets.iter().filter(|&&i| i > index) {
+                if files.is_empty() {
+                    break;
+                }
+                let kept = doc! {
+                    "attachments.storage_key": { "$in": files.iter().collect::<Vec<_>>() },
+                };
+                let newer = db.collection::<Document>(&format!("{CHAT_PREFIX}_{newer}"));
+                for key in file_refs(&newer, kept).await? {
+                    files.remove(&key);
+                }
+            }
+            // Files first, a bucket whose files are gone is dropped again by the next sweep
+            deleted_files += self.delete_files(room, files).await?;
+
+            bucket
+                .drop()
+                .await
+                .with_context(|| format!("Unable to drop {col:?}"))?;
+            self.cache.release_index(&format!("{room}.{col}"));
+            debug!(col, "Dropped expired bucket");
+            dropped += 1;
+        }
+
+        if dropped > 0 {
+            self.cache.invalidate(room);
+        }
+        Ok(Some((dropped, deleted_files)))
+    }
+
+    async fn delete_files(&self, room: &str, keys: BTreeSet<String>) -> Result<usize> {
+        for key in &keys {
+            self.delete_file(room, key).await?;
+        }
+        if !keys.is_empty() {
+            debug!(files = keys.len(), "Deleted files of expired messages");
+        }
+        Ok(keys.len())
+    }
+}
+
+/// Storage keys of the attachments of the messages that match `filter`
+async fn file_refs(messages: &Collection<Document>, filter: Document) -> Result<BTreeSet<String>> {
+    let keys = messages
+        .distinct("attachments.storage_key", filter)
+        .await
+        .context("Unable to find attachments")?;
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| match key {
+            Bson::String(key) => Some(key),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::FileMeta;
+    use crate::messaging::{Attachment, RoomConfig};
+    use matrix_metrics::Metrics;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn message(seq: i64, timestamp: DateTime) -> Message {
+        Message {
+            timestamp,
+            ..Message::test(seq)
+        }
+    }
+
+    #[test]
+    fn validates_retention() {
+        let count = Bucketing::Count { size: 10 };
+        assert!(Retention::MaxAge { days: 30 }.validate(count).is_ok());
+        assert!(Retention::MaxAge { days: 0 }.validate(count).is_err());
+        assert!(Retention::MaxCount { messages: 0 }.validate(count).is_err());
+        assert!(
+            Retention::MaxCount { messages: 100 }
+                .validate(Bucketing::Single)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn expires_buckets() {
+        let now = DateTime::now();
+        let days_ago = |days: i64| {
+            DateTime::from_millis(now.timestamp_millis() - days * DAY.as_millis() as i64)
+        };
+
+        let age = Retention::MaxAge { days: 30 };
+        assert!(age.expired(&message(1, days_ago(31)), 100, now));
+        assert!(!age.expired(&message(1, days_ago(29)), 100, now));
+
+        let count = Retention::MaxCount { messages: 50 };
+        assert!(count.expired(&message(50, now), 100, now));
+        assert!(!count.expired(&message(51, now), 100, now));
+    }
+
+    #[test]
+    fn filters_shared_messages() {
+        let count = Retention::MaxCount { messages: 50 };
+        assert_eq!(
+            count.shared_filter(120, DateTime::now()),
+            Some(doc! { SEQ_KEY: { "$lte": 70_i64 } })
+        );
+        assert_eq!(count.shared_filter(50, DateTime::now()), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local Mongo at MONGO_URL"]
+    async fn expired_buckets_take_their_files() {
+        let url = std::env::var("MONGO_URL").unwrap();
+        let (err_tx, _err_rx) = mpsc::channel(1);
+        let manager = MongoManager::new(&url, Uuid::new_v4(), err_tx, Metrics::new()).await;
+        let room = format!("retention-{}", Uuid::new_v4().simple());
+        let db = manager.client.as_ref().as_ref().unwrap().database(&room);
+
+        let config = RoomConfig {
+            allowed_users: vec!["user".to_string()],
+            bucketing: Bucketing::Count { size: 2 },
+            public: false,
+            retention: Some(Retention::MaxCount { messages: 1 }),
+        };
+        let mut config = bson::to_document(&config).unwrap();
+        config.insert(SEQ_KEY, 3_i64);
+        db.collection::<Document>(&format!("{CHAT_PREFIX}_0"))
+            .insert_one(config)
+            .await
+            .unwrap();
+
+        let (expired, shared) = (Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        for key in [expired, shared] {
+            let meta = FileMeta {
+                room: room.clone(),
+                author: "user".to_string(),
+                name: "cat.png".to_string(),
+                mime: "image/png".to_string(),
+            };
+            manager
+                .store_file(&key.to_string(), &meta, b"data")
+                .await
+                .unwrap();
+        }
+        let with_files = |seq, keys: &[_]| Message {
+            attachments: keys
+                .iter()
+                .map(|key: &uuid::fmt::Simple| Attachment {
+                    name: "cat.png".to_string(),
+                    mime: "image/png".to_string(),
+                    size: 4,
+                    storage_key: key.to_string(),
+                })
+                .collect(),
+            ..Message::test(seq)
+        };
+        db.collection::<Message>(&format!("{CHAT_PREFIX}_1"))
+            .insert_many([with_files(1, &[expired, shared]), Message::test(2)])
+            .await
+            .unwrap();
+        // Sent again by a message that is kept
+        db.collection::<Message>(&format!("{CHAT_PREFIX}_2"))
+            .insert_one(with_files(3, &[shared]))
+            .await
+            .unwrap();
+
+        let swept = manager.sweep_room(&room, DateTime::now()).await.unwrap();
+        let mut keys = manager.file_keys(&room).await.unwrap();
+        db.drop().await.unwrap();
+
+        assert_eq!(swept, Some((1, 1)));
+        assert_eq!(keys.pop(), Some(shared.to_string()));
+        assert!(keys.is_empty());
+    }
+}
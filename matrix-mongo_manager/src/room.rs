@@ -0,0 +1,152 @@
+use crate::layout::SHARED_DB;
+use matrix_errors::MatrixErr;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Mongo databases that can't be used for rooms
+pub(crate) const INVALID_ROOM_NAMES: &[&str] = &["admin", "config", "local", SHARED_DB];
+/// Mongo database names have to be shorter than 64 bytes
+pub const MAX_ROOM_NAME_BYTES: usize = 63;
+/// Not allowed in Mongo database names on any platform
+const FORBIDDEN_CHARS: &[char] = &['/', '\\', '.', '"', '$', '*', '<', '>', ':', '|', '?'];
+
+/// The one normalization every room name and namespace boundary goes through
+///
+/// Routing compares names with the boundaries of the instances, so both have to be canonical
+pub fn canonicalize(name: &str) -> String {
+    // Lowercasing can undo the composition, so compose again afterwards
+    let lowercase = name.nfkc().collect::<String>().to_lowercase();
+    lowercase.nfc().collect()
+}
+
+/// Validated and normalized room name, also a valid Mongo database name
+///
+/// Names are NFKC-normalized and lowercased by [`canonicalize`], so every spelling of a room ends up in the same
+/// database
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct RoomName(String);
+
+impl RoomName {
+    pub fn parse(name: &str) -> Result<Self, MatrixErr> {
+        let illegal = |reason| Err(MatrixErr::IllegalRoomName(name.to_string(), reason));
+
+        let normalized = canonicalize(name);
+
+        if normalized.is_empty() {
+            return illegal("it is empty");
+        }
+        if normalized.len() > MAX_ROOM_NAME_BYTES {
+            return illegal("it is longer than 63 bytes");
+        }
+        if normalized.chars().any(|c| FORBIDDEN_CHARS.contains(&c)) {
+            return illegal(r#"it contains one of / \ . " $ * < > : | ?"#);
+        }
+        if normalized.chars().any(char::is_whitespace) {
+            return illegal("it contains whitespace");
+        }
+        if normalized.chars().any(char::is_control) {
+            return illegal("it contains control characters");
+        }
+        if INVALID_ROOM_NAMES.contains(&normalized.as_str()) {
+            return illegal("it is reserved");
+        }
+
+        Ok(Self(normalized))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for RoomName {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for RoomName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for RoomName {
+    type Err = MatrixErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str) -> Option<String> {
+        RoomName::parse(name).ok().map(|n| n.to_string())
+    }
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(parse("MyRoom").as_deref(), Some("myroom"));
+        assert_eq!(parse("room_1-2").as_deref(), Some("room_1-2"));
+        // Precomposed and decomposed umlaut
+        assert_eq!(parse("Gr\u{00FC}n"), parse("Gru\u{0308}n"));
+        assert_eq!(parse("GRU\u{0308}N").as_deref(), Some("gr\u{00FC}n"));
+        // Fullwidth letters
+        assert_eq!(parse("\u{FF32}oom").as_deref(), Some("room"));
+    }
+
+    #[test]
+    fn rejects_illegal_characters() {
+        for name in [
+            "a/b",
+            "a\\b",
+            "a.b",
+            "a b",
+            "a\"b",
+            "a$b",
+            "a*b",
+            "a<b",
+            "a>b",
+            "a:b",
+            "a|b",
+            "a?b",
+            "a\0b",
+            "a\tb",
+            "a\u{00A0}b",
+            "a\u{7F}b",
+        ] {
+            assert_eq!(parse(name), None, "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_reserved_and_long_names() {
+        assert_eq!(parse(""), None);
+        for name in INVALID_ROOM_NAMES {
+            assert_eq!(parse(name), None);
+            assert_eq!(parse(&name.to_uppercase()), None);
+        }
+
+        let max = "a".repeat(MAX_ROOM_NAME_BYTES);
+        assert_eq!(parse(&max), Some(max.clone()));
+        assert_eq!(parse(&format!("{max}a")), None);
+        // Limit is in bytes, not chars
+        assert_eq!(parse(&"ü".repeat(32)), None);
+    }
+
+    #[test]
+    fn reports_name_and_reason() {
+        let err = RoomName::parse("a.b").unwrap_err();
+        assert!(matches!(err, MatrixErr::IllegalRoomName(ref name, _) if name == "a.b"));
+        assert!(err.to_string().contains("a.b"));
+    }
+}
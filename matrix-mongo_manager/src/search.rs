@@ -0,0 +1,373 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::bucketing::Bucketing;
+use crate::layout::{Layout, SHARED_DB, unique_seq_index};
+use crate::messaging::{CHAT_PREFIX, Message, SEQ_KEY};
+use crate::room::RoomName;
+use crate::thread::collect_messages;
+use anyhow::{Context, Result, bail};
+use bson::{DateTime, Document, doc};
+use matrix_errors::MatrixErr;
+use mongodb::IndexModel;
+use mongodb::options::IndexOptions;
+use serde::Serialize;
+use std::collections::HashSet;
+use tracing::{debug, instrument, trace, warn};
+
+const INTERNAL_ERR_MSG: &str = "Internal server error";
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+pub const MAX_SEARCH_LIMIT: usize = 100;
+const MAX_QUERY_CHARS: usize = 256;
+const TEXT_INDEX: &str = "content_text";
+
+/// Full-text search within a room, newest messages first
+#[derive(Clone, Debug, Default)]
+pub struct SearchQuery {
+    /// Words to look for, `"quoted phrases"` and `-excluded` words work like in Mongo text search
+    pub text: String,
+    pub author: Option<String>,
+    /// Inclusive
+    pub from: Option<DateTime>,
+    /// Exclusive
+    pub to: Option<DateTime>,
+    /// Only messages with a lower sequence number, taken from [`SearchPage::next_before`]
+    pub before: Option<i64>,
+    /// Clamped to [`MAX_SEARCH_LIMIT`]
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub messages: Vec<Message>,
+    /// Set if there may be more results, pass it as [`SearchQuery::before`] to get them
+    pub next_before: Option<i64>,
+}
+
+impl SearchQuery {
+    fn validate(&self) -> Result<(), MatrixErr> {
+        if self.text.trim().is_empty() {
+            return Err(MatrixErr::InvalidSearch("the query is empty"));
+        }
+        if self.text.chars().count() > MAX_QUERY_CHARS {
+            return Err(MatrixErr::InvalidSearch(
+                "the query is longer than 256 chars",
+            ));
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to)
+            && from >= to
+        {
+            return Err(MatrixErr::InvalidSearch("from has to be before to"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.clamp(1, MAX_SEARCH_LIMIT)
+    }
+
+    /// Everything but the room, which only the shared layout needs
+    pub(crate) fn filter(&self) -> Document {
+        let mut filter = doc! { "$text": { "$search": &self.text } };
+        if let Some(author) = &self.author {
+            filter.insert("author", author);
+        }
+        let mut timestamp = Document::new();
+        if let Some(from) = self.from {
+            timestamp.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            timestamp.insert("$lt", to);
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+        if let Some(before) = self.before {
+            filter.insert(SEQ_KEY, doc! { "$lt": before });
+        }
+        filter
+    }
+}
+
+impl MongoManager {
+    /// Searches the messages of a room with the text indexes of its buckets
+    ///
+    /// Messages written before sequence numbers all share `seq` 0, so they can't be paginated past the first page
+    /// they show up on
+    #[instrument(skip(query), fields(text = query.text))]
+    pub async fn search_messages(room: &str, query: SearchQuery) -> Result<SearchPage> {
+        let room = RoomName::parse(room)?;
+        query.validate()?;
+        let limit = query.limit();
+
+        let messages = match mappings::read_manager(&room).await {
+            Ok(either::Left(manager)) => manager
+                .search(&room, &query)
+                .await
+                .context(INTERNAL_ERR_MSG)
+                .map_err(|e| fritz!(manager, e))??,
+            Ok(either::Right((man, mig_m))) => {
+                let (res, mig_res) =
+                    tokio::join!(man.search(&room, &query), mig_m.search(&room, &query));
+                let mut messages = res
+                    .context("Failed to search manager")
+                    .context(INTERNAL_ERR_MSG)
+                    .map_err(|e| fritz!(man, e))??;
+                messages.extend(
+                    mig_res
+                        .context("Failed to search migration manager")
+                        .context(INTERNAL_ERR_MSG)
+                        .map_err(|e| fritz!(mig_m, e))??,
+                );
+                messages
+            }
+            Err(e) => {
+                warn!(?e, "Failed to get migration manager");
+                bail!(INTERNAL_ERR_MSG);
+            }
+        };
+
+        let mut messages = messages
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        messages.sort_unstable_by(|a, b| b.cmp(a));
+        messages.truncate(limit);
+
+        let next_before = match messages.last() {
+            Some(last) if messages.len() == limit && last.seq > 0 => Some(last.seq),
+            _ => None,
+        };
+        debug!(found = messages.len(), ?next_before, "Searched room");
+        Ok(SearchPage {
+            messages,
+            next_before,
+        })
+    }
+
+    /// Up to `limit` matches of one instance, newest first
+    #[instrument(skip_all)]
+    async fn search(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+    ) -> Result<Result<Vec<Message>, MatrixErr>> {
+        if self.layout().await? == Layout::Shared
+            && let Some(found) = self.search_shared(room, query).await?
+        {
+            return Ok(Ok(found));
+        }
+        self.search_per_room(room, query).await
+    }
+
+    pub(crate) async fn search_per_room(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+    ) -> Result<Result<Vec<Message>, MatrixErr>> {
+        let Some(indices) = self.cached_chat_collections(room).await? else {
+            return Ok(Err(MatrixErr::RoomNotFound(room.to_string())));
+        };
+        let candidates = search_buckets(self.cache.bucketing(room), &indices, query);
+
+        let db = backoff!(self).database(room);
+        let filter = query.filter();
+        let limit = query.limit();
+        let mut messages = vec![];
+        for index in candidates {
+            if messages.len() >= limit {
+                break;
+            }
+            let col = format!("{CHAT_PREFIX}_{index}");
+            self.ensure_indexes(room, &col).await?;
+
+            let cursor = db
+                .collection::<Message>(&col)
+                .find(filter.clone())
+                .sort(doc! { SEQ_KEY: -1 })
+                .limit((limit - messages.len()) as i64)
+                .await
+                .with_context(|| format!("Can't search collection {col:?}"))?;
+            let found = collect_messages(cursor).await?;
+            trace!(col, found = found.len(), "Searched bucket");
+            messages.extend(found);
+        }
+
+        Ok(Ok(messages))
+    }
+
+    /// Creates the sequence and text indexes of a bucket, once per instance and bucket
+    ///
+    /// Called whenever a message is written, buckets created before search existed get theirs on the first search.
+    /// Reads sort and paginate by sequence number, which would scan the whole bucket otherwise
+    #[instrument(skip(self))]
+    pub(crate) async fn ensure_indexes(&self, db: &str, col: &str) -> Result<()> {
+        let namespace = format!("{db}.{col}");
+        if !self.cache.claim_index(&namespace) {
+            return Ok(());
+        }
+
+        // The shared collection is only ever searched within one room, its sequence index comes with the layout
+        let (text_keys, seq_keys) = if db == SHARED_DB {
+            (doc! { "room": 1, "content": "text" }, None)
+        } else {
+            (doc! { "content": "text" }, Some(doc! { SEQ_KEY: 1 }))
+        };
+        let options = IndexOptions::builder()
+            .name(TEXT_INDEX.to_string())
+            // Rooms are in every language, so words are matched as they are
+            .default_language("none".to_string())
+            .build();
+        let indexes = seq_keys
+            .map(|keys| IndexModel::builder().keys(keys).build())
+            .into_iter()
+            .chain([IndexModel::builder()
+                .keys(text_keys)
+                .options(options)
+                .build()]);
+
+        let col = backoff!(self).database(db).collection::<Document>(col);
+        if let Err(e) = col.create_indexes(indexes).await {
+            self.cache.release_index(&namespace);
+            return Err(e).context("Unable to create bucket indexes");
+        }
+        // Instances that were converted before it existed, fails as long as the room has duplicates
+        if db == SHARED_DB
+            && let Err(e) = col.create_index(unique_seq_index()).await
+        {
+            self.cache.release_index(&namespace);
+            return Err(e).context("Unable to create unique sequence index");
+        }
+        debug!("Created bucket indexes");
+        Ok(())
+    }
+}
+
+/// Buckets that may contain matches, newest first
+///
+/// Rooms with time based buckets skip the ones outside of `from`/`to`, counted ones the ones after `before`
+fn search_buckets(bucketing: Option<Bucketing>, indices: &[u32], query: &SearchQuery) -> Vec<u32> {
+    let (mut lowest, mut highest) = (1, u32::MAX);
+    match bucketing {
+        Some(bucketing @ Bucketing::Count { .. }) => {
+            if let Some(last) = query.before.and_then(|b| bucketing.last_bucket_before(b)) {
+                highest = last;
+            }
+        }
+        Some(bucketing @ (Bucketing::Daily | Bucketing::Monthly)) => {
+            if let Some(from) = query.from {
+                lowest = bucketing.bucket(0, from);
+            }
+            if let Some(to) = query.to {
+                highest = bucketing.bucket(0, to);
+            }
+        }
+        Some(Bucketing::Single) | None => {}
+    }
+
+    indices
+        .iter()
+        .rev()
+        .copied()
+        .filter(|i| (lowest..=highest).contains(i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(y: i32, m: u32, d: u32) -> DateTime {
+        let date = chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        DateTime::from_chrono(date.and_hms_opt(12, 0, 0).unwrap().and_utc())
+    }
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            limit: DEFAULT_SEARCH_LIMIT,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validates_query() {
+        assert!(query("hello").validate().is_ok());
+        assert!(query(" ").validate().is_err());
+        assert!(query(&"a".repeat(MAX_QUERY_CHARS + 1)).validate().is_err());
+
+        let mut reversed = query("hello");
+        reversed.from = Some(ts(2026, 10, 18));
+        reversed.to = Some(ts(2026, 10, 17));
+        assert!(reversed.validate().is_err());
+
+        assert_eq!(
+            SearchQuery {
+                limit: 0,
+                ..query("a")
+            }
+            .limit(),
+            1
+        );
+        assert_eq!(
+            SearchQuery {
+                limit: 1000,
+                ..query("a")
+            }
+            .limit(),
+            MAX_SEARCH_LIMIT
+        );
+    }
+
+    #[test]
+    fn builds_filter() {
+        assert_eq!(
+            query("hello").filter(),
+            doc! { "$text": { "$search": "hello" } }
+        );
+
+        let full = SearchQuery {
+            author: Some("user".to_string()),
+            from: Some(ts(2026, 10, 1)),
+            to: Some(ts(2026, 10, 18)),
+            before: Some(42),
+            ..query("hello")
+        };
+        assert_eq!(
+            full.filter(),
+            doc! {
+                "$text": { "$search": "hello" },
+                "author": "user",
+                "timestamp": { "$gte": ts(2026, 10, 1), "$lt": ts(2026, 10, 18) },
+                SEQ_KEY: { "$lt": 42_i64 },
+            }
+        );
+    }
+
+    #[test]
+    fn skips_buckets_outside_of_query() {
+        let count = Some(Bucketing::Count { size: 10 });
+        let indices = [0, 1, 2, 3];
+        assert_eq!(search_buckets(count, &indices, &query("a")), [3, 2, 1]);
+        let before = SearchQuery {
+            before: Some(21),
+            ..query("a")
+        };
+        assert_eq!(search_buckets(count, &indices, &before), [2, 1]);
+
+        let days = [0, 20261016, 20261017, 20261018];
+        let range = SearchQuery {
+            from: Some(ts(2026, 10, 17)),
+            to: Some(ts(2026, 10, 17)),
+            ..query("a")
+        };
+        assert_eq!(
+            search_buckets(Some(Bucketing::Daily), &days, &range),
+            [20261017]
+        );
+        assert_eq!(
+            search_buckets(None, &days, &range),
+            [20261018, 20261017, 20261016]
+        );
+    }
+}
@@ -0,0 +1,552 @@
+use crate::MongoManager;
+use crate::idempotency::sent_id;
+use crate::layout::{LAYOUT_TTL, Layout, MESSAGES_COL, ROOMS_COL, SHARED_DB};
+use crate::messaging::{
+    CHAT_PREFIX, LAST_TS_KEY, MAX_WRITE_DURATION, MOVED_KEY, Message, RoomConfig, RoomHead,
+    RoomState, SEQ_KEY, before_filter,
+};
+use crate::room::INVALID_ROOM_NAMES;
+use crate::search::SearchQuery;
+use crate::thread::collect_messages;
+use anyhow::{Context, Result, bail};
+use bson::{DateTime, Document, doc};
+use futures::{StreamExt, stream};
+use matrix_errors::MatrixErr;
+use mongodb::error::{ErrorKind, InsertManyError, WriteFailure};
+use mongodb::options::ReturnDocument;
+use serde::Serialize;
+use std::mem;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, info, instrument, warn};
+
+/// Set on shared rooms whose messages are still (partly) in their own database
+pub(crate) const LEGACY_KEY: &str = "legacy";
+/// Time in-flight writes to a sealed room get to land before its messages are copied
+///
+/// Writes are abandoned after [`MAX_WRITE_DURATION`], the rest covers the way to Mongo
+const SEAL_GRACE: Duration = MAX_WRITE_DURATION.saturating_add(Duration::from_secs(5));
+/// Copy passes before a room that keeps receiving late writes is left for the next conversion
+const MAX_COPY_PASSES: u32 = 3;
+/// Rooms of an instance that are converted at the same time, each waits [`SEAL_GRACE`]
+const CONVERSIONS: usize = 16;
+const COPY_BATCH: usize = 1000;
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SharedMessage<'a> {
+    pub(crate) room: &'a str,
+    #[serde(flatten)]
+    pub(crate) message: &'a Message,
+}
+
+/// What happens to the database of a room after a copy pass
+#[derive(Debug, Eq, PartialEq)]
+enum CopyStep {
+    Drop,
+    Recopy,
+    /// Keeps the database, the room stays legacy until the next conversion
+    Abort,
+}
+
+impl CopyStep {
+    /// Every message a pass read is in the shared collection afterwards, only writes that landed during the pass
+    /// can be missing. The database is only dropped once it holds exactly what the last pass read
+    fn after_pass(pass: u32, read: u64, remaining: u64) -> Self {
+        if read == remaining {
+            CopyStep::Drop
+        } else if pass < MAX_COPY_PASSES {
+            CopyStep::Recopy
+        } else {
+            CopyStep::Abort
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ConversionReport {
+    pub converted: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl MongoManager {
+    /// Like `create_room`, but as a document in the shared `rooms` collection
+    #[instrument(skip(self, room_config), level = "debug")]
+    pub(crate) async fn create_shared_room(
+        &self,
+        room: &str,
+        room_config: &RoomConfig,
+        head: &RoomHead,
+    ) -> Result<Result<(), MatrixErr>> {
+        // Rooms that weren't converted yet still have their own database
+        if !self.chat_collections(room).await?.is_empty() {
+            return Ok(Err(MatrixErr::RoomAlreadyExists(room.to_string())));
+        }
+
+        let mut room_doc =
+            bson::to_document(room_config).context("Unable to serialize room config")?;
+        room_doc.insert("_id", room);
+        room_doc.insert(SEQ_KEY, head.seq);
+        if let Some(last_ts) = head.last_ts {
+            room_doc.insert(LAST_TS_KEY, last_ts);
+        }
+
+        match self.rooms()?.insert_one(room_doc).await {
+            Ok(_) => {}
+            Err(e) if only_duplicates(&e) => {
+                return Ok(Err(MatrixErr::RoomAlreadyExists(room.to_string())));
+            }
+            Err(e) => return Err(e).context("Unable to create room"),
+        }
+
+        debug!("Created shared room");
+        Ok(Ok(()))
+    }
+
+    /// Like `next_seq`, but for shared rooms
+    ///
+    /// returns: Result<Result<Option<RoomState>, MatrixErr>>
+    /// - Outer Err: Mongo Error
+    /// - Inner Err: Mongo works, but the user is not a member
+    /// - None: The room is not in the shared layout (yet)
+    #[instrument(skip(self, room, timestamp))]
+    pub(crate) async fn next_shared_seq(
+        &self,
+        room: &str,
+        user_name: &str,
+        timestamp: DateTime,
+    ) -> Result<Result<Option<RoomState>, MatrixErr>> {
+        let rooms = self.rooms()?;
+        let state = rooms
+            .clone_with_type::<RoomState>()
+            .find_one_and_update(
+                doc! { "_id": room, "allowed_users": user_name },
+                doc! {
+                    "$inc": { SEQ_KEY: 1_i64 },
+                    "$max": { LAST_TS_KEY: timestamp },
+                },
+            )
+            .return_document(ReturnDocument::After)
+            .await
+            .context("Unable to increment sequence number")?;
+        if state.is_some() {
+            return Ok(Ok(state));
+        }
+
+        // Slow path, find out why nothing matched
+        let exists = rooms
+            .find_one(doc! { "_id": room })
+            .projection(doc! { "_id": 1 })
+            .await
+            .context("Unable to get config")?
+            .is_some();
+        if exists {
+            return Ok(Err(MatrixErr::NotInRoom(room.to_string())));
+        }
+        Ok(Ok(None))
+    }
+
+    #[instrument(skip(self, room, msg))]
+    pub(crate) async fn write_shared(&self, room: &str, msg: &Message) -> Result<()> {
+        debug!("Writing message");
+        let mut message = bson::to_document(&SharedMessage { room, message: msg })
+            .context("Unable to serialize message")?;
+        if let Some(id) = sent_id(room, msg) {
+            message.insert("_id", id);
+        }
+        backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Document>(MESSAGES_COL)
+            .insert_one(message)
+            .await
+            .context("Failed to insert msg")?;
+
+        Ok(())
+    }
+
+    /// Like `read_n`, but for shared rooms, merges in the messages that weren't converted yet
+    ///
+    /// `None` if the room is not in the shared layout (yet)
+    #[instrument(skip_all)]
+    pub(crate) async fn read_n_shared(
+        &self,
+        room: &str,
+        n: usize,
+        before: Option<i64>,
+    ) -> Result<Option<(Vec<Message>, u32)>> {
+        let Some(room_doc) = self
+            .rooms()?
+            .find_one(doc! { "_id": room })
+            .projection(doc! { LEGACY_KEY: 1 })
+            .await
+            .context("Unable to get config")?
+        else {
+            return Ok(None);
+        };
+        if n == 0 {
+            return Ok(Some((vec![], 0)));
+        }
+        let mut filter = before_filter(before);
+        filter.insert("room", room);
+
+        let mut msg_cursor = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Message>(MESSAGES_COL)
+            .find(filter)
+            .sort(doc! { SEQ_KEY: -1, "timestamp": -1 })
+            .limit(n as i64)
+            .await
+            .with_context(|| format!("Can't read messages of room {room:?}"))?;
+
+        let mut messages = Vec::with_capacity(n);
+        while msg_cursor
+            .advance()
+            .await
+            .context("Advancing messages failed")?
+        {
+            match msg_cursor.deserialize_current() {
+                Ok(m) => messages.push(m),
+                Err(_) => debug!("Encountered migration marker"),
+            }
+        }
+        let mut collections_read = 1;
+
+        if room_doc.get_bool(LEGACY_KEY).unwrap_or_default() {
+            debug!("Room is still being converted");
+            // Dropped by the converter in the meantime, everything was copied then
+            if let Ok((legacy, legacy_read)) = self.read_n_per_room(room, n, before).await? {
+                messages.extend(legacy);
+                collections_read += legacy_read;
+            }
+        }
+
+        Ok(Some((messages, collections_read)))
+    }
+
+    /// Like `find_per_room_by_seq`, but for shared rooms
+    ///
+    /// `None` if the room is not in the shared layout (yet)
+    #[instrument(skip(self, room))]
+    pub(crate) async fn find_shared_by_seq(
+        &self,
+        room: &str,
+        seqs: &[i64],
+    ) -> Result<Option<Vec<Message>>> {
+        let Some(room_doc) = self
+            .rooms()?
+            .find_one(doc! { "_id": room })
+            .projection(doc! { LEGACY_KEY: 1 })
+            .await
+            .context("Unable to get config")?
+        else {
+            return Ok(None);
+        };
+
+        let cursor = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Message>(MESSAGES_COL)
+            .find(doc! { "room": room, SEQ_KEY: { "$in": seqs } })
+            .await
+            .with_context(|| format!("Can't find messages of room {room:?}"))?;
+        let mut messages = collect_messages(cursor).await?;
+
+        if room_doc.get_bool(LEGACY_KEY).unwrap_or_default() && messages.len() < seqs.len() {
+            debug!("Room is still being converted");
+            messages.extend(self.find_per_room_by_seq(room, seqs).await?);
+        }
+
+        Ok(Some(messages))
+    }
+
+    /// Like `search_per_room`, but for shared rooms
+    ///
+    /// `None` if the room is not in the shared layout (yet)
+    #[instrument(skip_all)]
+    pub(crate) async fn search_shared(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+    ) -> Result<Option<Vec<Message>>> {
+        let Some(room_doc) = self
+            .rooms()?
+            .find_one(doc! { "_id": room })
+            .projection(doc! { LEGACY_KEY: 1 })
+            .await
+            .context("Unable to get config")?
+        else {
+            return Ok(None);
+        };
+        self.ensure_indexes(SHARED_DB, MESSAGES_COL).await?;
+
+        let mut filter = query.filter();
+        filter.insert("room", room);
+        let cursor = backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Message>(MESSAGES_COL)
+            .find(filter)
+            .sort(doc! { SEQ_KEY: -1 })
+            .limit(query.limit() as i64)
+            .await
+            .with_context(|| format!("Can't search messages of room {room:?}"))?;
+        let mut messages = collect_messages(cursor).await?;
+
+        if room_doc.get_bool(LEGACY_KEY).unwrap_or_default() {
+            debug!("Room is still being converted");
+            if let Ok(legacy) = self.search_per_room(room, query).await? {
+                messages.extend(legacy);
+            }
+        }
+
+        Ok(Some(messages))
+    }
+
+    /// Switches the instance to the shared layout and moves every existing room into it
+    ///
+    /// Takes at least [`LAYOUT_TTL`], rooms that fail are left in their own database and can be converted by running
+    /// this again
+    #[instrument(skip_all, fields(id = ?self.db_id))]
+    pub async fn convert_to_shared(&self) -> Result<ConversionReport> {
+        self.set_layout(Layout::Shared).await?;
+        // Workers that still use the old layout may create new rooms in their own database until then
+        sleep(LAYOUT_TTL).await;
+
+        let rooms = backoff!(self)
+            .list_database_names()
+            .await
+            .context("Unable to list databases")?
+            .into_iter()
+            .filter(|name| !INVALID_ROOM_NAMES.contains(&name.as_str()));
+
+        let results = stream::iter(rooms)
+            .map(|room| async move {
+                let res = self.convert_room(&room).await;
+                (room, res)
+            })
+            .buffer_unordered(CONVERSIONS)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut report = ConversionReport::default();
+        for (room, res) in results {
+            match res {
+                Ok(()) => report.converted.push(room),
+                Err(e) => {
+                    warn!(room, ?e, "Unable to convert room");
+                    report.failed.push(room);
+                }
+            }
+        }
+
+        info!(
+            converted = report.converted.len(),
+            failed = report.failed.len(),
+            "Converted instance"
+        );
+        Ok(report)
+    }
+
+    #[instrument(skip(self))]
+    async fn convert_room(&self, room: &str) -> Result<()> {
+        let db = backoff!(self).database(room);
+
+        // Sealed rooms are skipped by `next_seq`, writers go to the shared room from now on
+        let Some(config_doc) = db
+            .collection::<Document>(&format!("{CHAT_PREFIX}_0"))
+            .find_one_and_update(doc! {}, doc! { "$set": { MOVED_KEY: true } })
+            .return_document(ReturnDocument::After)
+            .await
+            .context("Unable to seal room")?
+        else {
+            bail!("Room has no config");
+        };
+        let mut state = bson::from_document::<RoomState>(config_doc.clone())
+            .context("Unable to parse room config")?;
+        if !config_doc.contains_key(SEQ_KEY) {
+            state.head.seq = self.initial_seq(room).await?;
+        }
+
+        let mut room_doc =
+            bson::to_document(&state.config).context("Unable to serialize room config")?;
+        room_doc.insert("_id", room);
+        room_doc.insert(SEQ_KEY, state.head.seq);
+        if let Some(last_ts) = state.head.last_ts {
+            room_doc.insert(LAST_TS_KEY, last_ts);
+        }
+        room_doc.insert(LEGACY_KEY, true);
+        match self.rooms()?.insert_one(room_doc).await {
+            Ok(_) => {}
+            Err(e) if only_duplicates(&e) => debug!("Room was adopted by an earlier run"),
+            Err(e) => return Err(e).context("Unable to adopt room"),
+        }
+
+        sleep(SEAL_GRACE).await;
+
+        let mut pass = 1;
+        loop {
+            let read = self.copy_buckets(room).await?;
+            let remaining = self.count_buckets(room).await?;
+            match CopyStep::after_pass(pass, read, remaining) {
+                CopyStep::Drop => break,
+                CopyStep::Recopy => {
+                    warn!(pass, read, remaining, "Messages landed while copying");
+                    pass += 1;
+                }
+                CopyStep::Abort => {
+                    bail!("Room still received messages after {pass} copy passes, keeping it")
+                }
+            }
+        }
+        self.copy_receipts(room).await?;
+        self.copy_attachments(room).await?;
+
+        db.drop().await.context("Unable to drop room database")?;
+        self.rooms()?
+            .update_one(doc! { "_id": room }, doc! { "$unset": { LEGACY_KEY: "" } })
+            .await
+            .context("Unable to finish conversion")?;
+
+        info!(pass, "Converted room");
+        Ok(())
+    }
+
+    /// Copies every message of the buckets of a room, returns how many were read
+    async fn copy_buckets(&self, room: &str) -> Result<u64> {
+        let db = backoff!(self).database(room);
+        let mut read = 0;
+        for index in self.chat_collections(room).await? {
+            if index == 0 {
+                continue;
+            }
+            let mut cursor = db
+                .collection::<Document>(&format!("{CHAT_PREFIX}_{index}"))
+                .find(doc! {})
+                .await
+                .with_context(|| format!("Unable to read bucket {index}"))?;
+
+            let mut batch = Vec::with_capacity(COPY_BATCH);
+            while cursor
+                .advance()
+                .await
+                .context("Advancing messages failed")?
+            {
+                let mut message = cursor
+                    .deserialize_current()
+                    .context("Unable to read message")?;
+                message.insert("room", room);
+                batch.push(message);
+                read += 1;
+                if batch.len() >= COPY_BATCH {
+                    self.copy_batch(mem::take(&mut batch)).await?;
+                }
+            }
+            self.copy_batch(batch).await?;
+        }
+        Ok(read)
+    }
+
+    /// Messages in the buckets of a room
+    async fn count_buckets(&self, room: &str) -> Result<u64> {
+        let db = backoff!(self).database(room);
+        let mut count = 0;
+        for index in self.chat_collections(room).await? {
+            if index == 0 {
+                continue;
+            }
+            count += db
+                .collection::<Document>(&format!("{CHAT_PREFIX}_{index}"))
+                .count_documents(doc! {})
+                .await
+                .with_context(|| format!("Unable to count bucket {index}"))?;
+        }
+        Ok(count)
+    }
+
+    /// Keeps the original `_id`s, so documents copied by an earlier run are skipped
+    ///
+    /// Any duplicate counts as copied, on the `_id` as well as on the unique `(room, seq)` index
+    async fn copy_batch(&self, batch: Vec<Document>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        match backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Document>(MESSAGES_COL)
+            .insert_many(batch)
+            .ordered(false)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if only_duplicates(&e) => Ok(()),
+            Err(e) => Err(e).context("Unable to copy messages"),
+        }
+    }
+
+    /// State of a shared room, `None` if the room is not in the shared layout (yet)
+    pub(crate) async fn shared_state(&self, room: &str) -> Result<Option<RoomState>> {
+        self.rooms()?
+            .clone_with_type::<RoomState>()
+            .find_one(doc! { "_id": room })
+            .await
+            .context("Unable to get config")
+    }
+
+    pub(crate) fn rooms(&self) -> Result<mongodb::Collection<Document>> {
+        Ok(backoff!(self)
+            .database(SHARED_DB)
+            .collection::<Document>(ROOMS_COL))
+    }
+}
+
+/// Whether every failed write of `e` hit an already existing document
+pub(crate) fn only_duplicates(e: &mongodb::error::Error) -> bool {
+    match &*e.kind {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::InsertMany(InsertManyError {
+            write_errors: Some(errors),
+            write_concern_error: None,
+            ..
+        }) => errors.iter().all(|e| e.code == DUPLICATE_KEY),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_only_after_a_complete_pass() {
+        assert_eq!(CopyStep::after_pass(1, 10, 10), CopyStep::Drop);
+        // A late write landed after the cursor passed its bucket
+        assert_eq!(CopyStep::after_pass(1, 10, 11), CopyStep::Recopy);
+        assert_eq!(CopyStep::after_pass(2, 11, 11), CopyStep::Drop);
+        assert_eq!(
+            CopyStep::after_pass(MAX_COPY_PASSES, 11, 12),
+            CopyStep::Abort
+        );
+        // Deleted by retention while copying, recopying is still correct
+        assert_eq!(CopyStep::after_pass(1, 10, 9), CopyStep::Recopy);
+    }
+
+    #[test]
+    fn late_writes_land_before_the_copy() {
+        assert!(SEAL_GRACE > MAX_WRITE_DURATION);
+    }
+
+    #[test]
+    fn shared_messages_read_back_as_messages() {
+        let message = Message {
+            timestamp: DateTime::from_millis(1_700_000_000_000),
+            ..Message::test(7)
+        };
+        let doc = bson::to_document(&SharedMessage {
+            room: "room",
+            message: &message,
+        })
+        .unwrap();
+
+        assert_eq!(doc.get_str("room").unwrap(), "room");
+        assert_eq!(doc.get_i64(SEQ_KEY).unwrap(), 7);
+        assert_eq!(bson::from_document::<Message>(doc).unwrap(), message);
+    }
+}
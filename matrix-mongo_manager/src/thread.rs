@@ -0,0 +1,229 @@
+use super::mappings;
+use crate::MongoManager;
+use crate::bucketing::Bucketing;
+use crate::layout::Layout;
+use crate::messaging::{CHAT_PREFIX, Message, SEQ_KEY};
+use crate::room::RoomName;
+use anyhow::{Context, Result, bail};
+use bson::{DateTime, doc};
+use mongodb::Cursor;
+use std::collections::HashSet;
+use tracing::{debug, instrument, trace, warn};
+
+const INTERNAL_ERR_MSG: &str = "Internal server error";
+/// Buckets searched for referenced messages in rooms whose buckets don't follow the sequence number
+const MAX_CONTEXT_BUCKETS: usize = 31;
+
+impl MongoManager {
+    /// Messages that `messages` reply or react to, but that aren't part of `messages` themselves
+    ///
+    /// Sorted like [`Self::read_messages`], references that can't be found are skipped
+    #[instrument(skip_all)]
+    pub async fn read_context(room: &str, messages: &[Message]) -> Result<Vec<Message>> {
+        let room = RoomName::parse(room)?;
+        let seqs = missing_references(messages);
+        if seqs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let context = match mappings::read_manager(&room).await {
+            Ok(either::Left(manager)) => manager
+                .find_by_seq(&room, &seqs)
+                .await
+                .context(INTERNAL_ERR_MSG)
+                .map_err(|e| fritz!(manager, e))?,
+            Ok(either::Right((man, mig_m))) => {
+                let (res, mig_res) = tokio::join!(
+                    man.find_by_seq(&room, &seqs),
+                    mig_m.find_by_seq(&room, &seqs)
+                );
+                let mut context = res
+                    .context("Failed to read context from manager")
+                    .context(INTERNAL_ERR_MSG)
+                    .map_err(|e| fritz!(man, e))?;
+                context.extend(
+                    mig_res
+                        .context("Failed to read context from migration manager")
+                        .context(INTERNAL_ERR_MSG)
+                        .map_err(|e| fritz!(mig_m, e))?,
+                );
+                context
+            }
+            Err(e) => {
+                warn!(?e, "Failed to get migration manager");
+                bail!(INTERNAL_ERR_MSG);
+            }
+        };
+
+        let mut context = context
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        context.sort_unstable();
+
+        debug!(
+            requested = seqs.len(),
+            found = context.len(),
+            "Read context"
+        );
+        Ok(context)
+    }
+
+    #[instrument(skip(self, room))]
+    async fn find_by_seq(&self, room: &str, seqs: &[i64]) -> Result<Vec<Message>> {
+        if self.layout().await? == Layout::Shared
+            && let Some(found) = self.find_shared_by_seq(room, seqs).await?
+        {
+            return Ok(found);
+        }
+        self.find_per_room_by_seq(room, seqs).await
+    }
+
+    /// Only searches the buckets the sequence numbers can be in, unless the bucketing of the room is time based
+    ///
+    /// Empty if the room does not exist
+    #[instrument(skip(self, room))]
+    pub(crate) async fn find_per_room_by_seq(
+        &self,
+        room: &str,
+        seqs: &[i64],
+    ) -> Result<Vec<Message>> {
+        let Some(indices) = self.cached_chat_collections(room).await? else {
+            return Ok(vec![]);
+        };
+        let candidates = candidate_buckets(self.cache.bucketing(room), &indices, seqs);
+
+        let db = backoff!(self).database(room);
+        let mut remaining = seqs.to_vec();
+        let mut found = vec![];
+        for index in candidates {
+            if remaining.is_empty() {
+                break;
+            }
+            let cursor = db
+                .collection::<Message>(&format!("{CHAT_PREFIX}_{index}"))
+                .find(doc! { SEQ_KEY: { "$in": &remaining } })
+                .await
+                .with_context(|| format!("Can't search bucket {index}"))?;
+            let messages = collect_messages(cursor).await?;
+            trace!(index, found = messages.len(), "Searched bucket");
+
+            remaining.retain(|seq| !messages.iter().any(|m| m.seq == *seq));
+            found.extend(messages);
+        }
+
+        Ok(found)
+    }
+}
+
+/// Sorted and deduplicated references of `messages` to messages outside of `messages`
+fn missing_references(messages: &[Message]) -> Vec<i64> {
+    let present = messages.iter().map(|m| m.seq).collect::<HashSet<_>>();
+    let mut seqs = messages
+        .iter()
+        .filter_map(|m| m.reply_to)
+        .filter(|seq| !present.contains(seq))
+        .collect::<Vec<_>>();
+    seqs.sort_unstable();
+    seqs.dedup();
+    seqs
+}
+
+/// Buckets that may hold the messages `seqs`, newest first
+///
+/// Without a known bucketing, the newest [`MAX_CONTEXT_BUCKETS`] buckets are searched
+fn candidate_buckets(bucketing: Option<Bucketing>, indices: &[u32], seqs: &[i64]) -> Vec<u32> {
+    let mut candidates = match bucketing {
+        Some(bucketing @ (Bucketing::Count { .. } | Bucketing::Single)) => {
+            // The timestamp is ignored by both strategies
+            let mut buckets = seqs
+                .iter()
+                .map(|&seq| bucketing.bucket(seq, DateTime::MIN))
+                .filter(|b| indices.binary_search(b).is_ok())
+                .collect::<Vec<_>>();
+            buckets.sort_unstable();
+            buckets.dedup();
+            buckets
+        }
+        _ => indices.to_vec(),
+    };
+    candidates.retain(|&i| i != 0);
+    candidates.reverse();
+    candidates.truncate(MAX_CONTEXT_BUCKETS);
+    candidates
+}
+
+/// Skips documents that aren't messages, like migration markers
+pub(crate) async fn collect_messages(mut cursor: Cursor<Message>) -> Result<Vec<Message>> {
+    let mut messages = vec![];
+    while cursor
+        .advance()
+        .await
+        .context("Advancing messages failed")?
+    {
+        match cursor.deserialize_current() {
+            Ok(m) => messages.push(m),
+            Err(_) => debug!("Encountered migration marker"),
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::MessageKind;
+
+    fn message(seq: i64, reply_to: Option<i64>) -> Message {
+        Message {
+            kind: if reply_to.is_some() {
+                MessageKind::Reply
+            } else {
+                MessageKind::Text
+            },
+            reply_to,
+            ..Message::test(seq)
+        }
+    }
+
+    #[test]
+    fn only_missing_references_are_fetched() {
+        let page = [
+            message(5, Some(1)),
+            message(6, Some(5)),
+            message(7, Some(1)),
+            message(8, Some(3)),
+            message(9, None),
+        ];
+        assert_eq!(missing_references(&page), [1, 3]);
+        assert!(missing_references(&[message(1, None)]).is_empty());
+    }
+
+    #[test]
+    fn count_buckets_are_computed() {
+        let bucketing = Some(Bucketing::Count { size: 10 });
+        let indices = [0, 1, 2, 3, 4];
+        assert_eq!(candidate_buckets(bucketing, &indices, &[1, 5, 25]), [3, 1]);
+        // Buckets that don't exist (anymore) are skipped
+        assert_eq!(
+            candidate_buckets(bucketing, &indices, &[55]),
+            [] as [u32; 0]
+        );
+        assert_eq!(
+            candidate_buckets(Some(Bucketing::Single), &[0, 1], &[1, 1000]),
+            [1]
+        );
+    }
+
+    #[test]
+    fn time_buckets_are_searched_newest_first() {
+        let indices = (0..=40).collect::<Vec<u32>>();
+        let candidates = candidate_buckets(Some(Bucketing::Daily), &indices, &[1]);
+        assert_eq!(candidates.len(), MAX_CONTEXT_BUCKETS);
+        assert_eq!(candidates.first(), Some(&40));
+        assert!(!candidates.contains(&0));
+
+        assert_eq!(candidate_buckets(None, &[0, 1, 2], &[1]), [2, 1]);
+    }
+}
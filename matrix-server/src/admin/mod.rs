@@ -0,0 +1,363 @@
+use crate::messages::err_status;
+use crate::{AppState, ERR_KEY};
+use axum::Json;
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::{TryStreamExt, stream};
+use matrix_errors::MappingErr;
+use matrix_mongo_manager::export::ExportFormat;
+use matrix_mongo_manager::{MongoManager, mappings};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io;
+use std::sync::Arc;
+use tracing::{Instrument, Span, error, info, instrument, warn};
+use uuid::Uuid;
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewShard {
+    url: String,
+    from: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewMigration {
+    url: String,
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FormatParams {
+    format: Option<String>,
+}
+
+impl FormatParams {
+    fn format(&self) -> Result<ExportFormat, (StatusCode, Json<Value>)> {
+        match &self.format {
+            Some(format) => format.parse().map_err(|e: anyhow::Error| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ERR_KEY: e.to_string()})),
+                )
+            }),
+            None => Ok(ExportFormat::default()),
+        }
+    }
+}
+
+/// Rejects every request without a matching `Authorization: Bearer <token>` header
+#[instrument(skip_all)]
+pub(crate) async fn auth(State(token): State<Arc<str>>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix(BEARER_PREFIX))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()));
+
+    if !authorized {
+        warn!("Rejected unauthorized admin request");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ERR_KEY: "Invalid or missing admin token"})),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn list_shards(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db_manager.list_instances().await {
+        Ok(instances) => to_json(StatusCode::OK, &instances),
+        Err(e) => internal(e, "Failed to list shards"),
+    }
+}
+
+#[instrument(skip_all, fields(from))]
+pub(crate) async fn add_shard(
+    State(state): State<AppState>,
+    Json(shard): Json<NewShard>,
+) -> impl IntoResponse {
+    Span::current().record("from", &shard.from);
+
+    match state.db_manager.add_instance(&shard.url, &shard.from).await {
+        Ok(Ok(id)) => (StatusCode::CREATED, Json(json!({ "id": id }))),
+        Ok(Err(e)) => mapping_err(e),
+        Err(e) => internal(e, "Failed to add shard"),
+    }
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn remove_shard(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    // Taken before the next mapping refresh drops it
+    let manager = mappings::manager_by_id(id).await;
+    match state.db_manager.remove_instance(id).await {
+        Ok(Ok(())) => {
+            if let Some(manager) = manager {
+                relocate_entries(state, manager);
+            }
+            (StatusCode::OK, Json(json!({ "id": id })))
+        }
+        Ok(Err(e)) => mapping_err(e),
+        Err(e) => internal(e, "Failed to remove shard"),
+    }
+}
+
+/// Moves every room of the instance into the shared layout in the background
+#[instrument]
+pub(crate) async fn convert_shard(Path(id): Path<Uuid>) -> impl IntoResponse {
+    let Some(manager) = mappings::manager_by_id(id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ERR_KEY: format!("No manager for instance {id}")})),
+        );
+    };
+
+    tokio::spawn(
+        async move {
+            match manager.convert_to_shared().await {
+                Ok(report) => info!(?report, "Finished conversion"),
+                Err(e) => error!(?e, "Conversion failed"),
+            }
+        }
+        .in_current_span(),
+    );
+    (StatusCode::ACCEPTED, Json(json!({ "id": id })))
+}
+
+/// Rebuilds the room index of an instance in the background
+#[instrument]
+pub(crate) async fn reindex_shard(Path(id): Path<Uuid>) -> impl IntoResponse {
+    let Some(manager) = mappings::manager_by_id(id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ERR_KEY: format!("No manager for instance {id}")})),
+        );
+    };
+
+    tokio::spawn(
+        async move {
+            match manager.index_rooms().await {
+                Ok(report) => info!(?report, "Finished reindexing"),
+                Err(e) => error!(?e, "Reindexing failed"),
+            }
+        }
+        .in_current_span(),
+    );
+    (StatusCode::ACCEPTED, Json(json!({ "id": id })))
+}
+
+/// Moves the room index of an instance to where its rooms are written now in the background
+fn relocate_entries(state: AppState, manager: MongoManager) {
+    tokio::spawn(
+        async move {
+            if let Err(e) = state.db_manager.load_mappings(&state.metrics).await {
+                error!(?e, "Unable to refresh mappings, room index stays in place");
+                return;
+            }
+            match manager.relocate_entries().await {
+                Ok(report) => info!(?report, "Finished relocating room index"),
+                Err(e) => error!(?e, "Relocating room index failed"),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn list_migrations(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db_manager.list_migrations().await {
+        Ok(migrations) => to_json(StatusCode::OK, &migrations),
+        Err(e) => internal(e, "Failed to list migrations"),
+    }
+}
+
+#[instrument(skip_all, fields(from, to))]
+pub(crate) async fn start_migration(
+    State(state): State<AppState>,
+    Json(migration): Json<NewMigration>,
+) -> impl IntoResponse {
+    Span::current().record("from", &migration.from);
+    Span::current().record("to", &migration.to);
+
+    match state
+        .db_manager
+        .start_migration(&migration.url, &migration.from, &migration.to)
+        .await
+    {
+        Ok(Ok(id)) => (StatusCode::CREATED, Json(json!({ "id": id }))),
+        Ok(Err(e)) => mapping_err(e),
+        Err(e) => internal(e, "Failed to start migration"),
+    }
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn cancel_migration(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    // Taken before the next mapping refresh drops it
+    let manager = mappings::manager_by_id(id).await;
+    match state.db_manager.cancel_migration(id).await {
+        Ok(Ok(())) => {
+            if let Some(manager) = manager {
+                relocate_entries(state, manager);
+            }
+            (StatusCode::OK, Json(json!({ "id": id })))
+        }
+        Ok(Err(e)) => mapping_err(e),
+        Err(e) => internal(e, "Failed to cancel migration"),
+    }
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let metrics = &state.metrics;
+    let db_guard = state.db_manager.guard();
+    Json(json!({
+        "read_per_sec": metrics.read_ps(),
+        "write_per_sec": metrics.write_ps(),
+        "req_total": metrics.get_total_requests(),
+        "req_failed": metrics.get_total_fails(),
+        "mongo_circuit": {
+            "opened": metrics.get_circuit_opened(),
+            "half_opened": metrics.get_circuit_half_opened(),
+            "closed": metrics.get_circuit_closed(),
+        },
+        "room_cache": {
+            "hits": metrics.get_room_cache_hits(),
+            "misses": metrics.get_room_cache_misses(),
+        },
+        "postgres": {
+            "state": db_guard.state().to_string(),
+            "in_state_for_ms": db_guard.in_state_for().as_millis(),
+        },
+    }))
+}
+
+#[instrument]
+pub(crate) async fn route(Path(room): Path<String>) -> impl IntoResponse {
+    match MongoManager::explain_route(&room).await {
+        Ok(explanation) => to_json(StatusCode::OK, &explanation),
+        Err(e) => failed(e, "Failed to route room"),
+    }
+}
+
+/// Streams the room as JSON Lines or BSON, see [`MongoManager::export_room`]
+#[instrument(skip(state))]
+pub(crate) async fn export_room(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<FormatParams>,
+) -> Response {
+    let format = match params.format() {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    let rx = match MongoManager::export_room(&room).await {
+        Ok(rx) => rx,
+        Err(e) => {
+            state.metrics.fail();
+            return failed(e, "Failed to export room").into_response();
+        }
+    };
+    state.metrics.read();
+
+    // Errors after the first record can only abort the response
+    let body = stream::unfold(rx, move |mut rx| async move {
+        let chunk = rx
+            .recv()
+            .await?
+            .and_then(|record| format.encode(&record))
+            .map_err(io::Error::other);
+        Some((chunk, rx))
+    });
+    (
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// Recreates an exported room on the instance it is routed to
+#[instrument(skip_all)]
+pub(crate) async fn import_room(
+    State(state): State<AppState>,
+    Query(params): Query<FormatParams>,
+    body: Body,
+) -> impl IntoResponse {
+    let format = match params.format() {
+        Ok(format) => format,
+        Err(e) => return e,
+    };
+    let chunks = body.into_data_stream().map_err(anyhow::Error::from);
+    match MongoManager::import_room(format, chunks).await {
+        Ok(report) => {
+            state.metrics.write();
+            to_json(StatusCode::CREATED, &report)
+        }
+        Err(e) => {
+            state.metrics.fail();
+            failed(e, "Failed to import room")
+        }
+    }
+}
+
+fn to_json<T: serde::Serialize>(status: StatusCode, val: &T) -> (StatusCode, Json<Value>) {
+    match serde_json::to_value(val) {
+        Ok(val) => (status, Json(val)),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ERR_KEY: e.to_string()})),
+        ),
+    }
+}
+
+fn mapping_err(e: MappingErr) -> (StatusCode, Json<Value>) {
+    let status = match e {
+        MappingErr::DuplicateFrom(_)
+        | MappingErr::LastInstance
+        | MappingErr::FirstInstance
+        | MappingErr::Unmigrated(_)
+        | MappingErr::OverlappingMigration(_) => StatusCode::CONFLICT,
+        MappingErr::InvalidRange(..) => StatusCode::BAD_REQUEST,
+        MappingErr::NotCanonical(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        MappingErr::NotFound(_) => StatusCode::NOT_FOUND,
+    };
+    (status, Json(json!({ERR_KEY: e.to_string()})))
+}
+
+/// Errors of the request are reported as they are, everything else like [`internal`]
+fn failed(e: anyhow::Error, msg: &str) -> (StatusCode, Json<Value>) {
+    match err_status(&e) {
+        StatusCode::INTERNAL_SERVER_ERROR => internal(e, msg),
+        status => {
+            warn!(?e, "{msg}");
+            (status, Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+/// Only the log gets the error, it can contain urls and credentials of instances
+fn internal(e: anyhow::Error, msg: &str) -> (StatusCode, Json<Value>) {
+    error!(?e, "{msg}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ERR_KEY: msg})),
+    )
+}
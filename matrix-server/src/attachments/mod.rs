@@ -0,0 +1,104 @@
+use crate::messages::err_status;
+use crate::{AppState, ERR_KEY};
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use matrix_mongo_manager::MongoManager;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{Span, instrument, warn};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Upload {
+    user: String,
+    /// File name shown to the other members
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Download {
+    user: String,
+}
+
+/// Takes the raw file as body, the returned metadata goes into the `attachments` of a message
+#[instrument(skip_all, fields(room, user))]
+pub(crate) async fn upload(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<Upload>,
+    body: Bytes,
+) -> impl IntoResponse {
+    Span::current().record("room", &room);
+    Span::current().record("user", &params.user);
+
+    match MongoManager::upload_attachment(&room, &params.user, &params.name, body.to_vec()).await {
+        Ok(attachment) => {
+            state.metrics.write();
+            (StatusCode::CREATED, Json(json!(attachment)))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to upload attachment");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(room, user, key))]
+pub(crate) async fn download(
+    State(state): State<AppState>,
+    Path((room, key)): Path<(String, String)>,
+    Query(params): Query<Download>,
+) -> Response {
+    Span::current().record("room", &room);
+    Span::current().record("user", &params.user);
+    Span::current().record("key", &key);
+
+    match MongoManager::download_attachment(&room, &params.user, &key).await {
+        Ok((attachment, data)) => {
+            state.metrics.read();
+            let content_type = HeaderValue::from_str(&attachment.mime)
+                .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+            let disposition = format!(
+                "attachment; filename=\"{}\"",
+                header_safe_name(&attachment.name)
+            );
+            (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        HeaderValue::from_str(&disposition)
+                            .unwrap_or(HeaderValue::from_static("attachment")),
+                    ),
+                    // The type was sniffed by us, browsers must not guess another one
+                    (
+                        header::X_CONTENT_TYPE_OPTIONS,
+                        HeaderValue::from_static("nosniff"),
+                    ),
+                ],
+                data,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to download attachment");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()}))).into_response()
+        }
+    }
+}
+
+/// File names can contain anything, the header only gets printable ASCII without quotes
+fn header_safe_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
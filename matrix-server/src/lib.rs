@@ -1,34 +1,48 @@
+mod admin;
+mod attachments;
 mod messages;
+mod presence;
+mod receipts;
+mod rooms;
 
 use anyhow::{Context, Result};
-use axum::extract::Request;
+use axum::extract::{DefaultBodyLimit, Request, State};
 use axum::http::{HeaderValue, Method, StatusCode, header};
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
-use axum::{Router, ServiceExt};
+use axum::routing::{delete, get, post};
+use axum::{Router, ServiceExt, middleware};
 use matrix_commons::VERSION;
+use matrix_db_manager::DbManager;
+use matrix_db_manager::guard::DbState;
 use matrix_macros::get_env;
 use matrix_metrics::MetricsWrapper;
-use std::sync::atomic::Ordering;
+use matrix_mongo_manager::attachment::MAX_ATTACHMENT_BYTES;
+use matrix_mongo_manager::export::MAX_IMPORT_BYTES;
+use std::env;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::signal::unix::{SignalKind, signal};
 use tokio::{select, signal};
 use tower::Layer;
 use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::normalize_path::NormalizePathLayer;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 #[cfg(unix)]
 const DOCKER_SHUTDOWN_SIG_NUM: i32 = 15;
 
+const ERR_KEY: &str = "err";
+
 #[derive(Debug, Clone)]
 struct AppState {
     metrics: MetricsWrapper,
+    db_manager: DbManager,
 }
 
 #[instrument(name = "start server", skip_all)]
-pub async fn start(metrics: MetricsWrapper) -> Result<()> {
+pub async fn start(metrics: MetricsWrapper, db_manager: DbManager) -> Result<()> {
     const ORIGIN_ENV_KEY: &str = "ALLOW_ORIGIN_URL";
     let allow_origin = get_env!(ORIGIN_ENV_KEY);
     debug!(%allow_origin);
@@ -39,26 +53,89 @@ pub async fn start(metrics: MetricsWrapper) -> Result<()> {
         )
     })?;
     let port = get_env!("PORT", "8080", u16);
+    // Has to leave room for the JSON around a message of `MAX_MESSAGE_CHARS`
+    let max_body_bytes = get_env!("MAX_BODY_BYTES", "65536", usize);
+
+    const ADMIN_TOKEN_ENV_KEY: &str = "ADMIN_TOKEN";
+    let admin_token = env::var(ADMIN_TOKEN_ENV_KEY)
+        .ok()
+        .filter(|token| !token.is_empty())
+        .map(Arc::<str>::from);
 
     let cors = CorsLayer::new()
         .allow_origin(allow_origin)
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers([header::CONTENT_TYPE]);
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]);
 
-    let state = AppState { metrics };
+    let state = AppState {
+        metrics,
+        db_manager,
+    };
 
     info!(port, "Starting server");
 
     let v1_router = Router::new()
         .route("/addroom", post(messages::create_room))
         .route("/sendmessage", post(messages::send))
-        .route("/post/{room}", get(messages::read));
+        .route("/post/{room}", get(messages::read))
+        .route("/room/{room}/search", get(messages::search))
+        .route("/room/{room}/read", post(receipts::mark_read))
+        .route("/unread", get(receipts::unread))
+        .route("/rooms", get(rooms::list))
+        .route("/rooms/public", get(rooms::directory))
+        .route("/dm", post(rooms::open_direct))
+        .route(
+            "/presence",
+            get(presence::presence).put(presence::heartbeat),
+        )
+        .route(
+            "/room/{room}/typing",
+            get(presence::typing).put(presence::set_typing),
+        )
+        .layer(RequestBodyLimitLayer::new(max_body_bytes))
+        // Added after the message limit, files get their own
+        .route(
+            "/room/{room}/attachments",
+            post(attachments::upload).layer(RequestBodyLimitLayer::new(*MAX_ATTACHMENT_BYTES)),
+        )
+        .route("/room/{room}/attachments/{key}", get(attachments::download));
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/version", get(version))
         .route("/robots.txt", get(robots))
-        .nest("/v1", v1_router)
+        .nest("/v1", v1_router);
+
+    match admin_token {
+        Some(admin_token) => {
+            let admin_router = Router::new()
+                .route("/shards", get(admin::list_shards).post(admin::add_shard))
+                .route("/shards/{id}", delete(admin::remove_shard))
+                .route("/shards/{id}/convert", post(admin::convert_shard))
+                .route("/shards/{id}/reindex", post(admin::reindex_shard))
+                .route(
+                    "/migrations",
+                    get(admin::list_migrations).post(admin::start_migration),
+                )
+                .route("/migrations/{id}", delete(admin::cancel_migration))
+                .route("/route/{room}", get(admin::route))
+                .route("/metrics", get(admin::metrics))
+                .route("/rooms/{room}/export", get(admin::export_room))
+                .layer(RequestBodyLimitLayer::new(max_body_bytes))
+                // Added after the general limit, exports are far larger than other requests
+                .route(
+                    "/rooms/import",
+                    post(admin::import_room).layer(RequestBodyLimitLayer::new(*MAX_IMPORT_BYTES)),
+                )
+                .route_layer(middleware::from_fn_with_state(admin_token, admin::auth));
+            app = app.nest("/admin", admin_router);
+        }
+        None => warn!("{ADMIN_TOKEN_ENV_KEY} is not set, admin API is disabled"),
+    }
+
+    let app = app
         .with_state(state)
+        // The default limit of axum would still apply to JSON bodies otherwise, the routers have their own
+        .layer(DefaultBodyLimit::disable())
         .layer(cors);
 
     let app = ServiceExt::<Request>::into_make_service(
@@ -89,7 +166,7 @@ async fn shutdown_signal() {
     let docker_shutdown = async {
         match signal(SignalKind::from_raw(DOCKER_SHUTDOWN_SIG_NUM)) {
             Ok(mut sig) => {
-                if let None = sig.recv().await {
+                if sig.recv().await.is_none() {
                     error!("Can't receive signals for sig {DOCKER_SHUTDOWN_SIG_NUM} anymore");
                 }
             }
@@ -110,9 +187,9 @@ async fn shutdown_signal() {
     }
 }
 
-#[instrument]
-async fn version() -> impl IntoResponse {
-    if matrix_db_manager::guard::DbGuard::is_running(Ordering::Relaxed) {
+#[instrument(skip_all)]
+async fn version(State(state): State<AppState>) -> impl IntoResponse {
+    if state.db_manager.guard().state() == DbState::Open {
         return (StatusCode::INTERNAL_SERVER_ERROR, "DB Offline");
     }
     (StatusCode::OK, VERSION)
@@ -1,36 +1,91 @@
-use crate::AppState;
+use crate::{AppState, ERR_KEY};
 use axum::Json;
 use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use bson::DateTime;
 use chrono::Utc;
-use matrix_mongo_manager::messaging;
+use matrix_errors::{ContentErr, MatrixErr};
+use matrix_mongo_manager::bucketing::{self, Bucketing};
+use matrix_mongo_manager::messaging::{self, Attachment, MessageKind};
+use matrix_mongo_manager::retention::Retention;
+use matrix_mongo_manager::search::{DEFAULT_SEARCH_LIMIT, SearchQuery};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use tracing::{Span, instrument, warn};
 
-const ERR_KEY: &str = "err";
-
 #[derive(Debug, Deserialize)]
 pub(crate) struct RoomConfig {
     name: String,
     allowed_users: Vec<String>,
+    /// Falls back to `DEFAULT_BUCKETING`
+    bucketing: Option<Bucketing>,
+    /// Lists the room in the public directory
+    #[serde(default)]
+    public: bool,
+    /// Keeps every message if not set
+    retention: Option<Retention>,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct SendMessage {
     user: String,
     room: String,
+    /// Can be empty if there are attachments
+    #[serde(default)]
     msg: String,
+    #[serde(default)]
+    kind: SendKind,
+    reply_to: Option<i64>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    /// Makes retries of this send return the original message instead of posting it again
+    client_msg_id: Option<String>,
+}
+
+/// The kinds of [`MessageKind`] users can send, system messages are written by the worker only
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SendKind {
+    #[default]
+    Text,
+    Reply,
+    Reaction,
+}
+
+impl From<SendKind> for MessageKind {
+    fn from(kind: SendKind) -> Self {
+        match kind {
+            SendKind::Text => MessageKind::Text,
+            SendKind::Reply => MessageKind::Reply,
+            SendKind::Reaction => MessageKind::Reaction,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SearchParams {
+    q: String,
+    author: Option<String>,
+    /// RFC 3339, inclusive
+    from: Option<String>,
+    /// RFC 3339, exclusive
+    to: Option<String>,
+    /// `next_before` of the previous page
+    before: Option<i64>,
+    limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct ReadMessage {
     messages: Vec<messaging::Message>,
+    /// Messages the replies and reactions in `messages` refer to, if they aren't in `messages` already
+    context: Vec<messaging::Message>,
     total_messages: usize,
     collections_read: u32,
+    /// Pass as `before` to get the page of older messages
+    next_before: Option<i64>,
 }
 
 #[instrument(skip_all, fields(room))]
@@ -44,6 +99,9 @@ pub(crate) async fn create_room(
         &config.name,
         messaging::RoomConfig {
             allowed_users: config.allowed_users,
+            bucketing: config.bucketing.unwrap_or(*bucketing::DEFAULT_BUCKETING),
+            public: config.public,
+            retention: config.retention,
         },
     )
     .await
@@ -55,7 +113,7 @@ pub(crate) async fn create_room(
         Err(e) => {
             state.metrics.fail();
             warn!(?e, "Failed to add room");
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            (err_status(&e), e.to_string())
         }
     }
 }
@@ -68,23 +126,41 @@ pub(crate) async fn send(
     Span::current().record("user", &payload.user);
     Span::current().record("room", &payload.room);
 
-    if let Err(e) = matrix_mongo_manager::MongoManager::write_message(
+    let written = matrix_mongo_manager::MongoManager::write_message(
         &payload.room,
         messaging::Message {
+            seq: 0, // Assigned when writing
             author: payload.user,
             content: payload.msg,
             timestamp: DateTime::from_chrono(Utc::now()),
+            kind: payload.kind.into(),
+            reply_to: payload.reply_to,
+            attachments: payload.attachments, // Checked against the uploaded files
+            client_msg_id: payload.client_msg_id,
         },
     )
-    .await
-    {
-        state.metrics.fail();
-        warn!(?e, "Failed to post message");
-        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
-    };
-    state.metrics.write();
+    .await;
 
-    (StatusCode::CREATED, "Successfully posted".to_string())
+    match written {
+        // Retries get the original message, so clients can tell both cases apart by the timestamp at most
+        Ok(message) => {
+            state.metrics.write();
+            (StatusCode::CREATED, Json(json!(message)))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to post message");
+            let mut body = json!({ERR_KEY: e.to_string()});
+            // Lets clients shorten the message and send it again
+            if let Some(MatrixErr::InvalidMessage(ContentErr::TooLong(chars, max_chars))) =
+                e.chain().find_map(|c| c.downcast_ref::<MatrixErr>())
+            {
+                body["chars"] = json!(chars);
+                body["max_chars"] = json!(max_chars);
+            }
+            (err_status(&e), Json(body))
+        }
+    }
 }
 
 #[instrument(skip(state))]
@@ -107,12 +183,33 @@ pub(crate) async fn read(
         );
     };
 
-    match matrix_mongo_manager::MongoManager::read_messages(&room, n).await {
-        Ok((messages, col_cnt)) => {
+    const BEFORE_KEY: &str = "before";
+    let before = match params.get(BEFORE_KEY).map(|b| b.parse::<i64>()) {
+        None => None,
+        Some(Ok(before)) => Some(before),
+        Some(Err(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ERR_KEY: format!("{BEFORE_KEY} is not a valid cursor")})),
+            );
+        }
+    };
+
+    let read = async {
+        let (messages, col_cnt) =
+            matrix_mongo_manager::MongoManager::read_messages(&room, n, before).await?;
+        let context = matrix_mongo_manager::MongoManager::read_context(&room, &messages).await?;
+        anyhow::Ok((messages, context, col_cnt))
+    };
+
+    match read.await {
+        Ok((messages, context, col_cnt)) => {
             state.metrics.read();
             let msg_len = messages.len();
             let resp = ReadMessage {
+                next_before: messaging::next_before(&messages, n),
                 messages,
+                context,
                 total_messages: msg_len,
                 collections_read: col_cnt,
             };
@@ -127,10 +224,85 @@ pub(crate) async fn read(
         Err(e) => {
             state.metrics.fail();
             warn!(?e, "Failed to get messages");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ERR_KEY: e.to_string()})),
-            )
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn search(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    let parse_ts = |key: &str, ts: &Option<String>| match ts {
+        None => Ok(None),
+        Some(ts) => chrono::DateTime::parse_from_rfc3339(ts)
+            .map(|ts| Some(DateTime::from_chrono(ts.with_timezone(&Utc))))
+            .map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ERR_KEY: format!("{key}={ts:?} is not an RFC 3339 timestamp")})),
+                )
+            }),
+    };
+    let (from, to) = match (parse_ts("from", &params.from), parse_ts("to", &params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+
+    let query = SearchQuery {
+        text: params.q,
+        author: params.author,
+        from,
+        to,
+        before: params.before,
+        limit: params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+    };
+    match matrix_mongo_manager::MongoManager::search_messages(&room, query).await {
+        Ok(page) => {
+            state.metrics.read();
+            (StatusCode::OK, Json(json!(page)))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to search messages");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+/// Logical errors are the fault of the client, everything else is ours
+pub(crate) fn err_status(e: &anyhow::Error) -> StatusCode {
+    match e.chain().find_map(|c| c.downcast_ref::<MatrixErr>()) {
+        Some(
+            MatrixErr::IllegalRoomName(..)
+            | MatrixErr::InvalidRoomConfig(_)
+            | MatrixErr::InvalidSearch(_)
+            | MatrixErr::InvalidDirect(_)
+            | MatrixErr::InvalidExport(_),
+        ) => StatusCode::BAD_REQUEST,
+        Some(MatrixErr::InvalidMessage(e)) => match e {
+            ContentErr::Empty => StatusCode::BAD_REQUEST,
+            // Unlike bodies over the limit of the server, which are 413
+            ContentErr::TooLong(..)
+            | ContentErr::ControlCharacters
+            | ContentErr::InvalidReference(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ContentErr::MissingReference(_)
+            | ContentErr::UnexpectedReference
+            | ContentErr::InvalidAttachment(_)
+            | ContentErr::InvalidClientMsgId(_) => StatusCode::BAD_REQUEST,
+        },
+        Some(MatrixErr::AttachmentTooLarge(..)) => StatusCode::PAYLOAD_TOO_LARGE,
+        Some(MatrixErr::NotInRoom(_)) => StatusCode::FORBIDDEN,
+        Some(MatrixErr::RoomNotFound(_) | MatrixErr::AttachmentNotFound(_)) => {
+            StatusCode::NOT_FOUND
         }
+        Some(
+            MatrixErr::RoomAlreadyExists(_)
+            | MatrixErr::SendInProgress(_)
+            | MatrixErr::RoomMigrating(_),
+        ) => StatusCode::CONFLICT,
+        Some(MatrixErr::General(_)) | None => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
@@ -0,0 +1,181 @@
+use crate::messages::err_status;
+use crate::{AppState, ERR_KEY};
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use matrix_db_manager::ephemeral::PresenceStatus;
+use matrix_mongo_manager::MongoManager;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tracing::{Span, instrument, warn};
+
+/// Users that can be asked for at once
+const MAX_PRESENCE_USERS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+impl From<PresenceStatus> for Status {
+    fn from(status: PresenceStatus) -> Self {
+        match status {
+            PresenceStatus::Online => Status::Online,
+            PresenceStatus::Away => Status::Away,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Heartbeat {
+    user: String,
+    status: Status,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PresenceQuery {
+    /// Comma separated
+    users: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Typing {
+    user: String,
+    typing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TypingQuery {
+    user: String,
+}
+
+/// Has to be repeated before the presence TTL runs out, `offline` logs out right away
+#[instrument(skip_all, fields(user))]
+pub(crate) async fn heartbeat(
+    State(state): State<AppState>,
+    Json(payload): Json<Heartbeat>,
+) -> Response {
+    Span::current().record("user", &payload.user);
+
+    let status = match payload.status {
+        Status::Online => Some(PresenceStatus::Online),
+        Status::Away => Some(PresenceStatus::Away),
+        Status::Offline => None,
+    };
+    match state.db_manager.set_presence(&payload.user, status).await {
+        Ok(()) => {
+            state.metrics.write();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to set presence");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()}))).into_response()
+        }
+    }
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn presence(
+    State(state): State<AppState>,
+    Query(params): Query<PresenceQuery>,
+) -> impl IntoResponse {
+    let mut users = params
+        .users
+        .split(',')
+        .filter(|u| !u.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    users.sort();
+    users.dedup();
+    if users.len() > MAX_PRESENCE_USERS {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ERR_KEY: format!("At most {MAX_PRESENCE_USERS} users can be asked for")})),
+        );
+    }
+
+    match state.db_manager.presence(&users).await {
+        Ok(present) => {
+            state.metrics.read();
+            let mut statuses = users
+                .into_iter()
+                .map(|u| (u, Status::Offline))
+                .collect::<BTreeMap<_, _>>();
+            for p in present {
+                statuses.insert(p.user, p.status.into());
+            }
+            (StatusCode::OK, Json(json!({ "presence": statuses })))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to get presence");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+/// Has to be repeated while the user keeps typing, expires after the typing TTL otherwise
+#[instrument(skip_all, fields(room, user))]
+pub(crate) async fn set_typing(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Json(payload): Json<Typing>,
+) -> Response {
+    Span::current().record("room", &room);
+    Span::current().record("user", &payload.user);
+
+    let res = async {
+        let room = MongoManager::member_room(&room, &payload.user).await?;
+        state
+            .db_manager
+            .set_typing(&room, &payload.user, payload.typing)
+            .await
+    }
+    .await;
+    match res {
+        Ok(()) => {
+            state.metrics.write();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to set typing");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()}))).into_response()
+        }
+    }
+}
+
+/// Members of the room that are typing, without the asking user
+#[instrument(skip_all, fields(room, user))]
+pub(crate) async fn typing(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<TypingQuery>,
+) -> impl IntoResponse {
+    Span::current().record("room", &room);
+    Span::current().record("user", &params.user);
+
+    let res = async {
+        let room = MongoManager::member_room(&room, &params.user).await?;
+        state.db_manager.typing(&room).await
+    }
+    .await;
+    match res {
+        Ok(mut users) => {
+            state.metrics.read();
+            users.retain(|u| *u != params.user);
+            (StatusCode::OK, Json(json!({ "typing": users })))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to get typing users");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::messages::err_status;
+use crate::{AppState, ERR_KEY};
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use matrix_mongo_manager::MongoManager;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{Span, instrument, warn};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MarkRead {
+    user: String,
+    /// Sequence number of the newest message the user has seen
+    seq: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Unread {
+    user: String,
+}
+
+#[instrument(skip_all, fields(room, user))]
+pub(crate) async fn mark_read(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Json(payload): Json<MarkRead>,
+) -> impl IntoResponse {
+    Span::current().record("room", &room);
+    Span::current().record("user", &payload.user);
+
+    match MongoManager::mark_read(&room, &payload.user, payload.seq).await {
+        Ok(read_seq) => {
+            state.metrics.write();
+            (StatusCode::OK, Json(json!({ "read_seq": read_seq })))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to mark room as read");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+/// Unread counts of every room of the user
+#[instrument(skip_all, fields(user))]
+pub(crate) async fn unread(
+    State(state): State<AppState>,
+    Query(params): Query<Unread>,
+) -> impl IntoResponse {
+    Span::current().record("user", &params.user);
+
+    match MongoManager::unread_counts(&params.user).await {
+        Ok(rooms) => {
+            state.metrics.read();
+            (StatusCode::OK, Json(json!({ "rooms": rooms })))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to get unread counts");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
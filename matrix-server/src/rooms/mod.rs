@@ -0,0 +1,107 @@
+use crate::messages::err_status;
+use crate::{AppState, ERR_KEY};
+use axum::Json;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use matrix_mongo_manager::MongoManager;
+use matrix_mongo_manager::directory::{DEFAULT_DIRECTORY_LIMIT, MAX_DIRECTORY_LIMIT};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{Span, instrument, warn};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListRooms {
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OpenDirect {
+    user: String,
+    /// The other member of the room
+    target: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Directory {
+    /// Name of the last room of the previous page
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Rooms of the user with their newest message
+#[instrument(skip_all, fields(user))]
+pub(crate) async fn list(
+    State(state): State<AppState>,
+    Query(params): Query<ListRooms>,
+) -> impl IntoResponse {
+    Span::current().record("user", &params.user);
+
+    match MongoManager::list_rooms(&params.user).await {
+        Ok(rooms) => {
+            state.metrics.read();
+            (StatusCode::OK, Json(json!({ "rooms": rooms })))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to list rooms");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn directory(
+    State(state): State<AppState>,
+    Query(params): Query<Directory>,
+) -> impl IntoResponse {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_DIRECTORY_LIMIT)
+        .clamp(1, MAX_DIRECTORY_LIMIT);
+    match MongoManager::public_rooms(params.after.as_deref(), limit).await {
+        Ok(rooms) => {
+            state.metrics.read();
+            let next_after = (rooms.len() >= limit)
+                .then(|| rooms.last().map(|r| r.room.clone()))
+                .flatten();
+            (
+                StatusCode::OK,
+                Json(json!({ "rooms": rooms, "next_after": next_after })),
+            )
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to list public rooms");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
+
+/// Creates the direct room of both users, or returns it if it exists already
+#[instrument(skip_all, fields(user, target))]
+pub(crate) async fn open_direct(
+    State(state): State<AppState>,
+    Json(payload): Json<OpenDirect>,
+) -> impl IntoResponse {
+    Span::current().record("user", &payload.user);
+    Span::current().record("target", &payload.target);
+
+    match MongoManager::open_direct(&payload.user, &payload.target).await {
+        Ok(direct) => {
+            let status = if direct.created {
+                state.metrics.write();
+                StatusCode::CREATED
+            } else {
+                state.metrics.read();
+                StatusCode::OK
+            };
+            (status, Json(json!(direct)))
+        }
+        Err(e) => {
+            state.metrics.fail();
+            warn!(?e, "Failed to open direct room");
+            (err_status(&e), Json(json!({ERR_KEY: e.to_string()})))
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use futures::stream;
+use matrix_commons::VERSION;
+use matrix_db_manager::DbManager;
+use matrix_mongo_manager::MongoManager;
+use matrix_mongo_manager::export::ExportFormat;
+use matrix_mongo_manager::mappings;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+/// Bytes read from an import file at once
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Parser)]
+#[command(version = VERSION, about = "Matrix worker, serves the HTTP API without a subcommand")]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Migrates the database and serves the HTTP API, the default
+    Serve,
+    /// Migrates the database and exits
+    Migrate,
+    /// Manages the Mongo instances rooms are routed to
+    #[command(subcommand)]
+    Mappings(MappingsCommand),
+    /// Explains which instances hold a room
+    Route { room: String },
+    /// Exports and imports rooms
+    #[command(subcommand)]
+    Rooms(RoomsCommand),
+    /// Pings Postgres and every Mongo instance, fails if any of them is down
+    Health,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum MappingsCommand {
+    /// Lists the instances and running migrations
+    List,
+    /// Adds an instance for the rooms from `from` up to the next instance
+    ///
+    /// Unless it is the lowest, a migration to `url` has to cover `from`
+    Add {
+        url: String,
+        #[arg(long)]
+        from: String,
+    },
+    /// Removes an instance, its rooms fall to the previous one
+    Remove { id: Uuid },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum RoomsCommand {
+    /// Writes the config and every message of a room
+    Export {
+        room: String,
+        /// `jsonl` or `bson`
+        #[arg(long, default_value_t)]
+        format: ExportFormat,
+        /// Writes to stdout if not set
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Recreates an exported room on the instance it is routed to
+    Import {
+        /// `jsonl` or `bson`
+        #[arg(long, default_value_t)]
+        format: ExportFormat,
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Ping {
+    /// Unset for Postgres
+    id: Option<Uuid>,
+    latency_ms: Option<u128>,
+    err: Option<String>,
+}
+
+impl Ping {
+    fn new(id: Option<Uuid>, res: Result<Duration>) -> Self {
+        match res {
+            Ok(latency) => Self {
+                id,
+                latency_ms: Some(latency.as_millis()),
+                err: None,
+            },
+            Err(e) => Self {
+                id,
+                latency_ms: None,
+                err: Some(format!("{e:#}")),
+            },
+        }
+    }
+}
+
+impl Command {
+    /// Serves the HTTP API when running in the foreground, the rest writes JSON to stdout
+    pub(crate) fn is_serve(command: &Option<Self>) -> bool {
+        matches!(command, None | Some(Command::Serve))
+    }
+
+    pub(crate) async fn run(self, db_manager: DbManager) -> Result<()> {
+        match self {
+            Command::Serve => crate::serve(db_manager).await,
+            Command::Migrate => {
+                db_manager.migrate().await.context("DB Migration failed")?;
+                info!("Migrated database");
+                Ok(())
+            }
+            Command::Mappings(MappingsCommand::List) => {
+                let instances = db_manager.list_instances().await?;
+                let migrations = db_manager.list_migrations().await?;
+                print_json(&serde_json::json!({
+                    "instances": instances,
+                    "migrations": migrations,
+                }))
+            }
+            Command::Mappings(MappingsCommand::Add { url, from }) => {
+                let id = db_manager.add_instance(&url, &from).await??;
+                print_json(&serde_json::json!({ "id": id }))
+            }
+            Command::Mappings(MappingsCommand::Remove { id }) => {
+                load_mappings(&db_manager).await?;
+                let manager = mappings::manager_by_id(id).await;
+                db_manager.remove_instance(id).await??;
+
+                // Its rooms are routed elsewhere now, and so is their index
+                load_mappings(&db_manager).await?;
+                let relocated = match manager {
+                    Some(manager) => Some(manager.relocate_entries().await?),
+                    None => None,
+                };
+                print_json(&serde_json::json!({ "id": id, "relocated": relocated }))
+            }
+            Command::Route { room } => {
+                load_mappings(&db_manager).await?;
+                print_json(&MongoManager::explain_route(&room).await?)
+            }
+            Command::Rooms(command) => {
+                load_mappings(&db_manager).await?;
+                command.run().await
+            }
+            Command::Health => health(&db_manager).await,
+        }
+    }
+}
+
+impl RoomsCommand {
+    async fn run(self) -> Result<()> {
+        match self {
+            RoomsCommand::Export { room, format, out } => match out {
+                Some(out) => {
+                    let file = File::create(&out)
+                        .await
+                        .with_context(|| format!("Unable to create {}", out.display()))?;
+                    export(&room, format, file).await
+                }
+                None => export(&room, format, tokio::io::stdout()).await,
+            },
+            RoomsCommand::Import { format, file } => {
+                let file = File::open(&file)
+                    .await
+                    .with_context(|| format!("Unable to open {}", file.display()))?;
+                let report = MongoManager::import_room(format, read_chunks(file)).await?;
+                print_json(&report)
+            }
+        }
+    }
+}
+
+/// Connects to the Mongo instances once, the server refreshes them in the background instead
+async fn load_mappings(db_manager: &DbManager) -> Result<()> {
+    db_manager
+        .load_mappings(&matrix_metrics::Metrics::new())
+        .await
+        .context("Unable to load Mongo mappings")
+}
+
+#[instrument(skip_all)]
+async fn health(db_manager: &DbManager) -> Result<()> {
+    let postgres = Ping::new(None, db_manager.ping().await);
+    let mut err = None;
+    let mut mongo = vec![];
+    // The instances are only known through Postgres
+    if postgres.err.is_none() {
+        if db_manager.list_instances().await?.is_empty() {
+            err = Some("No regular Mongo instances found");
+        } else {
+            load_mappings(db_manager).await?;
+            let managers = mappings::all_managers().await;
+            mongo = futures::future::join_all(
+                managers
+                    .iter()
+                    .map(|m| async { Ping::new(Some(m.db_id), m.ping().await) }),
+            )
+            .await;
+        }
+    }
+
+    let healthy = postgres.err.is_none() && err.is_none() && mongo.iter().all(|p| p.err.is_none());
+    print_json(&serde_json::json!({
+        "healthy": healthy,
+        "err": err,
+        "postgres": postgres,
+        "mongo": mongo,
+    }))?;
+    // Nothing left to report, the exit code is what health checks look at
+    if !healthy {
+        exit(1);
+    }
+    Ok(())
+}
+
+#[instrument(skip(out))]
+async fn export(room: &str, format: ExportFormat, out: impl AsyncWrite + Unpin) -> Result<()> {
+    let mut rx = MongoManager::export_room(room).await?;
+    let mut out = BufWriter::new(out);
+    let mut records = 0usize;
+    while let Some(record) = rx.recv().await {
+        out.write_all(&format.encode(&record?)?)
+            .await
+            .context("Unable to write export")?;
+        records += 1;
+    }
+    out.flush().await.context("Unable to write export")?;
+
+    info!(records, "Exported room");
+    Ok(())
+}
+
+fn read_chunks(
+    reader: impl AsyncRead + Unpin,
+) -> impl futures::Stream<Item = Result<Vec<u8>>> + Unpin {
+    Box::pin(stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0; READ_CHUNK];
+        let len = reader
+            .read(&mut chunk)
+            .await
+            .context("Unable to read import")?;
+        if len == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(len);
+        Ok(Some((chunk, reader)))
+    }))
+}
+
+fn print_json<T: Serialize>(val: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(val).context("Unable to serialize output")?;
+    println!("{json}");
+    Ok(())
+}
@@ -1,15 +1,21 @@
+mod cli;
+
 use anyhow::{Context, Result};
+use clap::Parser;
+use cli::{Cli, Command};
 use matrix_commons::VERSION;
 use matrix_db_manager::DbManager;
-use std::env;
 use std::process::exit;
 use std::time::Duration;
+use std::{env, io};
 use tracing::{Level, info, subscriber};
 use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let _ = dotenvy::dotenv();
+    let cli = Cli::parse();
     {
         const ENV_KEY: &str = "LOG_LEVEL";
         #[cfg(debug_assertions)]
@@ -28,28 +34,48 @@ async fn main() -> Result<()> {
             Err(_) => DEFAULT_LEVEL,
         };
 
-        let fmt_sub = FmtSubscriber::builder().with_max_level(lvl).finish();
+        // Subcommands may write their output to stdout
+        let writer = if Command::is_serve(&cli.command) {
+            BoxMakeWriter::new(io::stdout)
+        } else {
+            BoxMakeWriter::new(io::stderr)
+        };
+        let fmt_sub = FmtSubscriber::builder()
+            .with_max_level(lvl)
+            .with_writer(writer)
+            .finish();
 
         subscriber::set_global_default(fmt_sub)
             .with_context(|| format!("Failed to set global default subscriber with lvl {lvl}"))?;
     }
 
-    info!("Starting matrix worker v{VERSION}");
-
     let db_manager = DbManager::new()
         .await
         .context("Failed to initialize DB Manager")?;
+    cli.command.unwrap_or(Command::Serve).run(db_manager).await
+}
+
+pub(crate) async fn serve(db_manager: DbManager) -> Result<()> {
+    info!("Starting matrix worker v{VERSION}");
+    // Invalid settings stop the worker here rather than on the first request reading them
+    matrix_db_manager::load_config();
 
     db_manager.migrate().await.context("DB Migration failed")?;
 
+    let metrics = matrix_metrics::Metrics::new();
+    // Stored boundaries that would route rooms differently stop the worker before it serves
+    db_manager
+        .load_mappings(&metrics)
+        .await
+        .context("Unable to load Mongo mappings")?;
     {
         let db_manager = db_manager.clone();
+        let metrics = metrics.clone();
         tokio::spawn(async move {
-            db_manager.manage_mongo().await;
+            db_manager.manage_mongo(metrics).await;
         });
     }
 
-    let metrics = matrix_metrics::Metrics::new();
     {
         let db_manager = db_manager.clone();
         let metrics = metrics.clone();
@@ -58,11 +84,16 @@ async fn main() -> Result<()> {
         });
     }
 
+    // Cluster-wide jobs, each runs on one worker only
+    db_manager.spawn_ephemeral_sweep();
+    db_manager.spawn_retention();
+
     tokio::time::sleep(Duration::from_secs(1)).await;
 
-    matrix_server::start(metrics)
+    matrix_server::start(metrics, db_manager.clone())
         .await
         .context("Failed to start and run HTTP server")?;
+    db_manager.step_down().await;
 
     Ok(())
 }